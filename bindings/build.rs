@@ -38,6 +38,7 @@ fn main() {
         .blocklist_function("SE_InitPlugin")
         .blocklist_function("TF_InitGraph")
         .blocklist_function("TF_InitKernel")
+        .blocklist_function("TF_InitProfiler")
         // TF_Code_TF_OK -> TF_OK, better matches C
         .prepend_enum_name(false)
         // The input header we would like to generate
@@ -1,5 +1,18 @@
+use std::{
+    cell::RefCell,
+    ffi::{CStr, CString},
+};
+
 use super::raw::*;
 
+thread_local! {
+    // Outputs allocated through `allocate_output` by kernels computing on this
+    // thread, by context. TF's output accessors can't tell whether an output
+    // was allocated, they crash on one which wasn't
+    static ALLOCATED_OUTPUTS: RefCell<Vec<(*mut TF_OpKernelContext, TensorInfo)>> =
+        const { RefCell::new(Vec::new()) };
+}
+
 impl TF_Status {
     /// # Safety
     ///
@@ -7,6 +20,98 @@ impl TF_Status {
     pub unsafe fn is_ok(self: *mut Self) -> bool {
         TF_GetCode(self) == TF_OK
     }
+
+    /// # Safety
+    ///
+    /// Should be called on a valid, initialized TF_Status
+    /// Interior zero bytes truncate the message
+    pub unsafe fn set(self: *mut Self, code: TF_Code, message: &str) {
+        let message = message.split('\0').next().unwrap_or_default();
+        let message = CString::new(message).unwrap();
+        TF_SetStatus(self, code, message.as_ptr());
+    }
+
+    /// # Safety
+    ///
+    /// Should be called on a valid, initialized TF_Status
+    pub unsafe fn message(self: *mut Self) -> String {
+        CStr::from_ptr(TF_Message(self))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Creates a new TF_Status, e.g. to be reported through TF_OpKernelContext::failure
+    pub fn with_message(code: TF_Code, message: &str) -> *mut Self {
+        unsafe {
+            let status = TF_NewStatus();
+            status.set(code, message);
+            status
+        }
+    }
+}
+
+/// Owned TF_Status, deleted when dropped
+pub struct Status(*mut TF_Status);
+
+impl Default for Status {
+    fn default() -> Self {
+        Self(unsafe { TF_NewStatus() })
+    }
+}
+
+impl Status {
+    pub fn new(code: TF_Code, message: &str) -> Self {
+        Self(TF_Status::with_message(code, message))
+    }
+
+    pub fn as_ptr(&self) -> *mut TF_Status {
+        self.0
+    }
+
+    /// Hands the status over, e.g. to TF_OpKernelContext::failure
+    pub fn into_raw(self) -> *mut TF_Status {
+        let status = self.0;
+        std::mem::forget(self);
+        status
+    }
+
+    pub fn is_ok(&self) -> bool {
+        unsafe { self.0.is_ok() }
+    }
+
+    pub fn code(&self) -> TF_Code {
+        unsafe { TF_GetCode(self.0) }
+    }
+
+    pub fn message(&self) -> String {
+        unsafe { self.0.message() }
+    }
+
+    /// Err with this status unless it is OK
+    pub fn check(self) -> Result<Self, Self> {
+        match self.is_ok() {
+            true => Ok(self),
+            false => Err(self),
+        }
+    }
+}
+
+impl Drop for Status {
+    fn drop(&mut self) {
+        unsafe { TF_DeleteStatus(self.0) }
+    }
+}
+
+impl std::fmt::Debug for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Status({}, {:?})", self.code(), self.message())
+    }
+}
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message())
+    }
 }
 
 impl TF_OpKernelConstruction {
@@ -74,6 +179,49 @@ impl TF_OpKernelConstruction {
             Err(status)
         }
     }
+
+    /// # Safety
+    ///
+    /// Should be called on a TF_OpKernelConstruction received by kernel creation function
+    pub unsafe fn get_attr_string_list(
+        self: *mut Self,
+        attr_name: &'static str,
+    ) -> Result<Vec<String>, *mut TF_Status> {
+        assert!(attr_name.ends_with('\0'), "Strings must be zero-terminated");
+
+        let (list_size, total_size) = self.get_attr_size(attr_name)?;
+        let list_size = list_size.max(0) as usize;
+
+        let status = TF_NewStatus();
+        let mut values = vec![std::ptr::null_mut(); list_size];
+        let mut lengths = vec![0; list_size];
+        let mut storage = vec![0u8; total_size as usize];
+
+        TF_OpKernelConstruction_GetAttrStringList(
+            self,
+            attr_name.as_ptr() as *const i8,
+            values.as_mut_ptr(),
+            lengths.as_mut_ptr(),
+            list_size as i32,
+            storage.as_mut_ptr() as *mut std::ffi::c_void,
+            total_size as u64,
+            status,
+        );
+
+        if status.is_ok() {
+            TF_DeleteStatus(status);
+            Ok(values
+                .iter()
+                .zip(&lengths)
+                .map(|(&value, &length)| {
+                    let bytes = std::slice::from_raw_parts(value as *const u8, length as usize);
+                    String::from_utf8_lossy(bytes).into_owned()
+                })
+                .collect())
+        } else {
+            Err(status)
+        }
+    }
 }
 
 impl TF_OpKernelContext {
@@ -109,6 +257,40 @@ impl TF_OpKernelContext {
         }
     }
 
+    /// # Safety
+    ///
+    /// Should be called on a TF_OpKernelContext received by kernel compute function
+    pub unsafe fn inputs_info(self: *mut Self) -> Vec<TensorInfo> {
+        (0..TF_NumInputs(self))
+            .filter_map(|i| match self.get_input(i) {
+                Ok(input) => {
+                    let info = TensorInfo {
+                        dims: input.dims(),
+                        dtype: TF_TensorType(input),
+                        bytes: TF_TensorByteSize(input) as u64,
+                    };
+                    TF_DeleteTensor(input);
+                    Some(info)
+                }
+                Err(status) => {
+                    TF_DeleteStatus(status);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Outputs the kernel allocated through `allocate_output`, in allocation
+    /// order. Forgets them, so it should be called once the kernel is done
+    pub fn take_outputs_info(self: *mut Self) -> Vec<TensorInfo> {
+        ALLOCATED_OUTPUTS.with(|outputs| {
+            let mut outputs = outputs.borrow_mut();
+            let (taken, kept) = outputs.drain(..).partition(|(ctx, _)| *ctx == self);
+            *outputs = kept;
+            taken.into_iter().map(|(_, info)| info).collect()
+        })
+    }
+
     /// # Safety
     ///
     /// Should be called on a TF_OpKernelContext received by kernel compute function
@@ -123,14 +305,15 @@ impl TF_OpKernelContext {
     pub unsafe fn allocate_output(
         self: *mut Self,
         i: i32,
-        dims: &Vec<i64>,
+        dims: &[i64],
         len: u64,
     ) -> Result<*mut TF_Tensor, *mut TF_Status> {
         let status = TF_NewStatus();
+        let dtype = TF_ExpectedOutputDataType(self, i);
         let output = TF_AllocateOutput(
             self,
             i,
-            TF_ExpectedOutputDataType(self, i),
+            dtype,
             dims.as_ptr(),
             dims.len() as i32,
             len,
@@ -139,6 +322,12 @@ impl TF_OpKernelContext {
 
         if status.is_ok() {
             TF_DeleteStatus(status);
+            let info = TensorInfo {
+                dims: dims.to_vec(),
+                dtype,
+                bytes: len,
+            };
+            ALLOCATED_OUTPUTS.with(|outputs| outputs.borrow_mut().push((self, info)));
             Ok(output)
         } else {
             Err(status)
@@ -146,6 +335,37 @@ impl TF_OpKernelContext {
     }
 }
 
+/// Shape, type and size of a tensor
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorInfo {
+    pub dims: Vec<i64>,
+    pub dtype: TF_DataType,
+    pub bytes: u64,
+}
+
+// Names match the ones TF uses in graphs and errors
+pub fn data_type_name(dtype: TF_DataType) -> &'static str {
+    match dtype {
+        TF_FLOAT => "float",
+        TF_DOUBLE => "double",
+        TF_HALF => "half",
+        TF_BFLOAT16 => "bfloat16",
+        TF_INT8 => "int8",
+        TF_INT16 => "int16",
+        TF_INT32 => "int32",
+        TF_INT64 => "int64",
+        TF_UINT8 => "uint8",
+        TF_UINT16 => "uint16",
+        TF_UINT32 => "uint32",
+        TF_UINT64 => "uint64",
+        TF_BOOL => "bool",
+        TF_STRING => "string",
+        TF_COMPLEX64 => "complex64",
+        TF_COMPLEX128 => "complex128",
+        _ => "unknown",
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct NamedDims {
     pub n: i64,
@@ -190,7 +410,7 @@ impl TF_Tensor {
     /// # Safety
     ///
     /// Should be called on an initialized TF_Tensor
-    pub unsafe fn dims_named(self: *mut Self, format: &String) -> NamedDims {
+    pub unsafe fn dims_named(self: *mut Self, format: &str) -> NamedDims {
         let dims = self.dims();
 
         assert!(dims.len() == 4);
@@ -256,7 +476,8 @@ fn get_format_indices(format: &str) -> (usize, usize, usize, usize) {
 
 #[cfg(test)]
 mod tests {
-    use super::offset_from_tensor_coordinates;
+    use super::{offset_from_tensor_coordinates, TensorInfo, ALLOCATED_OUTPUTS};
+    use crate::raw::{TF_OpKernelContext, TF_FLOAT};
 
     // Our test tensor is 2x2x2x2
     // n=0 c=0
@@ -363,4 +584,23 @@ mod tests {
 
         offset_test_base(raw, [2, 2, 2, 2], "CNHW");
     }
+
+    #[test]
+    fn outputs_info_per_context() {
+        let (first, second) = (8 as *mut TF_OpKernelContext, 16 as *mut TF_OpKernelContext);
+        let info = |bytes| TensorInfo {
+            dims: vec![bytes as i64 / 4],
+            dtype: TF_FLOAT,
+            bytes,
+        };
+        ALLOCATED_OUTPUTS.with(|outputs| {
+            outputs
+                .borrow_mut()
+                .extend([(first, info(4)), (second, info(8)), (first, info(12))])
+        });
+
+        assert_eq!(first.take_outputs_info(), [info(4), info(12)]);
+        assert_eq!(first.take_outputs_info(), []);
+        assert_eq!(second.take_outputs_info(), [info(8)]);
+    }
 }
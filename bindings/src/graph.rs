@@ -0,0 +1,429 @@
+// Messages of TF's `tensorflow/core/framework/graph.proto` and the protos it
+// depends on, which graph optimizer plugins receive and return serialized.
+// Parts no pass looks into are kept as their encoded bytes, so that graphs
+// survive a round-trip unchanged. prost drops fields it doesn't know, so every
+// field of these messages up to TF 2.16 is declared
+use std::collections::BTreeMap;
+
+use crate::raw::{TF_DataType, TF_INT32, TF_INT64};
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct GraphDef {
+    #[prost(message, repeated, tag = "1")]
+    pub node: Vec<NodeDef>,
+    #[prost(message, optional, tag = "4")]
+    pub versions: Option<VersionDef>,
+    // Deprecated in favor of `versions`
+    #[prost(int32, tag = "3")]
+    pub version: i32,
+    // Encoded FunctionDefLibrary
+    #[prost(bytes = "vec", optional, tag = "2")]
+    pub library: Option<Vec<u8>>,
+    // Encoded GraphDebugInfo
+    #[prost(bytes = "vec", optional, tag = "5")]
+    pub debug_info: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NodeDef {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(string, tag = "2")]
+    pub op: String,
+    /// `node`, `node:output` or `^node` for control dependencies, which come last
+    #[prost(string, repeated, tag = "3")]
+    pub input: Vec<String>,
+    #[prost(string, tag = "4")]
+    pub device: String,
+    #[prost(btree_map = "string, message", tag = "5")]
+    pub attr: BTreeMap<String, AttrValue>,
+    // Encoded NodeDef.ExperimentalDebugInfo
+    #[prost(bytes = "vec", optional, tag = "6")]
+    pub experimental_debug_info: Option<Vec<u8>>,
+    // Encoded FullTypeDef
+    #[prost(bytes = "vec", optional, tag = "7")]
+    pub experimental_type: Option<Vec<u8>>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct VersionDef {
+    #[prost(int32, tag = "1")]
+    pub producer: i32,
+    #[prost(int32, tag = "2")]
+    pub min_consumer: i32,
+    #[prost(int32, repeated, tag = "3")]
+    pub bad_consumers: Vec<i32>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct AttrValue {
+    #[prost(oneof = "AttrValueValue", tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10")]
+    pub value: Option<AttrValueValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum AttrValueValue {
+    #[prost(message, tag = "1")]
+    List(ListValue),
+    #[prost(bytes, tag = "2")]
+    S(Vec<u8>),
+    #[prost(int64, tag = "3")]
+    I(i64),
+    #[prost(float, tag = "4")]
+    F(f32),
+    #[prost(bool, tag = "5")]
+    B(bool),
+    // A TF_DataType
+    #[prost(int32, tag = "6")]
+    Type(i32),
+    #[prost(message, tag = "7")]
+    Shape(TensorShapeProto),
+    // Boxed, as tensors are much larger than the other values
+    #[prost(message, boxed, tag = "8")]
+    Tensor(Box<TensorProto>),
+    #[prost(string, tag = "9")]
+    Placeholder(String),
+    #[prost(message, tag = "10")]
+    Func(NameAttrList),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct ListValue {
+    #[prost(bytes = "vec", repeated, tag = "2")]
+    pub s: Vec<Vec<u8>>,
+    #[prost(int64, repeated, tag = "3")]
+    pub i: Vec<i64>,
+    #[prost(float, repeated, tag = "4")]
+    pub f: Vec<f32>,
+    #[prost(bool, repeated, tag = "5")]
+    pub b: Vec<bool>,
+    #[prost(int32, repeated, tag = "6")]
+    pub r#type: Vec<i32>,
+    #[prost(message, repeated, tag = "7")]
+    pub shape: Vec<TensorShapeProto>,
+    #[prost(message, repeated, tag = "8")]
+    pub tensor: Vec<TensorProto>,
+    #[prost(message, repeated, tag = "9")]
+    pub func: Vec<NameAttrList>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct NameAttrList {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(btree_map = "string, message", tag = "2")]
+    pub attr: BTreeMap<String, AttrValue>,
+}
+
+/// Dimensions of size -1 are unknown
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TensorShapeProto {
+    #[prost(message, repeated, tag = "2")]
+    pub dim: Vec<Dim>,
+    #[prost(bool, tag = "3")]
+    pub unknown_rank: bool,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Dim {
+    #[prost(int64, tag = "1")]
+    pub size: i64,
+    #[prost(string, tag = "2")]
+    pub name: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TensorProto {
+    #[prost(int32, tag = "1")]
+    pub dtype: i32,
+    #[prost(message, optional, tag = "2")]
+    pub tensor_shape: Option<TensorShapeProto>,
+    #[prost(int32, tag = "3")]
+    pub version_number: i32,
+    #[prost(bytes = "vec", tag = "4")]
+    pub tensor_content: Vec<u8>,
+    // Bit patterns of half and bfloat16 values
+    #[prost(int32, repeated, tag = "13")]
+    pub half_val: Vec<i32>,
+    #[prost(float, repeated, tag = "5")]
+    pub float_val: Vec<f32>,
+    #[prost(double, repeated, tag = "6")]
+    pub double_val: Vec<f64>,
+    #[prost(int32, repeated, tag = "7")]
+    pub int_val: Vec<i32>,
+    #[prost(bytes = "vec", repeated, tag = "8")]
+    pub string_val: Vec<Vec<u8>>,
+    #[prost(float, repeated, tag = "9")]
+    pub scomplex_val: Vec<f32>,
+    #[prost(int64, repeated, tag = "10")]
+    pub int64_val: Vec<i64>,
+    #[prost(bool, repeated, tag = "11")]
+    pub bool_val: Vec<bool>,
+    #[prost(double, repeated, tag = "12")]
+    pub dcomplex_val: Vec<f64>,
+    // Encoded ResourceHandleProtos
+    #[prost(bytes = "vec", repeated, tag = "14")]
+    pub resource_handle_val: Vec<Vec<u8>>,
+    // Encoded VariantTensorDataProtos
+    #[prost(bytes = "vec", repeated, tag = "15")]
+    pub variant_val: Vec<Vec<u8>>,
+    #[prost(uint32, repeated, tag = "16")]
+    pub uint32_val: Vec<u32>,
+    #[prost(uint64, repeated, tag = "17")]
+    pub uint64_val: Vec<u64>,
+    // Bit patterns of float8 values, a byte each
+    #[prost(bytes = "vec", tag = "18")]
+    pub float8_val: Vec<u8>,
+}
+
+impl TensorShapeProto {
+    pub fn new(dims: &[i64]) -> Self {
+        Self {
+            dim: dims
+                .iter()
+                .map(|&size| Dim {
+                    size,
+                    name: String::new(),
+                })
+                .collect(),
+            unknown_rank: false,
+        }
+    }
+
+    /// Sizes of the dimensions, None if the rank is unknown
+    pub fn dims(&self) -> Option<Vec<i64>> {
+        (!self.unknown_rank).then(|| self.dim.iter().map(|dim| dim.size).collect())
+    }
+}
+
+impl AttrValue {
+    pub fn string(value: &str) -> Self {
+        Self {
+            value: Some(AttrValueValue::S(value.as_bytes().to_vec())),
+        }
+    }
+
+    pub fn int(value: i64) -> Self {
+        Self {
+            value: Some(AttrValueValue::I(value)),
+        }
+    }
+
+    pub fn string_list(values: &[&str]) -> Self {
+        Self {
+            value: Some(AttrValueValue::List(ListValue {
+                s: values
+                    .iter()
+                    .map(|value| value.as_bytes().to_vec())
+                    .collect(),
+                ..Default::default()
+            })),
+        }
+    }
+
+    pub fn data_type(value: TF_DataType) -> Self {
+        Self {
+            value: Some(AttrValueValue::Type(value as i32)),
+        }
+    }
+
+    pub fn tensor(value: TensorProto) -> Self {
+        Self {
+            value: Some(AttrValueValue::Tensor(Box::new(value))),
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match &self.value {
+            Some(AttrValueValue::S(value)) => std::str::from_utf8(value).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self.value {
+            Some(AttrValueValue::I(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_data_type(&self) -> Option<TF_DataType> {
+        match self.value {
+            Some(AttrValueValue::Type(value)) => Some(value as TF_DataType),
+            _ => None,
+        }
+    }
+
+    pub fn as_tensor(&self) -> Option<&TensorProto> {
+        match &self.value {
+            Some(AttrValueValue::Tensor(value)) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl TensorProto {
+    /// Vector of int32 values
+    pub fn int32s(values: &[i32]) -> Self {
+        Self {
+            dtype: TF_INT32 as i32,
+            tensor_shape: Some(TensorShapeProto::new(&[values.len() as i64])),
+            int_val: values.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    /// Values of an int32 or int64 tensor, whether listed or packed into
+    /// `tensor_content`. A single listed value fills the whole shape
+    pub fn ints(&self) -> Option<Vec<i64>> {
+        let (size, listed): (usize, Vec<i64>) = match self.dtype as TF_DataType {
+            TF_INT32 => (4, self.int_val.iter().map(|&value| value as i64).collect()),
+            TF_INT64 => (8, self.int64_val.clone()),
+            _ => return None,
+        };
+        if !self.tensor_content.is_empty() {
+            return Some(
+                self.tensor_content
+                    .chunks_exact(size)
+                    .map(|bytes| match size {
+                        4 => i32::from_le_bytes(bytes.try_into().unwrap()) as i64,
+                        _ => i64::from_le_bytes(bytes.try_into().unwrap()),
+                    })
+                    .collect(),
+            );
+        }
+
+        let elements = self
+            .tensor_shape
+            .as_ref()
+            .and_then(TensorShapeProto::dims)
+            .map_or(1, |dims| dims.iter().product::<i64>().max(0) as usize);
+        match listed.as_slice() {
+            [value] => Some(vec![*value; elements]),
+            _ => Some(listed),
+        }
+    }
+}
+
+impl NodeDef {
+    /// Data inputs, without control dependencies
+    pub fn data_inputs(&self) -> impl Iterator<Item = &str> {
+        self.input
+            .iter()
+            .map(String::as_str)
+            .filter(|input| !input.starts_with('^'))
+    }
+
+    /// Nodes this node waits for without consuming their outputs
+    pub fn control_inputs(&self) -> impl Iterator<Item = &str> {
+        self.input
+            .iter()
+            .filter_map(|input| input.strip_prefix('^'))
+    }
+}
+
+/// Node and output index of an input, `node` being output 0 of `node`
+pub fn parse_input(input: &str) -> (&str, i32) {
+    let input = input.trim_start_matches('^');
+    match input.rsplit_once(':') {
+        Some((node, index)) => match index.parse() {
+            Ok(index) => (node, index),
+            Err(_) => (input, 0),
+        },
+        None => (input, 0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_input, AttrValue, GraphDef, NodeDef, TensorProto, TensorShapeProto};
+    use crate::raw::{TF_FLOAT, TF_INT32, TF_INT64};
+    use prost::Message;
+
+    #[test]
+    fn graph_keeps_opaque_fields() {
+        let mut node = NodeDef {
+            name: "relu".to_owned(),
+            op: "Relu".to_owned(),
+            input: vec!["x".to_owned(), "^init".to_owned()],
+            experimental_type: Some(vec![8, 1]),
+            ..Default::default()
+        };
+        node.attr
+            .insert("T".to_owned(), AttrValue::data_type(TF_FLOAT));
+        let graph = GraphDef {
+            node: vec![node],
+            library: Some(vec![]),
+            ..Default::default()
+        };
+
+        let decoded = GraphDef::decode(&graph.encode_to_vec()[..]).unwrap();
+        assert_eq!(decoded, graph);
+        assert_eq!(decoded.node[0].attr["T"].as_data_type(), Some(TF_FLOAT));
+        assert_eq!(decoded.node[0].data_inputs().collect::<Vec<_>>(), ["x"]);
+        assert_eq!(
+            decoded.node[0].control_inputs().collect::<Vec<_>>(),
+            ["init"]
+        );
+    }
+
+    #[test]
+    fn graph_keeps_every_tensor_field() {
+        let tensor = TensorProto {
+            dtype: TF_FLOAT as i32,
+            tensor_shape: Some(TensorShapeProto::new(&[2, -1])),
+            version_number: 1,
+            tensor_content: vec![1, 2],
+            half_val: vec![0x3c00],
+            float_val: vec![1.0],
+            double_val: vec![2.0],
+            int_val: vec![3],
+            string_val: vec![b"s".to_vec()],
+            scomplex_val: vec![4.0, 5.0],
+            int64_val: vec![6],
+            bool_val: vec![true],
+            dcomplex_val: vec![7.0, 8.0],
+            resource_handle_val: vec![vec![9]],
+            variant_val: vec![vec![10]],
+            uint32_val: vec![11],
+            uint64_val: vec![12],
+            float8_val: vec![0x38, 0x40],
+        };
+        let attr = AttrValue::tensor(tensor.clone());
+        let decoded = AttrValue::decode(&attr.encode_to_vec()[..]).unwrap();
+        assert_eq!(decoded.as_tensor(), Some(&tensor));
+
+        // Field 18 as TF writes it, length-delimited
+        let float8 = TensorProto::decode(&[0x92, 0x01, 0x02, 0x38, 0x40][..]).unwrap();
+        assert_eq!(float8.float8_val, [0x38, 0x40]);
+    }
+
+    #[test]
+    fn graph_tensor_ints() {
+        let tensor = TensorProto::int32s(&[0, 2, 3, 1]);
+        assert_eq!(tensor.ints(), Some(vec![0, 2, 3, 1]));
+
+        let packed = TensorProto {
+            dtype: TF_INT64 as i32,
+            tensor_content: [3i64, -1].iter().flat_map(|v| v.to_le_bytes()).collect(),
+            ..Default::default()
+        };
+        assert_eq!(packed.ints(), Some(vec![3, -1]));
+
+        let splat = TensorProto {
+            dtype: TF_INT32 as i32,
+            tensor_shape: Some(TensorShapeProto::new(&[3])),
+            int_val: vec![7],
+            ..Default::default()
+        };
+        assert_eq!(splat.ints(), Some(vec![7, 7, 7]));
+    }
+
+    #[test]
+    fn graph_parse_input() {
+        assert_eq!(parse_input("x"), ("x", 0));
+        assert_eq!(parse_input("split:1"), ("split", 1));
+        assert_eq!(parse_input("^init"), ("init", 0));
+        assert_eq!(parse_input("scope/a:b"), ("scope/a:b", 0));
+    }
+}
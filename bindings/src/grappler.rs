@@ -0,0 +1,278 @@
+// What grappler knows about the graph an optimizer plugin is given: nodes which
+// have to survive optimization, and statically inferred tensor properties
+use std::{
+    ffi::{c_char, c_int, c_void, CString},
+    ptr::null_mut,
+};
+
+use prost::Message;
+
+use super::{
+    compute::Status,
+    graph::{TensorProto, TensorShapeProto},
+    raw::*,
+};
+
+/// OpInfo.TensorProperties of `tensorflow/core/protobuf/op_performance_data.proto`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TensorProperties {
+    // A TF_DataType
+    #[prost(int32, tag = "1")]
+    pub dtype: i32,
+    #[prost(message, optional, tag = "2")]
+    pub shape: Option<TensorShapeProto>,
+    // Only set for constant tensors when values were requested
+    #[prost(message, optional, tag = "3")]
+    pub value: Option<TensorProto>,
+}
+
+impl TensorProperties {
+    pub fn data_type(&self) -> TF_DataType {
+        self.dtype as TF_DataType
+    }
+
+    /// Sizes of the dimensions, -1 for unknown ones, None if the rank is unknown
+    pub fn dims(&self) -> Option<Vec<i64>> {
+        self.shape.as_ref().and_then(TensorShapeProto::dims)
+    }
+}
+
+type ListSizeFn =
+    unsafe extern "C" fn(*const TF_GrapplerItem, *mut c_int, *mut u64, *mut TF_Status);
+type ListFn = unsafe extern "C" fn(
+    *const TF_GrapplerItem,
+    *mut *mut c_char,
+    *mut u64,
+    c_int,
+    *mut c_void,
+    u64,
+    *mut TF_Status,
+);
+
+// TF copies the strings into storage sized by the first call
+unsafe fn string_list(
+    item: *const TF_GrapplerItem,
+    list_size: ListSizeFn,
+    list: ListFn,
+) -> Result<Vec<String>, Status> {
+    let status = Status::default();
+    let mut num_values = 0;
+    let mut storage_size = 0;
+    list_size(item, &mut num_values, &mut storage_size, status.as_ptr());
+    let status = status.check()?;
+
+    let mut values = vec![null_mut(); num_values as usize];
+    let mut lengths = vec![0; num_values as usize];
+    let mut storage = vec![0u8; storage_size as usize];
+    list(
+        item,
+        values.as_mut_ptr(),
+        lengths.as_mut_ptr(),
+        num_values,
+        storage.as_mut_ptr() as *mut c_void,
+        storage_size,
+        status.as_ptr(),
+    );
+    status.check()?;
+
+    Ok(decode_string_list(&values, &lengths))
+}
+
+// Strings the list function pointed `values` at, within its storage
+unsafe fn decode_string_list(values: &[*mut c_char], lengths: &[u64]) -> Vec<String> {
+    values
+        .iter()
+        .zip(lengths)
+        .map(|(&value, &length)| {
+            let bytes = std::slice::from_raw_parts(value as *const u8, length as usize);
+            String::from_utf8_lossy(bytes).into_owned()
+        })
+        .collect()
+}
+
+impl TF_GrapplerItem {
+    /// # Safety
+    ///
+    /// Should be called on the TF_GrapplerItem received by an optimize function
+    /// Includes fetch nodes, nodes fed with inputs and those of the signature
+    pub unsafe fn nodes_to_preserve(self: *const Self) -> Result<Vec<String>, Status> {
+        string_list(
+            self,
+            TF_GetNodesToPreserveListSize,
+            TF_GetNodesToPreserveList,
+        )
+    }
+
+    /// # Safety
+    ///
+    /// Should be called on the TF_GrapplerItem received by an optimize function
+    pub unsafe fn fetch_nodes(self: *const Self) -> Result<Vec<String>, Status> {
+        string_list(self, TF_GetFetchNodesListSize, TF_GetFetchNodesList)
+    }
+}
+
+/// Tensor properties of the graph of a TF_GrapplerItem, available once inferred
+pub struct GraphProperties {
+    raw: *mut TF_GraphProperties,
+}
+
+impl GraphProperties {
+    /// # Safety
+    ///
+    /// `item` should be the TF_GrapplerItem received by an optimize function,
+    /// and outlive the properties
+    pub unsafe fn new(item: *const TF_GrapplerItem) -> Self {
+        Self {
+            raw: TF_NewGraphProperties(item),
+        }
+    }
+
+    /// Infers shapes and dtypes without running the graph. Feeds of unknown shape
+    /// are taken to match their placeholders with `assume_valid_feeds`
+    pub fn infer_statically(
+        &mut self,
+        assume_valid_feeds: bool,
+        aggressive_shape_inference: bool,
+        include_tensor_values: bool,
+    ) -> Result<(), Status> {
+        let status = Status::default();
+        unsafe {
+            TF_InferStatically(
+                self.raw,
+                assume_valid_feeds as TF_Bool,
+                aggressive_shape_inference as TF_Bool,
+                include_tensor_values as TF_Bool,
+                include_tensor_values as TF_Bool,
+                status.as_ptr(),
+            )
+        };
+        status.check().map(drop)
+    }
+
+    pub fn input_properties(&self, node: &str) -> Result<Vec<TensorProperties>, Status> {
+        unsafe {
+            self.properties(
+                node,
+                TF_GetInputPropertiesListSize,
+                TF_GetInputPropertiesList,
+            )
+        }
+    }
+
+    pub fn output_properties(&self, node: &str) -> Result<Vec<TensorProperties>, Status> {
+        unsafe {
+            self.properties(
+                node,
+                TF_GetOutputPropertiesListSize,
+                TF_GetOutputPropertiesList,
+            )
+        }
+    }
+
+    unsafe fn properties(
+        &self,
+        node: &str,
+        list_size: unsafe extern "C" fn(
+            *mut TF_GraphProperties,
+            *const c_char,
+            *mut c_int,
+            *mut TF_Status,
+        ),
+        list: unsafe extern "C" fn(
+            *mut TF_GraphProperties,
+            *const c_char,
+            *mut *mut TF_Buffer,
+            c_int,
+            *mut TF_Status,
+        ),
+    ) -> Result<Vec<TensorProperties>, Status> {
+        let node = CString::new(node)
+            .map_err(|_| Status::new(TF_INVALID_ARGUMENT, "Node name contains a zero byte"))?;
+        let status = Status::default();
+        let mut num_values = 0;
+        list_size(self.raw, node.as_ptr(), &mut num_values, status.as_ptr());
+        let status = status.check()?;
+
+        let mut buffers: Vec<_> = (0..num_values).map(|_| TF_NewBuffer()).collect();
+        list(
+            self.raw,
+            node.as_ptr(),
+            buffers.as_mut_ptr(),
+            num_values,
+            status.as_ptr(),
+        );
+        let properties = status.check().and_then(|_| {
+            buffers
+                .iter()
+                .map(|&buffer| {
+                    decode_properties(buffer).map_err(|error| {
+                        Status::new(
+                            TF_INTERNAL,
+                            &format!("Failed to decode tensor properties: {}", error),
+                        )
+                    })
+                })
+                .collect()
+        });
+        for buffer in buffers {
+            TF_DeleteBuffer(buffer);
+        }
+        properties
+    }
+}
+
+impl Drop for GraphProperties {
+    fn drop(&mut self) {
+        unsafe { TF_DeleteGraphProperties(self.raw) }
+    }
+}
+
+unsafe fn decode_properties(
+    buffer: *const TF_Buffer,
+) -> Result<TensorProperties, prost::DecodeError> {
+    let data = match (*buffer).data.is_null() {
+        true => &[][..],
+        false => std::slice::from_raw_parts((*buffer).data as *const u8, (*buffer).length as usize),
+    };
+    TensorProperties::decode(data)
+}
+
+#[cfg(test)]
+mod tests {
+    // Only the decoding is tested, the rest calls into TF
+    use super::{decode_properties, decode_string_list, TensorProperties};
+    use crate::{
+        graph::TensorShapeProto,
+        raw::{TF_Buffer, TF_FLOAT},
+    };
+    use prost::Message;
+    use std::ffi::{c_char, c_void};
+
+    #[test]
+    fn grappler_string_list() {
+        // Laid out like TF does, one after the other in the storage
+        let mut storage = *b"reluoutput";
+        let base = storage.as_mut_ptr() as *mut c_char;
+        let values = unsafe { [base, base.add(4)] };
+        let nodes = unsafe { decode_string_list(&values, &[4, 6]) };
+        assert_eq!(nodes, ["relu", "output"]);
+    }
+
+    #[test]
+    fn grappler_decode_properties() {
+        let properties = TensorProperties {
+            dtype: TF_FLOAT as i32,
+            shape: Some(TensorShapeProto::new(&[-1, 3])),
+            value: None,
+        };
+        let data = properties.encode_to_vec();
+        let buffer = TF_Buffer {
+            data: data.as_ptr() as *const c_void,
+            length: data.len() as u64,
+            data_deallocator: None,
+        };
+        let decoded = unsafe { decode_properties(&buffer) }.unwrap();
+        assert_eq!(decoded.data_type(), TF_FLOAT);
+        assert_eq!(decoded.dims(), Some(vec![-1, 3]));
+    }
+}
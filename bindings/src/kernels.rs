@@ -1,7 +1,24 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, sync::Mutex};
 
 use super::raw::*;
 
+/// Kernel registered through a KernelBuilder
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisteredKernel {
+    /// Op the kernel implements
+    pub op: &'static str,
+    pub device_type: &'static str,
+    /// Types of the type attrs the kernel is constrained to
+    pub constraints: HashMap<&'static str, TF_DataType>,
+}
+
+static REGISTERED_KERNELS: Mutex<Vec<RegisteredKernel>> = Mutex::new(Vec::new());
+
+/// Kernels registered so far, in order of registration
+pub fn registered_kernels() -> Vec<RegisteredKernel> {
+    REGISTERED_KERNELS.lock().unwrap().clone()
+}
+
 pub struct KernelBuilder<T> {
     kernel_name: &'static str,
     op_name: &'static str,
@@ -66,6 +83,16 @@ impl<T> KernelBuilder<T> {
     }
 
     pub fn register(self) {
+        let op_name = self.op_name.trim_end_matches('\0');
+        let registered = RegisteredKernel {
+            op: self.kernel_name.trim_end_matches('\0'),
+            device_type: self.device_type.trim_end_matches('\0'),
+            constraints: self
+                .constraints
+                .iter()
+                .map(|(&name, &dt)| (name.trim_end_matches('\0'), dt))
+                .collect(),
+        };
         unsafe {
             let builder = TF_NewKernelBuilder(
                 self.kernel_name.as_ptr() as *const i8,
@@ -79,9 +106,11 @@ impl<T> KernelBuilder<T> {
                 let status = TF_NewStatus();
                 TF_KernelBuilder_TypeConstraint(builder, name.as_ptr() as *const i8, dt, status);
                 if TF_OK != TF_GetCode(status) {
-                    eprintln!(
-                        "Error while registering {} kernel with attribute {}",
-                        self.op_name, name
+                    log::error!(
+                        "Error while registering {} kernel with attribute {}: {}",
+                        op_name,
+                        name.trim_end_matches('\0'),
+                        status.message()
                     );
                     TF_DeleteStatus(status);
                     return;
@@ -92,11 +121,17 @@ impl<T> KernelBuilder<T> {
             let status = TF_NewStatus();
             TF_RegisterKernelBuilder(self.op_name.as_ptr() as *const i8, builder, status);
             if TF_OK != TF_GetCode(status) {
-                eprintln!("Error while registering {} kernel", self.op_name);
+                log::error!(
+                    "Error while registering {} kernel: {}",
+                    op_name,
+                    status.message()
+                );
                 TF_DeleteStatus(status);
                 return;
             }
             TF_DeleteStatus(status);
+            REGISTERED_KERNELS.lock().unwrap().push(registered);
+            log::debug!("Registered {} kernel", op_name);
         }
     }
 }
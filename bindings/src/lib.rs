@@ -1,5 +1,11 @@
 #![feature(arbitrary_self_types)]
 
 pub mod compute;
+pub mod graph;
+pub mod grappler;
 pub mod kernels;
+pub mod ops;
 pub mod raw;
+pub mod shape;
+pub mod stats;
+pub mod xplane;
@@ -0,0 +1,191 @@
+// Definitions of new ops, the counterpart of KernelBuilder for ops TF doesn't
+// have. Specs use the syntax of REGISTER_OP, e.g. "value: T" for an input and
+// "T: {float, half}" for an attr
+use super::{
+    compute::Status,
+    raw::*,
+    shape::{shape_inference_fn, ShapeFn},
+};
+
+pub type ShapeInferenceFn = unsafe extern "C" fn(*mut TF_ShapeInferenceContext, *mut TF_Status);
+
+pub struct OpBuilder {
+    op_name: &'static str,
+    inputs: Vec<&'static str>,
+    outputs: Vec<&'static str>,
+    attrs: Vec<&'static str>,
+    is_commutative: bool,
+    is_aggregate: bool,
+    is_stateful: bool,
+    allows_uninitialized_input: bool,
+    deprecation: Option<(i32, &'static str)>,
+    shape_inference_fn: Option<ShapeInferenceFn>,
+}
+
+impl OpBuilder {
+    pub fn new(op_name: &'static str) -> Self {
+        assert!(op_name.ends_with('\0'), "Strings must be zero-terminated");
+        Self {
+            op_name,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            attrs: Vec::new(),
+            is_commutative: false,
+            is_aggregate: false,
+            is_stateful: false,
+            allows_uninitialized_input: false,
+            deprecation: None,
+            shape_inference_fn: None,
+        }
+    }
+
+    /// `name: type`, e.g. "value: T" or "values: N * T"
+    pub fn input(mut self, spec: &'static str) -> Self {
+        assert!(spec.ends_with('\0'), "Strings must be zero-terminated");
+        self.inputs.push(spec);
+        self
+    }
+
+    pub fn output(mut self, spec: &'static str) -> Self {
+        assert!(spec.ends_with('\0'), "Strings must be zero-terminated");
+        self.outputs.push(spec);
+        self
+    }
+
+    /// `name: type = default`, e.g. "data_format: {'NHWC', 'NCHW'} = 'NHWC'"
+    pub fn attr(mut self, spec: &'static str) -> Self {
+        assert!(spec.ends_with('\0'), "Strings must be zero-terminated");
+        self.attrs.push(spec);
+        self
+    }
+
+    /// Inputs can be swapped, which lets grappler canonicalize them
+    pub fn commutative(mut self) -> Self {
+        self.is_commutative = true;
+        self
+    }
+
+    /// Adds up its inputs, which lets grappler reassociate it
+    pub fn aggregate(mut self) -> Self {
+        self.is_aggregate = true;
+        self
+    }
+
+    /// Has side effects or a result which isn't a function of its inputs,
+    /// so it's never folded, deduplicated or pruned as dead
+    pub fn stateful(mut self) -> Self {
+        self.is_stateful = true;
+        self
+    }
+
+    pub fn allows_uninitialized_input(mut self) -> Self {
+        self.allows_uninitialized_input = true;
+        self
+    }
+
+    /// Fails graphs from `version` on with `explanation`
+    pub fn deprecated(mut self, version: i32, explanation: &'static str) -> Self {
+        assert!(
+            explanation.ends_with('\0'),
+            "Strings must be zero-terminated"
+        );
+        self.deprecation = Some((version, explanation));
+        self
+    }
+
+    /// Without one, the shapes of the outputs are unknown
+    pub fn shape_inference(mut self, function: ShapeInferenceFn) -> Self {
+        self.shape_inference_fn = Some(function);
+        self
+    }
+
+    /// Shape function over an InferenceContext, a closure without captures
+    pub fn shape_fn(self, function: impl ShapeFn) -> Self {
+        self.shape_inference(shape_inference_fn(function))
+    }
+
+    pub fn register(self) {
+        let op_name = self.op_name.trim_end_matches('\0');
+        unsafe {
+            let builder = TF_NewOpDefinitionBuilder(self.op_name.as_ptr() as *const i8);
+            for input in self.inputs {
+                TF_OpDefinitionBuilderAddInput(builder, input.as_ptr() as *const i8);
+            }
+            for output in self.outputs {
+                TF_OpDefinitionBuilderAddOutput(builder, output.as_ptr() as *const i8);
+            }
+            for attr in self.attrs {
+                TF_OpDefinitionBuilderAddAttr(builder, attr.as_ptr() as *const i8);
+            }
+            TF_OpDefinitionBuilderSetIsCommutative(builder, self.is_commutative);
+            TF_OpDefinitionBuilderSetIsAggregate(builder, self.is_aggregate);
+            TF_OpDefinitionBuilderSetIsStateful(builder, self.is_stateful);
+            TF_OpDefinitionBuilderSetAllowsUninitializedInput(
+                builder,
+                self.allows_uninitialized_input,
+            );
+            if let Some((version, explanation)) = self.deprecation {
+                TF_OpDefinitionBuilderDeprecated(
+                    builder,
+                    version,
+                    explanation.as_ptr() as *const i8,
+                );
+            }
+            if self.shape_inference_fn.is_some() {
+                TF_OpDefinitionBuilderSetShapeInferenceFunction(builder, self.shape_inference_fn);
+            }
+
+            // Takes ownership of the builder. TF only queues the definition, its
+            // specs are parsed once TF finalizes its op registry, where invalid
+            // ones are fatal. Nothing is reported here, the status is always OK
+            let status = Status::default();
+            TF_RegisterOpDefinition(builder, status.as_ptr());
+            log::debug!("Queued registration of {} op", op_name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Shape functions are tested through InferenceContext, as wrapping one
+    // for TF references the C API
+    use super::OpBuilder;
+
+    #[test]
+    fn op_builder_state() {
+        let builder = OpBuilder::new("Test\0")
+            .input("x: T\0")
+            .input("y: T\0")
+            .output("z: T\0")
+            .attr("T: {float, half}\0")
+            .commutative()
+            .stateful()
+            .deprecated(42, "Use Other\0");
+
+        assert_eq!(builder.inputs, ["x: T\0", "y: T\0"]);
+        assert_eq!(builder.outputs, ["z: T\0"]);
+        assert_eq!(builder.attrs, ["T: {float, half}\0"]);
+        assert!(builder.is_commutative && builder.is_stateful);
+        assert!(!builder.is_aggregate && !builder.allows_uninitialized_input);
+        assert_eq!(builder.deprecation, Some((42, "Use Other\0")));
+        assert!(builder.shape_inference_fn.is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Strings must be zero-terminated")]
+    fn op_builder_name_needs_nul() {
+        OpBuilder::new("Test");
+    }
+
+    #[test]
+    #[should_panic(expected = "Strings must be zero-terminated")]
+    fn op_builder_spec_needs_nul() {
+        OpBuilder::new("Test\0").attr("T: type");
+    }
+
+    #[test]
+    #[should_panic(expected = "Strings must be zero-terminated")]
+    fn op_builder_explanation_needs_nul() {
+        OpBuilder::new("Test\0").deprecated(1, "Gone");
+    }
+}
@@ -0,0 +1,540 @@
+// Shape inference for ops defined with OpBuilder. Shape functions work on
+// plain Shapes within an InferenceContext, which is read from TF's context
+// before the function runs and written back after it, so they can be tested
+// on hand-built shapes without TF. Their errors are plain ShapeErrors rather
+// than the RAII Status used elsewhere in the bindings, since creating a Status
+// calls into TF, which tests can't link. They only become a TF status once the
+// function returns, and convert into a Status for callers which need one
+use std::{
+    fmt,
+    mem::size_of,
+    panic::{catch_unwind, AssertUnwindSafe},
+};
+
+use super::{compute::Status, raw::*};
+
+/// Shape of a tensor, dimensions of which may be unknown, as may the rank
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shape {
+    dims: Option<Vec<Option<i64>>>,
+}
+
+impl Shape {
+    /// Dimensions of size -1 are unknown
+    pub fn new(dims: &[i64]) -> Self {
+        Self::from_dims(dims.iter().map(|&dim| (dim >= 0).then_some(dim)).collect())
+    }
+
+    pub fn from_dims(dims: Vec<Option<i64>>) -> Self {
+        Self { dims: Some(dims) }
+    }
+
+    pub fn unknown() -> Self {
+        Self { dims: None }
+    }
+
+    pub fn unknown_with_rank(rank: usize) -> Self {
+        Self::from_dims(vec![None; rank])
+    }
+
+    pub fn scalar() -> Self {
+        Self::from_dims(Vec::new())
+    }
+
+    pub fn vector(size: Option<i64>) -> Self {
+        Self::from_dims(vec![size])
+    }
+
+    pub fn rank(&self) -> Option<usize> {
+        self.dims.as_ref().map(Vec::len)
+    }
+
+    pub fn dims(&self) -> Option<&[Option<i64>]> {
+        self.dims.as_deref()
+    }
+
+    /// Size of a dimension, None if it or the rank is unknown. Negative
+    /// indices count from the end
+    pub fn dim(&self, index: i64) -> Result<Option<i64>, ShapeError> {
+        let dims = match &self.dims {
+            Some(dims) => dims,
+            None => return Ok(None),
+        };
+        let position = match index < 0 {
+            true => dims.len() as i64 + index,
+            false => index,
+        };
+        usize::try_from(position)
+            .ok()
+            .and_then(|position| dims.get(position))
+            .copied()
+            .ok_or_else(|| {
+                invalid_argument(format!(
+                    "Out of range dimension index {} of shape {}",
+                    index, self
+                ))
+            })
+    }
+
+    pub fn is_fully_defined(&self) -> bool {
+        self.dims
+            .as_ref()
+            .is_some_and(|dims| dims.iter().all(Option::is_some))
+    }
+
+    /// Number of elements, if known
+    pub fn num_elements(&self) -> Option<i64> {
+        self.dims
+            .as_ref()?
+            .iter()
+            .try_fold(1, |n, dim| Some(n * (*dim)?))
+    }
+
+    /// This shape, with the rank given if it was unknown
+    pub fn with_rank(&self, rank: usize) -> Result<Self, ShapeError> {
+        match self.rank() {
+            None => Ok(Self::unknown_with_rank(rank)),
+            Some(actual) if actual == rank => Ok(self.clone()),
+            Some(actual) => Err(invalid_argument(format!(
+                "Shape must be rank {} but is rank {}",
+                rank, actual
+            ))),
+        }
+    }
+
+    pub fn with_rank_at_least(&self, rank: usize) -> Result<Self, ShapeError> {
+        match self.rank() {
+            Some(actual) if actual < rank => Err(invalid_argument(format!(
+                "Shape must be at least rank {} but is rank {}",
+                rank, actual
+            ))),
+            _ => Ok(self.clone()),
+        }
+    }
+
+    pub fn with_rank_at_most(&self, rank: usize) -> Result<Self, ShapeError> {
+        match self.rank() {
+            Some(actual) if actual > rank => Err(invalid_argument(format!(
+                "Shape must be at most rank {} but is rank {}",
+                rank, actual
+            ))),
+            _ => Ok(self.clone()),
+        }
+    }
+
+    /// Shape which is compatible with both, knowing what either knows
+    pub fn merge(&self, other: &Self) -> Result<Self, ShapeError> {
+        let (dims, other_dims) = match (&self.dims, &other.dims) {
+            (None, _) => return Ok(other.clone()),
+            (_, None) => return Ok(self.clone()),
+            (Some(dims), Some(other_dims)) => (dims, other_dims),
+        };
+        if dims.len() != other_dims.len() {
+            return Err(invalid_argument(format!(
+                "Shapes {} and {} must be equal rank",
+                self, other
+            )));
+        }
+        dims.iter()
+            .zip(other_dims)
+            .map(|(&dim, &other_dim)| match (dim, other_dim) {
+                (Some(size), Some(other_size)) if size != other_size => {
+                    Err(invalid_argument(format!(
+                        "Dimensions of shapes {} and {} must be equal, but are {} and {}",
+                        self, other, size, other_size
+                    )))
+                }
+                _ => Ok(dim.or(other_dim)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self::from_dims)
+    }
+
+    /// Dimensions of this shape followed by those of `other`
+    pub fn concatenate(&self, other: &Self) -> Self {
+        match (&self.dims, &other.dims) {
+            (Some(dims), Some(other_dims)) => {
+                Self::from_dims(dims.iter().chain(other_dims).copied().collect())
+            }
+            _ => Self::unknown(),
+        }
+    }
+
+    /// Dimensions from `start` up to `end`, which are clamped to the rank
+    pub fn subshape(&self, start: usize, end: usize) -> Self {
+        match &self.dims {
+            Some(dims) => {
+                let end = end.min(dims.len());
+                Self::from_dims(dims[start.min(end)..end].to_vec())
+            }
+            None => Self::unknown(),
+        }
+    }
+
+    /// Shape of the result of an elementwise op with broadcasting, following
+    /// numpy's rules: dimensions are aligned at the end, and those of size 1
+    /// stretch to match
+    pub fn broadcast(&self, other: &Self) -> Result<Self, ShapeError> {
+        let (dims, other_dims) = match (&self.dims, &other.dims) {
+            (Some(dims), Some(other_dims)) => (dims, other_dims),
+            _ => return Ok(Self::unknown()),
+        };
+        let rank = dims.len().max(other_dims.len());
+        let padded = |dims: &[Option<i64>]| {
+            std::iter::repeat_n(Some(1), rank - dims.len())
+                .chain(dims.iter().copied())
+                .collect::<Vec<_>>()
+        };
+        padded(dims)
+            .into_iter()
+            .zip(padded(other_dims))
+            .map(|dims| match dims {
+                (Some(1), dim) | (dim, Some(1)) => Ok(dim),
+                (Some(size), Some(other_size)) if size != other_size => {
+                    Err(invalid_argument(format!(
+                        "Incompatible shapes for broadcasting: {} and {}",
+                        self, other
+                    )))
+                }
+                // Unknown ones match the other, unless that's 1
+                (Some(size), _) | (_, Some(size)) => Ok(Some(size)),
+                (None, None) => Ok(None),
+            })
+            .collect::<Result<_, _>>()
+            .map(Self::from_dims)
+    }
+
+    unsafe fn from_handle(ctx: *mut TF_ShapeInferenceContext, handle: *mut TF_ShapeHandle) -> Self {
+        if TF_ShapeInferenceContextRankKnown(ctx, handle) == 0 {
+            return Self::unknown();
+        }
+        let rank = TF_ShapeInferenceContextRank(ctx, handle);
+        let dim = TF_NewDimensionHandle();
+        let dims = (0..rank)
+            .map(|i| {
+                TF_ShapeInferenceContextDim(ctx, handle, i, dim);
+                (TF_DimensionHandleValueKnown(dim) != 0).then(|| TF_DimensionHandleValue(dim))
+            })
+            .collect();
+        TF_DeleteDimensionHandle(dim);
+        Self::from_dims(dims)
+    }
+
+    // The C API can't make a shape of given dimensions, so it's concatenated
+    // from vectors. A size of u64::MAX is -1 to TF, an unknown dimension
+    unsafe fn to_handle(
+        ctx: *mut TF_ShapeInferenceContext,
+        dims: &[Option<i64>],
+    ) -> Result<*mut TF_ShapeHandle, ShapeError> {
+        let mut shape = TF_ShapeInferenceContextScalar(ctx);
+        for &dim in dims {
+            let vector =
+                TF_ShapeInferenceContextVectorFromSize(ctx, dim.map_or(u64::MAX, |dim| dim as u64));
+            let concatenated = TF_NewShapeHandle();
+            let status = Status::default();
+            TF_ShapeInferenceContextConcatenateShapes(
+                ctx,
+                shape,
+                vector,
+                concatenated,
+                status.as_ptr(),
+            );
+            TF_DeleteShapeHandle(shape);
+            TF_DeleteShapeHandle(vector);
+            shape = concatenated;
+            if let Err(status) = status.check() {
+                TF_DeleteShapeHandle(shape);
+                return Err(status.into());
+            }
+        }
+        Ok(shape)
+    }
+}
+
+/// Like TF's DebugString of shapes, e.g. `[2,?]`, or `?` if the rank is unknown
+impl fmt::Display for Shape {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let dims = match &self.dims {
+            Some(dims) => dims,
+            None => return f.write_str("?"),
+        };
+        let dims: Vec<_> = dims
+            .iter()
+            .map(|dim| match dim {
+                Some(size) => size.to_string(),
+                None => "?".to_owned(),
+            })
+            .collect();
+        write!(f, "[{}]", dims.join(","))
+    }
+}
+
+/// Failure of a shape function, reported to TF with its code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShapeError {
+    pub code: TF_Code,
+    pub message: String,
+}
+
+impl ShapeError {
+    pub fn new(code: TF_Code, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+// Errors of TF's shape inference context
+impl From<Status> for ShapeError {
+    fn from(status: Status) -> Self {
+        Self::new(status.code(), status.message())
+    }
+}
+
+impl From<ShapeError> for Status {
+    fn from(error: ShapeError) -> Self {
+        Status::new(error.code, &error.message)
+    }
+}
+
+impl fmt::Display for ShapeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+fn invalid_argument(message: String) -> ShapeError {
+    ShapeError::new(TF_INVALID_ARGUMENT, message)
+}
+
+/// Shapes of the inputs of an op and those inferred for its outputs
+pub struct InferenceContext {
+    raw: *mut TF_ShapeInferenceContext,
+    inputs: Vec<Shape>,
+    outputs: Vec<Shape>,
+}
+
+impl InferenceContext {
+    /// Context of hand-built input shapes, for testing shape functions
+    pub fn new(inputs: Vec<Shape>) -> Self {
+        Self {
+            raw: std::ptr::null_mut(),
+            inputs,
+            outputs: Vec::new(),
+        }
+    }
+
+    unsafe fn from_raw(raw: *mut TF_ShapeInferenceContext) -> Result<Self, ShapeError> {
+        let handle = TF_NewShapeHandle();
+        let inputs: Result<Vec<_>, _> = (0..TF_ShapeInferenceContextNumInputs(raw))
+            .map(|i| {
+                let status = Status::default();
+                TF_ShapeInferenceContextGetInput(raw, i as i32, handle, status.as_ptr());
+                status.check().map(|_| Shape::from_handle(raw, handle))
+            })
+            .collect();
+        TF_DeleteShapeHandle(handle);
+        Ok(Self {
+            raw,
+            inputs: inputs?,
+            outputs: Vec::new(),
+        })
+    }
+
+    pub fn num_inputs(&self) -> usize {
+        self.inputs.len()
+    }
+
+    pub fn input(&self, index: usize) -> Result<&Shape, ShapeError> {
+        self.inputs.get(index).ok_or_else(|| {
+            invalid_argument(format!(
+                "Input {} out of range, the op has {} inputs",
+                index,
+                self.inputs.len()
+            ))
+        })
+    }
+
+    /// Shape of an input, with the rank given if it was unknown
+    pub fn input_with_rank(&self, index: usize, rank: usize) -> Result<Shape, ShapeError> {
+        self.input(index)?.with_rank(rank)
+    }
+
+    /// Type of a type attr of the op, like "T"
+    pub fn attr_type(&self, name: &str) -> Result<TF_DataType, ShapeError> {
+        if self.raw.is_null() {
+            return Err(invalid_argument(format!(
+                "No attr {} in a hand-built context",
+                name
+            )));
+        }
+        let name = std::ffi::CString::new(name)
+            .map_err(|_| invalid_argument("Attr name contains a zero byte".to_owned()))?;
+        let status = Status::default();
+        let mut dtype = 0;
+        unsafe {
+            TF_ShapeInferenceContext_GetAttrType(
+                self.raw,
+                name.as_ptr(),
+                &mut dtype,
+                status.as_ptr(),
+            )
+        };
+        status.check().map(|_| dtype).map_err(ShapeError::from)
+    }
+
+    /// Outputs which aren't set are of unknown shape
+    pub fn set_output(&mut self, index: usize, shape: Shape) {
+        if self.outputs.len() <= index {
+            self.outputs.resize(index + 1, Shape::unknown());
+        }
+        self.outputs[index] = shape;
+    }
+
+    pub fn output(&self, index: usize) -> Option<&Shape> {
+        self.outputs.get(index)
+    }
+
+    // There's no unknown shape to set a single output to, so all are set
+    // unknown first
+    unsafe fn apply(&self) -> Result<(), ShapeError> {
+        let status = Status::default();
+        TF_ShapeInferenceContextSetUnknownShape(self.raw, status.as_ptr());
+        status.check()?;
+        for (i, output) in self.outputs.iter().enumerate() {
+            let dims = match output.dims() {
+                Some(dims) => dims,
+                None => continue,
+            };
+            let handle = Shape::to_handle(self.raw, dims)?;
+            let status = Status::default();
+            TF_ShapeInferenceContextSetOutput(self.raw, i as i32, handle, status.as_ptr());
+            TF_DeleteShapeHandle(handle);
+            status.check()?;
+        }
+        Ok(())
+    }
+}
+
+/// Shape function of an op, which sets the shapes of outputs
+pub trait ShapeFn: Fn(&mut InferenceContext) -> Result<(), ShapeError> + Copy + 'static {}
+
+impl<F: Fn(&mut InferenceContext) -> Result<(), ShapeError> + Copy + 'static> ShapeFn for F {}
+
+/// C function running `function`, which can't capture anything, since TF
+/// passes no data along to shape functions
+pub fn shape_inference_fn<F: ShapeFn>(
+    _function: F,
+) -> unsafe extern "C" fn(*mut TF_ShapeInferenceContext, *mut TF_Status) {
+    assert_eq!(size_of::<F>(), 0, "Shape functions can't capture variables");
+    run_shape_fn::<F>
+}
+
+unsafe extern "C" fn run_shape_fn<F: ShapeFn>(
+    ctx: *mut TF_ShapeInferenceContext,
+    status: *mut TF_Status,
+) {
+    // Unwinding into TF is undefined, so panics are reported as errors
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        // F has no data, so any value is the function
+        let function: F = std::mem::zeroed();
+        let mut context = InferenceContext::from_raw(ctx)?;
+        function(&mut context)?;
+        context.apply()
+    }))
+    .unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        Err(ShapeError::new(
+            TF_INTERNAL,
+            format!("Shape function panicked: {}", message),
+        ))
+    });
+    match result {
+        Ok(()) => status.set(TF_OK, ""),
+        Err(error) => status.set(error.code, &error.message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InferenceContext, Shape, ShapeError};
+    use crate::raw::TF_INVALID_ARGUMENT;
+
+    #[test]
+    fn shape_rank_and_merge() {
+        let shape = Shape::new(&[2, -1, 3]);
+        assert_eq!(shape.to_string(), "[2,?,3]");
+        assert_eq!(shape.rank(), Some(3));
+        assert_eq!(shape.dim(-1).unwrap(), Some(3));
+        assert_eq!(shape.dim(1).unwrap(), None);
+        assert!(shape.dim(3).is_err());
+        assert_eq!(shape.num_elements(), None);
+
+        assert_eq!(
+            Shape::unknown().with_rank(2).unwrap(),
+            Shape::new(&[-1, -1])
+        );
+        let error = shape.with_rank(2).unwrap_err();
+        assert_eq!(error.code, TF_INVALID_ARGUMENT);
+        assert_eq!(error.message, "Shape must be rank 2 but is rank 3");
+        assert!(shape.with_rank_at_least(3).is_ok());
+        assert!(shape.with_rank_at_most(2).is_err());
+
+        assert_eq!(
+            shape.merge(&Shape::new(&[-1, 4, -1])).unwrap(),
+            Shape::new(&[2, 4, 3])
+        );
+        assert_eq!(shape.merge(&Shape::unknown()).unwrap(), shape);
+        assert!(shape.merge(&Shape::new(&[2, 4, 5])).is_err());
+        assert!(shape.merge(&Shape::new(&[2, 4])).is_err());
+
+        assert_eq!(
+            shape.concatenate(&Shape::vector(Some(5))),
+            Shape::new(&[2, -1, 3, 5])
+        );
+        assert_eq!(shape.subshape(1, 5), Shape::new(&[-1, 3]));
+    }
+
+    #[test]
+    fn shape_broadcast() {
+        let broadcast = |a: &[i64], b: &[i64]| Shape::new(a).broadcast(&Shape::new(b));
+        assert_eq!(
+            broadcast(&[4, 1, 3], &[2, 1]).unwrap(),
+            Shape::new(&[4, 2, 3])
+        );
+        assert_eq!(broadcast(&[-1, 3], &[5, 1]).unwrap(), Shape::new(&[5, 3]));
+        assert_eq!(broadcast(&[-1], &[1]).unwrap(), Shape::new(&[-1]));
+        assert_eq!(broadcast(&[], &[2, 2]).unwrap(), Shape::new(&[2, 2]));
+        assert!(broadcast(&[2, 3], &[3, 2]).is_err());
+        assert_eq!(
+            Shape::unknown().broadcast(&Shape::scalar()).unwrap(),
+            Shape::unknown()
+        );
+    }
+
+    #[test]
+    fn shape_fn_on_hand_built_shapes() {
+        let matmul = |ctx: &mut InferenceContext| -> Result<(), ShapeError> {
+            let a = ctx.input_with_rank(0, 2)?;
+            let b = ctx.input_with_rank(1, 2)?;
+            // Inner dimensions have to agree
+            Shape::vector(a.dim(1)?).merge(&Shape::vector(b.dim(0)?))?;
+            ctx.set_output(0, Shape::from_dims(vec![a.dim(0)?, b.dim(1)?]));
+            Ok(())
+        };
+
+        let mut ctx = InferenceContext::new(vec![Shape::new(&[8, -1]), Shape::new(&[4, 2])]);
+        matmul(&mut ctx).unwrap();
+        assert_eq!(ctx.output(0), Some(&Shape::new(&[8, 2])));
+
+        let mut ctx = InferenceContext::new(vec![Shape::new(&[8, 3]), Shape::new(&[4, 2])]);
+        assert!(matmul(&mut ctx).is_err());
+        let mut ctx = InferenceContext::new(vec![Shape::new(&[8, 3])]);
+        assert!(matmul(&mut ctx).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+// Kernel statistics gathered by the plugin while TFP_KERNEL_STATS is set. The
+// plugin is a shared library with its own copy of every static, so processes
+// which loaded it read them through the function it exports, whose symbol is
+// looked up with dlsym like any other entry point of the plugin
+use std::{ffi::c_void, time::Duration};
+
+use prost::Message;
+
+/// Symbol of the `SnapshotFn` exported by the plugin
+pub const SNAPSHOT_SYMBOL: &str = "TFP_KernelStatsSnapshot\0";
+
+/// Passes the encoded snapshot to `write`, along with `arg`
+pub type SnapshotFn = unsafe extern "C" fn(write: WriteFn, arg: *mut c_void);
+pub type WriteFn = unsafe extern "C" fn(arg: *mut c_void, data: *const u8, len: usize);
+
+/// Statistics of an op and dtype at the time of the snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub struct KernelStats {
+    pub op_name: String,
+    // Of the first input, "none" for kernels without inputs
+    pub dtype: String,
+    pub count: u64,
+    pub total: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+impl KernelStats {
+    pub fn mean(&self) -> Duration {
+        self.total / self.count.max(1) as u32
+    }
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct Snapshot {
+    #[prost(message, repeated, tag = "1")]
+    kernels: Vec<Entry>,
+}
+
+// Durations in nanoseconds
+#[derive(Clone, PartialEq, prost::Message)]
+struct Entry {
+    #[prost(string, tag = "1")]
+    op_name: String,
+    #[prost(string, tag = "2")]
+    dtype: String,
+    #[prost(uint64, tag = "3")]
+    count: u64,
+    #[prost(uint64, tag = "4")]
+    total: u64,
+    #[prost(uint64, tag = "5")]
+    p50: u64,
+    #[prost(uint64, tag = "6")]
+    p90: u64,
+    #[prost(uint64, tag = "7")]
+    p99: u64,
+    #[prost(uint64, tag = "8")]
+    max: u64,
+    #[prost(uint64, tag = "9")]
+    bytes_read: u64,
+    #[prost(uint64, tag = "10")]
+    bytes_written: u64,
+}
+
+pub fn encode(stats: &[KernelStats]) -> Vec<u8> {
+    let nanos = |duration: Duration| duration.as_nanos() as u64;
+    Snapshot {
+        kernels: stats
+            .iter()
+            .map(|stats| Entry {
+                op_name: stats.op_name.clone(),
+                dtype: stats.dtype.clone(),
+                count: stats.count,
+                total: nanos(stats.total),
+                p50: nanos(stats.p50),
+                p90: nanos(stats.p90),
+                p99: nanos(stats.p99),
+                max: nanos(stats.max),
+                bytes_read: stats.bytes_read,
+                bytes_written: stats.bytes_written,
+            })
+            .collect(),
+    }
+    .encode_to_vec()
+}
+
+pub fn decode(data: &[u8]) -> Result<Vec<KernelStats>, prost::DecodeError> {
+    let kernels = Snapshot::decode(data)?.kernels;
+    Ok(kernels
+        .into_iter()
+        .map(|entry| KernelStats {
+            op_name: entry.op_name,
+            dtype: entry.dtype,
+            count: entry.count,
+            total: Duration::from_nanos(entry.total),
+            p50: Duration::from_nanos(entry.p50),
+            p90: Duration::from_nanos(entry.p90),
+            p99: Duration::from_nanos(entry.p99),
+            max: Duration::from_nanos(entry.max),
+            bytes_read: entry.bytes_read,
+            bytes_written: entry.bytes_written,
+        })
+        .collect())
+}
+
+/// Statistics gathered by the plugin so far, the kernels which took the
+/// longest in total first
+///
+/// # Safety
+///
+/// `snapshot` should be the function the plugin exports as `SNAPSHOT_SYMBOL`
+pub unsafe fn snapshot(snapshot: SnapshotFn) -> Result<Vec<KernelStats>, prost::DecodeError> {
+    unsafe extern "C" fn write(arg: *mut c_void, data: *const u8, len: usize) {
+        let buffer = &mut *(arg as *mut Vec<u8>);
+        buffer.extend_from_slice(std::slice::from_raw_parts(data, len));
+    }
+
+    let mut buffer = Vec::new();
+    snapshot(write, &mut buffer as *mut Vec<u8> as *mut c_void);
+    decode(&buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, snapshot, KernelStats, WriteFn};
+    use std::{ffi::c_void, time::Duration};
+
+    fn stats() -> Vec<KernelStats> {
+        let ms = Duration::from_millis;
+        vec![
+            KernelStats {
+                op_name: "Relu".to_owned(),
+                dtype: "float".to_owned(),
+                count: 3,
+                total: ms(6),
+                p50: ms(2),
+                p90: ms(3),
+                p99: ms(3),
+                max: ms(3),
+                bytes_read: 48,
+                bytes_written: 48,
+            },
+            KernelStats {
+                op_name: "NoInputs".to_owned(),
+                dtype: "none".to_owned(),
+                count: 1,
+                total: Duration::from_nanos(1),
+                p50: Duration::from_nanos(1),
+                p90: Duration::from_nanos(1),
+                p99: Duration::from_nanos(1),
+                max: Duration::from_nanos(1),
+                bytes_read: 0,
+                bytes_written: 0,
+            },
+        ]
+    }
+
+    // Writes in two parts, which the reader has to join
+    unsafe extern "C" fn exported(write: WriteFn, arg: *mut c_void) {
+        let data = encode(&stats());
+        let (first, second) = data.split_at(data.len() / 2);
+        write(arg, first.as_ptr(), first.len());
+        write(arg, second.as_ptr(), second.len());
+    }
+
+    #[test]
+    fn stats_snapshot_round_trip() {
+        let snapshot = unsafe { snapshot(exported) }.unwrap();
+
+        assert_eq!(snapshot, stats());
+        assert_eq!(snapshot[0].mean(), Duration::from_millis(2));
+    }
+}
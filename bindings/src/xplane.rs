@@ -0,0 +1,124 @@
+// Messages of TF's `tsl/profiler/protobuf/xplane.proto`, which profiler
+// plugins use to hand collected traces over to TF
+use std::collections::HashMap;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct XSpace {
+    #[prost(message, repeated, tag = "1")]
+    pub planes: Vec<XPlane>,
+    #[prost(string, repeated, tag = "2")]
+    pub errors: Vec<String>,
+    #[prost(string, repeated, tag = "3")]
+    pub warnings: Vec<String>,
+    #[prost(string, repeated, tag = "4")]
+    pub hostnames: Vec<String>,
+}
+
+/// Timeline of a single device, or of the host
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct XPlane {
+    #[prost(int64, tag = "1")]
+    pub id: i64,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(message, repeated, tag = "3")]
+    pub lines: Vec<XLine>,
+    #[prost(map = "int64, message", tag = "4")]
+    pub event_metadata: HashMap<i64, XEventMetadata>,
+    #[prost(map = "int64, message", tag = "5")]
+    pub stat_metadata: HashMap<i64, XStatMetadata>,
+    #[prost(message, repeated, tag = "6")]
+    pub stats: Vec<XStat>,
+}
+
+/// Sequence of events which don't overlap, e.g. a stream
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct XLine {
+    #[prost(int64, tag = "1")]
+    pub id: i64,
+    #[prost(int64, tag = "10")]
+    pub display_id: i64,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(string, tag = "11")]
+    pub display_name: String,
+    // Start of the line since the Unix epoch
+    #[prost(int64, tag = "3")]
+    pub timestamp_ns: i64,
+    #[prost(int64, tag = "9")]
+    pub duration_ps: i64,
+    #[prost(message, repeated, tag = "4")]
+    pub events: Vec<XEvent>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct XEvent {
+    #[prost(int64, tag = "1")]
+    pub metadata_id: i64,
+    #[prost(oneof = "XEventData", tags = "2, 5")]
+    pub data: Option<XEventData>,
+    #[prost(int64, tag = "3")]
+    pub duration_ps: i64,
+    #[prost(message, repeated, tag = "4")]
+    pub stats: Vec<XStat>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum XEventData {
+    // Start of the event relative to the line timestamp
+    #[prost(int64, tag = "2")]
+    OffsetPs(i64),
+    #[prost(int64, tag = "5")]
+    NumOccurrences(i64),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct XStat {
+    #[prost(int64, tag = "1")]
+    pub metadata_id: i64,
+    #[prost(oneof = "XStatValue", tags = "2, 3, 4, 5, 6, 7")]
+    pub value: Option<XStatValue>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum XStatValue {
+    #[prost(double, tag = "2")]
+    DoubleValue(f64),
+    #[prost(uint64, tag = "3")]
+    Uint64Value(u64),
+    #[prost(int64, tag = "4")]
+    Int64Value(i64),
+    #[prost(string, tag = "5")]
+    StrValue(String),
+    #[prost(bytes, tag = "6")]
+    BytesValue(Vec<u8>),
+    // Id of an XStatMetadata whose name is the value
+    #[prost(uint64, tag = "7")]
+    RefValue(u64),
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct XEventMetadata {
+    #[prost(int64, tag = "1")]
+    pub id: i64,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(string, tag = "4")]
+    pub display_name: String,
+    #[prost(bytes, tag = "3")]
+    pub metadata: Vec<u8>,
+    #[prost(message, repeated, tag = "5")]
+    pub stats: Vec<XStat>,
+    #[prost(int64, repeated, tag = "6")]
+    pub child_id: Vec<i64>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct XStatMetadata {
+    #[prost(int64, tag = "1")]
+    pub id: i64,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    #[prost(string, tag = "3")]
+    pub description: String,
+}
@@ -1,8 +1,8 @@
 #include "tensorflow/c/experimental/stream_executor/stream_executor.h"
+#include "tensorflow/c/experimental/pluggable_profiler/pluggable_profiler.h"
 #include "tensorflow/c/kernels.h"
-
-// Needed for optimizer only
-// #include "tensorflow/c/experimental/grappler/grappler.h"
+#include "tensorflow/c/ops.h"
+#include "tensorflow/c/experimental/grappler/grappler.h"
 
 struct SP_Stream_st {
   explicit SP_Stream_st(void* stream_h) : stream_handle(stream_h) {}
@@ -15,6 +15,6 @@ struct SP_Event_st {
 };
 
 struct SP_Timer_st {
-  explicit SP_Timer_st(int id) : timer_handle(id) {}
-  int timer_handle;
+  explicit SP_Timer_st(void* timer_h) : timer_handle(timer_h) {}
+  void* timer_handle;
 };
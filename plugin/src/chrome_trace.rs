@@ -0,0 +1,221 @@
+// Standalone trace export: with TFP_TRACE_FILE set, the device timeline is
+// recorded for the whole process and written as Chrome trace JSON at exit,
+// which chrome://tracing and Perfetto open directly
+use std::{fmt::Write, path::Path, sync::Once, time::Instant};
+
+use crate::{
+    bindings::compute::data_type_name,
+    config,
+    model::Activity,
+    trace::{self, Timeline},
+    DEVICE_TYPE,
+};
+
+// Track of synchronous copies, which don't belong to a stream
+const SYNC_TID: i64 = -1;
+
+/// Starts recording if an export was requested
+pub fn init() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        if config::get().trace_file.is_some() {
+            trace::start_export();
+            unsafe { libc::atexit(export_at_exit) };
+        }
+    });
+}
+
+extern "C" fn export_at_exit() {
+    if let Some(path) = &config::get().trace_file {
+        if let Err(error) = export(path, &trace::take_export()) {
+            log::error!("Failed to write trace to {}: {}", path.display(), error);
+        }
+    }
+}
+
+fn export(path: &Path, timeline: &Timeline) -> std::io::Result<()> {
+    let origin = timeline
+        .events
+        .iter()
+        .map(|event| event.begin)
+        .chain(
+            timeline
+                .allocations
+                .iter()
+                .map(|allocation| allocation.time),
+        )
+        .min();
+    let origin = match origin {
+        Some(origin) => origin,
+        None => Instant::now(),
+    };
+    std::fs::write(path, chrome_trace(timeline, origin))
+}
+
+/// JSON object format of the Trace Event Format, timestamps are microseconds
+/// since `origin`
+fn chrome_trace(timeline: &Timeline, origin: Instant) -> String {
+    let micros = |instant: Instant| instant.saturating_duration_since(origin).as_secs_f64() * 1e6;
+    let device = json_string(DEVICE_TYPE.trim_end_matches('\0'));
+
+    let mut events = vec![format!(
+        r#"{{"name":"process_name","ph":"M","pid":0,"args":{{"name":{}}}}}"#,
+        device
+    )];
+
+    let mut tids: Vec<_> = timeline
+        .events
+        .iter()
+        .map(|event| event.stream.map_or(SYNC_TID, |stream| stream as i64))
+        .collect();
+    tids.sort_unstable();
+    tids.dedup();
+    for tid in tids {
+        let name = match tid {
+            SYNC_TID => "Synchronous copies".to_owned(),
+            stream => format!("Stream #{}", stream),
+        };
+        events.push(format!(
+            r#"{{"name":"thread_name","ph":"M","pid":0,"tid":{},"args":{{"name":{}}}}}"#,
+            tid,
+            json_string(&name)
+        ));
+    }
+
+    for event in &timeline.events {
+        let tid = event.stream.map_or(SYNC_TID, |stream| stream as i64);
+        let category = match event.activity {
+            Activity::Memcpy(..) => "memcpy",
+            Activity::Memset(_) => "memset",
+            Activity::Kernel(_) => "kernel",
+        };
+
+        let mut args = String::new();
+        match &event.kernel {
+            Some(kernel) => {
+                let shapes: Vec<_> = kernel.inputs.iter().map(|input| &input.dims).collect();
+                let dtypes: Vec<_> = kernel
+                    .inputs
+                    .iter()
+                    .map(|input| data_type_name(input.dtype))
+                    .collect();
+                write!(
+                    args,
+                    r#""shapes":{},"dtypes":{}"#,
+                    json_string(&format!("{:?}", shapes)),
+                    json_string(&dtypes.join(", "))
+                )
+                .unwrap();
+            }
+            None => write!(args, r#""bytes":{}"#, event.bytes()).unwrap(),
+        }
+
+        events.push(format!(
+            r#"{{"name":{},"cat":"{}","ph":"X","ts":{:.3},"dur":{:.3},"pid":0,"tid":{},"args":{{{}}}}}"#,
+            json_string(event.name()),
+            category,
+            micros(event.begin),
+            micros(event.end) - micros(event.begin),
+            tid,
+            args
+        ));
+    }
+
+    for allocation in &timeline.allocations {
+        events.push(format!(
+            r#"{{"name":"Device memory","ph":"C","ts":{:.3},"pid":0,"args":{{"bytes_in_use":{}}}}}"#,
+            micros(allocation.time),
+            allocation.bytes_in_use
+        ));
+    }
+
+    format!(
+        "{{\"displayTimeUnit\":\"ms\",\"traceEvents\":[\n{}\n]}}\n",
+        events.join(",\n")
+    )
+}
+
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if (c as u32) < 0x20 => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{chrome_trace, json_string};
+    use crate::{
+        bindings::{compute::TensorInfo, raw::TF_FLOAT},
+        model::{Activity, Direction},
+        trace::{Allocation, Event, Kernel, Timeline},
+    };
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn json_string_escapes() {
+        assert_eq!(json_string("a\"b\\c\nd\u{1}"), r#""a\"b\\c\nd\u0001""#);
+    }
+
+    #[test]
+    fn chrome_trace_events() {
+        let origin = Instant::now();
+        let ms = Duration::from_millis;
+        let relu = Kernel {
+            op_name: "Relu".to_owned(),
+            inputs: vec![TensorInfo {
+                dims: vec![2, 3],
+                dtype: TF_FLOAT,
+                bytes: 24,
+            }],
+        };
+        let timeline = Timeline {
+            events: vec![
+                Event {
+                    stream: Some(3),
+                    activity: Activity::Kernel(ms(1)),
+                    kernel: Some(relu),
+                    begin: origin + ms(1),
+                    end: origin + ms(2),
+                },
+                Event {
+                    stream: None,
+                    activity: Activity::Memcpy(Direction::DeviceToHost, 24),
+                    kernel: None,
+                    begin: origin + ms(2),
+                    end: origin + ms(3),
+                },
+            ],
+            allocations: vec![Allocation {
+                time: origin,
+                bytes: 24,
+                bytes_in_use: 24,
+            }],
+        };
+
+        let trace = chrome_trace(&timeline, origin);
+        let lines: Vec<_> = trace.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"displayTimeUnit":"ms","traceEvents":["#,
+                r#"{"name":"process_name","ph":"M","pid":0,"args":{"name":"MY_DEVICE"}},"#,
+                r#"{"name":"thread_name","ph":"M","pid":0,"tid":-1,"args":{"name":"Synchronous copies"}},"#,
+                r#"{"name":"thread_name","ph":"M","pid":0,"tid":3,"args":{"name":"Stream #3"}},"#,
+                r#"{"name":"Relu","cat":"kernel","ph":"X","ts":1000.000,"dur":1000.000,"pid":0,"tid":3,"args":{"shapes":"[[2, 3]]","dtypes":"float"}},"#,
+                r#"{"name":"MemcpyDtoH","cat":"memcpy","ph":"X","ts":2000.000,"dur":1000.000,"pid":0,"tid":-1,"args":{"bytes":24}},"#,
+                r#"{"name":"Device memory","ph":"C","ts":0.000,"pid":0,"args":{"bytes_in_use":24}}"#,
+                r#"]}"#,
+            ]
+        );
+    }
+}
@@ -0,0 +1,338 @@
+// Plugin configuration, read once from TFP_* environment variables
+use std::{ffi::CString, path::PathBuf, str::FromStr, sync::OnceLock, time::Duration};
+
+use crate::{model::DeviceModel, stats};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryMode {
+    // Device memory is ordinary host memory
+    Host,
+    // Device memory lives in a separate mapping which faults on host access
+    Discrete,
+}
+
+impl FromStr for MemoryMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "host" => Ok(Self::Host),
+            "discrete" => Ok(Self::Discrete),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Setting of a built-in grappler optimizer, like TF_TriState
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Toggle {
+    // Whatever the session config says
+    Default,
+    Off,
+    On,
+}
+
+impl FromStr for Toggle {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default" => Ok(Self::Default),
+            "off" | "false" | "0" => Ok(Self::Off),
+            "on" | "true" | "1" => Ok(Self::On),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for Toggle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Default => "default",
+            Self::Off => "off",
+            Self::On => "on",
+        })
+    }
+}
+
+/// Built-in grappler optimizers for graphs on the device, TP_OptimizerConfigs
+#[derive(Debug)]
+pub struct GrapplerConfig {
+    pub disable_model_pruning: bool,
+    pub implementation_selector: Toggle,
+    pub function_optimization: Toggle,
+    pub common_subgraph_elimination: Toggle,
+    pub arithmetic_optimization: Toggle,
+    pub debug_stripper: Toggle,
+    pub constant_folding: Toggle,
+    pub shape_optimization: Toggle,
+    pub auto_mixed_precision: Toggle,
+    pub auto_mixed_precision_onednn_bfloat16: Toggle,
+    pub auto_mixed_precision_mkl: Toggle,
+    pub pin_to_host_optimization: Toggle,
+    pub layout_optimizer: Toggle,
+    pub remapping: Toggle,
+    pub loop_optimization: Toggle,
+    pub dependency_optimization: Toggle,
+    pub auto_parallel: Toggle,
+    pub memory_optimization: Toggle,
+    pub scoped_allocator_optimization: Toggle,
+}
+
+impl GrapplerConfig {
+    // TFP_GRAPPLER_<OPTIMIZER>, e.g. TFP_GRAPPLER_CONSTANT_FOLDING=off.
+    // The remapper fuses ops into kernels this device doesn't have,
+    // and the layout optimizer prefers NCHW, which the kernels don't support
+    fn from_env() -> Self {
+        let toggle = |name: &str| env(&format!("TFP_GRAPPLER_{}", name), Toggle::Default);
+        Self {
+            disable_model_pruning: env("TFP_GRAPPLER_DISABLE_MODEL_PRUNING", false),
+            implementation_selector: toggle("IMPLEMENTATION_SELECTOR"),
+            function_optimization: toggle("FUNCTION_OPTIMIZATION"),
+            common_subgraph_elimination: toggle("COMMON_SUBGRAPH_ELIMINATION"),
+            arithmetic_optimization: toggle("ARITHMETIC_OPTIMIZATION"),
+            debug_stripper: toggle("DEBUG_STRIPPER"),
+            constant_folding: toggle("CONSTANT_FOLDING"),
+            shape_optimization: toggle("SHAPE_OPTIMIZATION"),
+            auto_mixed_precision: toggle("AUTO_MIXED_PRECISION"),
+            auto_mixed_precision_onednn_bfloat16: toggle("AUTO_MIXED_PRECISION_ONEDNN_BFLOAT16"),
+            auto_mixed_precision_mkl: toggle("AUTO_MIXED_PRECISION_MKL"),
+            pin_to_host_optimization: toggle("PIN_TO_HOST_OPTIMIZATION"),
+            layout_optimizer: env("TFP_GRAPPLER_LAYOUT_OPTIMIZER", Toggle::Off),
+            remapping: env("TFP_GRAPPLER_REMAPPING", Toggle::Off),
+            loop_optimization: toggle("LOOP_OPTIMIZATION"),
+            dependency_optimization: toggle("DEPENDENCY_OPTIMIZATION"),
+            auto_parallel: toggle("AUTO_PARALLEL"),
+            memory_optimization: toggle("MEMORY_OPTIMIZATION"),
+            scoped_allocator_optimization: toggle("SCOPED_ALLOCATOR_OPTIMIZATION"),
+        }
+    }
+
+    /// Every optimizer with its setting, in the order of TP_OptimizerConfigs
+    pub fn toggles(&self) -> [(&'static str, Toggle); 18] {
+        [
+            ("implementation_selector", self.implementation_selector),
+            ("function_optimization", self.function_optimization),
+            (
+                "common_subgraph_elimination",
+                self.common_subgraph_elimination,
+            ),
+            ("arithmetic_optimization", self.arithmetic_optimization),
+            ("debug_stripper", self.debug_stripper),
+            ("constant_folding", self.constant_folding),
+            ("shape_optimization", self.shape_optimization),
+            ("auto_mixed_precision", self.auto_mixed_precision),
+            (
+                "auto_mixed_precision_onednn_bfloat16",
+                self.auto_mixed_precision_onednn_bfloat16,
+            ),
+            ("auto_mixed_precision_mkl", self.auto_mixed_precision_mkl),
+            ("pin_to_host_optimization", self.pin_to_host_optimization),
+            ("layout_optimizer", self.layout_optimizer),
+            ("remapping", self.remapping),
+            ("loop_optimization", self.loop_optimization),
+            ("dependency_optimization", self.dependency_optimization),
+            ("auto_parallel", self.auto_parallel),
+            ("memory_optimization", self.memory_optimization),
+            (
+                "scoped_allocator_optimization",
+                self.scoped_allocator_optimization,
+            ),
+        ]
+    }
+}
+
+/// Type the mixed precision pass converts float subgraphs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MixedPrecision {
+    Off,
+    Bfloat16,
+    Float16,
+}
+
+impl FromStr for MixedPrecision {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" | "false" | "0" => Ok(Self::Off),
+            "bfloat16" => Ok(Self::Bfloat16),
+            "float16" | "half" => Ok(Self::Float16),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Op types by how the mixed precision pass treats them
+#[derive(Debug)]
+pub struct MixedPrecisionConfig {
+    pub precision: MixedPrecision,
+    // Converted wherever they have a half precision kernel
+    pub allow: Vec<String>,
+    // Converted when next to converted ops, unless they feed denied ops
+    pub infer: Vec<String>,
+    // Never converted, and keep the infer ops feeding them in float32
+    pub deny: Vec<String>,
+}
+
+impl MixedPrecisionConfig {
+    // TFP_MIXED_PRECISION and comma-separated TFP_MIXED_PRECISION_<LIST>s,
+    // which replace the default lists
+    fn from_env() -> Self {
+        Self {
+            precision: env("TFP_MIXED_PRECISION", MixedPrecision::Off),
+            allow: env_list(
+                "TFP_MIXED_PRECISION_ALLOW",
+                &[
+                    "Conv2D",
+                    "MatMul",
+                    "BatchMatMulV2",
+                    "_FusedBiasAddActivation",
+                ],
+            ),
+            infer: env_list(
+                "TFP_MIXED_PRECISION_INFER",
+                &[
+                    "BiasAdd", "Add", "AddV2", "Sub", "Mul", "Relu", "Relu6", "Elu", "Tanh",
+                    "Sigmoid", "Identity", "MaxPool", "AvgPool",
+                ],
+            ),
+            deny: env_list(
+                "TFP_MIXED_PRECISION_DENY",
+                &[
+                    "Exp",
+                    "Log",
+                    "Pow",
+                    "Softmax",
+                    "LogSoftmax",
+                    "SoftmaxCrossEntropyWithLogits",
+                    "SparseSoftmaxCrossEntropyWithLogits",
+                    "Sum",
+                    "Mean",
+                ],
+            ),
+        }
+    }
+}
+
+/// Descriptive attributes and performance figures reported for the device
+#[derive(Debug)]
+pub struct DeviceInfo {
+    pub hardware_name: CString,
+    pub vendor: CString,
+    pub pci_bus_id: CString,
+    pub numa_node: i32,
+    // Bytes per second
+    pub memory_bandwidth: i64,
+    pub gflops: f64,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub memory_mode: MemoryMode,
+    // Size of the device address space in discrete mode
+    pub memory_size: usize,
+    // Boundary device and host allocations are aligned to, a power of two
+    pub memory_alignment: usize,
+    // Whether TF may allocate host memory which kernels access directly
+    pub unified_memory: bool,
+    pub model: DeviceModel,
+    pub device: DeviceInfo,
+    // Chrome trace of device activity written at exit
+    pub trace_file: Option<PathBuf>,
+    // Where kernel statistics are reported at exit, if they're gathered
+    pub kernel_stats: Option<stats::Output>,
+    // Whether the plugin's own optimizer passes run
+    pub optimizer: bool,
+    // Where the graphs the optimizer is given and returns are written
+    pub optimizer_dump_dir: Option<PathBuf>,
+    pub grappler: GrapplerConfig,
+    pub mixed_precision: MixedPrecisionConfig,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        let model = DeviceModel {
+            htod_bandwidth: env("TFP_MODEL_HTOD_GBPS", 0.0) * 1e9,
+            dtoh_bandwidth: env("TFP_MODEL_DTOH_GBPS", 0.0) * 1e9,
+            dtod_bandwidth: env("TFP_MODEL_DTOD_GBPS", 0.0) * 1e9,
+            kernel_latency: Duration::from_micros(env("TFP_MODEL_KERNEL_LATENCY_US", 0)),
+            compute_factor: env("TFP_MODEL_COMPUTE_FACTOR", 0.0),
+        };
+        // Reported bandwidth follows the simulated one, unless it's disabled
+        let memory_bandwidth = match model.dtod_bandwidth > 0.0 {
+            true => model.dtod_bandwidth,
+            false => 20e9,
+        };
+
+        Self {
+            memory_mode: env("TFP_MEMORY_MODE", MemoryMode::Host),
+            memory_size: env("TFP_MEMORY_SIZE", 512_000_000),
+            memory_alignment: match env("TFP_MEMORY_ALIGNMENT", 64usize) {
+                alignment if alignment.is_power_of_two() => alignment,
+                alignment => {
+                    log::warn!(
+                        "Ignoring TFP_MEMORY_ALIGNMENT {}, not a power of two",
+                        alignment
+                    );
+                    64
+                }
+            },
+            unified_memory: env("TFP_UNIFIED_MEMORY", false),
+            model,
+            device: DeviceInfo {
+                hardware_name: env_cstring("TFP_DEVICE_HARDWARE_NAME", "Fake CPU Device"),
+                vendor: env_cstring("TFP_DEVICE_VENDOR", "tfp"),
+                pci_bus_id: env_cstring("TFP_DEVICE_PCI_BUS_ID", "0000:00:00.0"),
+                numa_node: env("TFP_DEVICE_NUMA_NODE", 0),
+                memory_bandwidth: (env("TFP_DEVICE_MEMORY_BANDWIDTH_GBPS", memory_bandwidth / 1e9)
+                    * 1e9) as i64,
+                gflops: env("TFP_DEVICE_GFLOPS", 100.0),
+            },
+            trace_file: std::env::var_os("TFP_TRACE_FILE").map(PathBuf::from),
+            kernel_stats: std::env::var("TFP_KERNEL_STATS")
+                .ok()
+                .and_then(|output| output.parse().ok()),
+            optimizer: env("TFP_OPTIMIZER", true),
+            optimizer_dump_dir: std::env::var_os("TFP_OPTIMIZER_DUMP_DIR").map(PathBuf::from),
+            grappler: GrapplerConfig::from_env(),
+            mixed_precision: MixedPrecisionConfig::from_env(),
+        }
+    }
+}
+
+pub fn get() -> &'static Config {
+    static CONFIG: OnceLock<Config> = OnceLock::new();
+    CONFIG.get_or_init(Config::from_env)
+}
+
+fn env<T: FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            log::warn!("Ignoring invalid value of {}: {}", name, value);
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+fn env_list(name: &str, default: &[&str]) -> Vec<String> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_owned)
+            .collect(),
+        Err(_) => default.iter().map(|&value| value.to_owned()).collect(),
+    }
+}
+
+// Strings handed to TF as C strings can't contain a nul
+fn env_cstring(name: &str, default: &str) -> CString {
+    let value = env(name, default.to_owned());
+    CString::new(value).unwrap_or_else(|_| {
+        log::warn!("Ignoring invalid value of {}: contains a nul", name);
+        CString::new(default).unwrap()
+    })
+}
@@ -0,0 +1,237 @@
+// Events mark a point in a stream: pending once recorded and complete (or
+// errored) when the stream worker reaches that point
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::stream::{Stream, StreamError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventStatus {
+    Pending,
+    Complete,
+    Error(StreamError),
+}
+
+// One record of the event. Waiters hold on to the record they wait for, so
+// re-recording the event doesn't release them before their point is reached
+#[derive(Debug)]
+struct Record {
+    status: Mutex<EventStatus>,
+    reached: Condvar,
+}
+
+impl Record {
+    fn new(status: EventStatus) -> Arc<Self> {
+        Arc::new(Self {
+            status: Mutex::new(status),
+            reached: Condvar::new(),
+        })
+    }
+
+    fn wait(&self) -> Result<(), StreamError> {
+        let mut status = self.status.lock().unwrap();
+        while *status == EventStatus::Pending {
+            status = self.reached.wait(status).unwrap();
+        }
+
+        match &*status {
+            EventStatus::Error(error) => Err(error.clone()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Event {
+    last: Mutex<Arc<Record>>,
+}
+
+impl Default for Event {
+    // Events which were never recorded are complete, waiting on them is a no-op
+    fn default() -> Self {
+        Self {
+            last: Mutex::new(Record::new(EventStatus::Complete)),
+        }
+    }
+}
+
+impl Event {
+    /// Marks the event pending until the stream reaches all work enqueued so far
+    pub fn record(self: &Arc<Self>, stream: &Stream) {
+        let record = Record::new(EventStatus::Pending);
+        *self.last.lock().unwrap() = record.clone();
+
+        stream.enqueue_always(move |status| {
+            *record.status.lock().unwrap() = match status {
+                Ok(()) => EventStatus::Complete,
+                Err(error) => EventStatus::Error(error.clone()),
+            };
+            record.reached.notify_all();
+            Ok(())
+        });
+    }
+
+    pub fn status(&self) -> EventStatus {
+        let record = self.last.lock().unwrap().clone();
+        let status = record.status.lock().unwrap().clone();
+        status
+    }
+
+    /// Blocks the host until the last record of the event is reached
+    pub fn wait(&self) -> Result<(), StreamError> {
+        let record = self.last.lock().unwrap().clone();
+        record.wait()
+    }
+
+    /// Makes the stream wait for the last record of the event before running
+    /// any work enqueued later. An errored event fails the waiting stream
+    pub fn wait_on(self: &Arc<Self>, stream: &Stream) {
+        let record = self.last.lock().unwrap().clone();
+        stream.enqueue(move || record.wait());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, EventStatus};
+    use crate::stream::Stream;
+    use std::{
+        sync::{mpsc, Arc, Mutex},
+        time::Duration,
+    };
+
+    #[test]
+    fn event_unrecorded_is_complete() {
+        let event = Event::default();
+
+        assert_eq!(event.status(), EventStatus::Complete);
+        assert_eq!(event.wait(), Ok(()));
+    }
+
+    #[test]
+    fn event_pending_until_reached() {
+        let stream = Stream::new("test".to_owned());
+        let event = Arc::new(Event::default());
+        let (sender, receiver) = mpsc::channel::<()>();
+
+        // Worker is blocked until the test lets it go
+        stream.enqueue(move || {
+            receiver.recv().unwrap();
+            Ok(())
+        });
+        event.record(&stream);
+        assert_eq!(event.status(), EventStatus::Pending);
+
+        sender.send(()).unwrap();
+        assert_eq!(event.wait(), Ok(()));
+        assert_eq!(event.status(), EventStatus::Complete);
+    }
+
+    #[test]
+    fn event_reports_stream_error() {
+        let stream = Stream::new("test".to_owned());
+        let event = Arc::new(Event::default());
+
+        stream.enqueue(|| Err("failed".into()));
+        event.record(&stream);
+
+        assert_eq!(event.wait(), Err("failed".into()));
+        assert_eq!(event.status(), EventStatus::Error("failed".into()));
+    }
+
+    #[test]
+    fn event_orders_streams() {
+        let producer = Stream::new("producer".to_owned());
+        let consumer = Stream::new("consumer".to_owned());
+        let event = Arc::new(Event::default());
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        let producer_log = log.clone();
+        producer.enqueue(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            producer_log.lock().unwrap().push("produce");
+            Ok(())
+        });
+        event.record(&producer);
+
+        event.wait_on(&consumer);
+        let consumer_log = log.clone();
+        consumer.enqueue(move || {
+            consumer_log.lock().unwrap().push("consume");
+            Ok(())
+        });
+
+        assert_eq!(consumer.synchronize(), Ok(()));
+        assert_eq!(*log.lock().unwrap(), vec!["produce", "consume"]);
+    }
+
+    #[test]
+    fn event_error_fails_waiting_stream() {
+        let producer = Stream::new("producer".to_owned());
+        let consumer = Stream::new("consumer".to_owned());
+        let event = Arc::new(Event::default());
+
+        producer.enqueue(|| Err("failed".into()));
+        event.record(&producer);
+        event.wait_on(&consumer);
+
+        assert_eq!(consumer.synchronize(), Err("failed".into()));
+    }
+
+    #[test]
+    fn event_rerecord_supersedes() {
+        let first = Stream::new("first".to_owned());
+        let second = Stream::new("second".to_owned());
+        let event = Arc::new(Event::default());
+        let (sender, receiver) = mpsc::channel::<()>();
+
+        first.enqueue(move || {
+            receiver.recv().unwrap();
+            Ok(())
+        });
+        event.record(&first);
+        event.record(&second);
+
+        // First record is still blocked, but only the last one is awaited
+        assert_eq!(event.wait(), Ok(()));
+        assert_eq!(event.status(), EventStatus::Complete);
+
+        sender.send(()).unwrap();
+        assert_eq!(first.synchronize(), Ok(()));
+        assert_eq!(event.status(), EventStatus::Complete);
+    }
+
+    #[test]
+    fn event_rerecord_keeps_earlier_waiters() {
+        let first = Stream::new("first".to_owned());
+        let second = Stream::new("second".to_owned());
+        let consumer = Stream::new("consumer".to_owned());
+        let event = Arc::new(Event::default());
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let (sender, receiver) = mpsc::channel::<()>();
+
+        let first_log = log.clone();
+        first.enqueue(move || {
+            receiver.recv().unwrap();
+            first_log.lock().unwrap().push("produce");
+            Ok(())
+        });
+        event.record(&first);
+        event.wait_on(&consumer);
+        let consumer_log = log.clone();
+        consumer.enqueue(move || {
+            consumer_log.lock().unwrap().push("consume");
+            Ok(())
+        });
+
+        // The newer record completes first, the consumer still waits for
+        // the one it was ordered after
+        event.record(&second);
+        assert_eq!(event.wait(), Ok(()));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(log.lock().unwrap().is_empty());
+
+        sender.send(()).unwrap();
+        assert_eq!(consumer.synchronize(), Ok(()));
+        assert_eq!(*log.lock().unwrap(), vec!["produce", "consume"]);
+    }
+}
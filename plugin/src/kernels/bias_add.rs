@@ -2,9 +2,11 @@ use crate::{
     bindings::{
         compute::offset_from_tensor_coordinates,
         kernels::KernelBuilder,
-        raw::{TF_OpKernelConstruction, TF_OpKernelContext, TF_TensorData, TF_FLOAT},
+        raw::{
+            TF_OpKernelConstruction, TF_OpKernelContext, TF_Status, TF_FLOAT, TF_INVALID_ARGUMENT,
+        },
     },
-    kernels::TYPE_CONSTRAINT_T,
+    kernels::{run_kernel, tensor_data, TYPE_CONSTRAINT_T},
     DEVICE_TYPE,
 };
 
@@ -33,60 +35,70 @@ unsafe extern "C" fn create(construction: *mut TF_OpKernelConstruction) -> *mut
 }
 
 unsafe extern "C" fn compute(kernel: *mut BiasAddKernel, ctx: *mut TF_OpKernelContext) {
-    let device_data = &*match ctx.get_stream::<String>() {
-        Ok(stream) => stream,
-        Err(status) => return ctx.failure(status),
-    };
+    run_kernel(BIAS_ADD_KERNEL_NAME, ctx, || {
+        add_bias(ctx, &(*kernel).format, |x| x)
+    })
+}
 
-    let format = (*kernel).format.clone();
-    eprintln!("device: {}, format: {:#?}", device_data, format);
+/// Adds the bias to the input and applies `activation` to the sum
+pub(super) unsafe fn add_bias(
+    ctx: *mut TF_OpKernelContext,
+    format: &str,
+    activation: impl Fn(f32) -> f32,
+) -> Result<(), *mut TF_Status> {
+    log::trace!("format: {}", format);
 
-    let input = match ctx.get_input(0) {
-        Ok(input) => input,
-        Err(status) => return ctx.failure(status),
-    };
-    let input_dims = input.dims_named(&format);
+    // Shapes are only checked by the kernel, which can't panic across the C API
+    let input = ctx.get_input(0)?;
+    let dims = input.dims();
+    if dims.len() != 4 {
+        return Err(TF_Status::with_message(
+            TF_INVALID_ARGUMENT,
+            &format!("Input must be 4-D, got shape {:?}", dims),
+        ));
+    }
+    let input_dims = input.dims_named(format);
 
-    let bias = match ctx.get_input(1) {
-        Ok(input) => input,
-        Err(status) => return ctx.failure(status),
-    };
+    let bias = ctx.get_input(1)?;
     let bias_dims = bias.dims();
-
-    assert!(bias_dims.len() == 1);
-    assert!(*bias_dims.first().unwrap() == input_dims.c);
+    if bias_dims != [input_dims.c] {
+        return Err(TF_Status::with_message(
+            TF_INVALID_ARGUMENT,
+            &format!(
+                "Bias must be 1-D of size {}, the channels of the input, got shape {:?}",
+                input_dims.c, bias_dims
+            ),
+        ));
+    }
 
     let len = input.element_count() as usize;
     if len == 0 {
-        return;
+        return Ok(());
     }
 
-    let dims = input.dims();
-    let output = match ctx.allocate_output(
+    let output = ctx.allocate_output(
         0,
         &dims,
         (input.element_count() as u64) * (std::mem::size_of::<f32>() as u64),
-    ) {
-        Ok(output) => output,
-        Err(status) => return ctx.failure(status),
-    };
+    )?;
 
-    let input_raw: &mut [f32] =
-        std::slice::from_raw_parts_mut(TF_TensorData(input) as *mut f32, len);
-    let bias_raw: &mut [f32] = std::slice::from_raw_parts_mut(TF_TensorData(bias) as *mut f32, len);
-    let output_raw: &mut [f32] =
-        std::slice::from_raw_parts_mut(TF_TensorData(output) as *mut f32, len);
+    let input_raw: &mut [f32] = std::slice::from_raw_parts_mut(tensor_data(input), len);
+    let bias_raw: &mut [f32] =
+        std::slice::from_raw_parts_mut(tensor_data(bias), input_dims.c as usize);
+    let output_raw: &mut [f32] = std::slice::from_raw_parts_mut(tensor_data(output), len);
 
     for i in 0..input_dims.n as usize {
         for j in 0..input_dims.h as usize {
             for k in 0..input_dims.w as usize {
-                for (l, current_bias) in bias_raw.iter().enumerate().take(input_dims.c as usize) {
-                    let x = offset_from_tensor_coordinates(&dims, &format, i, j, k, l);
-                    output_raw[x] = input_raw[x] + current_bias;
+                for (l, current_bias) in bias_raw.iter().enumerate() {
+                    let x = offset_from_tensor_coordinates(&dims, format, i, j, k, l);
+                    output_raw[x] = activation(input_raw[x] + current_bias);
                 }
             }
         }
     }
+
+    Ok(())
 }
 
 unsafe extern "C" fn delete(kernel: *mut BiasAddKernel) {
@@ -0,0 +1,157 @@
+// BiasAdd followed by an activation in a single pass over the data, which the
+// optimizer substitutes for the separate ops. TF has no such op, so the plugin
+// defines it, named and attributed like the fused ops of TF's remapper
+use std::sync::Once;
+
+use crate::{
+    bindings::{
+        kernels::KernelBuilder,
+        ops::OpBuilder,
+        raw::{
+            TF_DeleteStatus, TF_OpKernelConstruction, TF_OpKernelConstruction_Failure,
+            TF_OpKernelContext, TF_Status, TF_FLOAT, TF_UNIMPLEMENTED,
+        },
+    },
+    kernels::{bias_add::add_bias, run_kernel, TYPE_CONSTRAINT_T},
+    DEVICE_TYPE,
+};
+
+pub static FUSED_BIAS_ADD_KERNEL_NAME: &str = "_FusedBiasAddActivation\0";
+static FUSED_BIAS_ADD_OP_NAME: &str = "FusedBiasAddActivationOp\0";
+
+/// Applied after BiasAdd, as listed in `fused_ops` after it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Activation {
+    Relu,
+    Relu6,
+}
+
+impl Activation {
+    fn from_fused_ops(fused_ops: &[String]) -> Option<Self> {
+        match fused_ops {
+            [bias_add, activation] if bias_add == "BiasAdd" => match activation.as_str() {
+                "Relu" => Some(Activation::Relu),
+                "Relu6" => Some(Activation::Relu6),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn apply(self, x: f32) -> f32 {
+        match self {
+            Activation::Relu => x.max(0f32),
+            Activation::Relu6 => x.clamp(0f32, 6f32),
+        }
+    }
+}
+
+struct FusedBiasAddKernel {
+    format: String,
+    activation: Activation,
+}
+
+/// Defines the op, once per process. Called when the library is loaded and
+/// again from TF_InitKernel, before the kernel is registered
+pub fn register_op() {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        OpBuilder::new(FUSED_BIAS_ADD_KERNEL_NAME)
+            .input("value: T\0")
+            .input("bias: T\0")
+            .output("output: T\0")
+            .attr("T: {float}\0")
+            .attr("data_format: {'NHWC', 'NCHW'} = 'NHWC'\0")
+            .attr("fused_ops: list(string) = []\0")
+            .shape_fn(|ctx| {
+                let shape = ctx.input(0)?.clone();
+                ctx.set_output(0, shape);
+                Ok(())
+            })
+            .register();
+    });
+}
+
+pub fn init() {
+    register_op();
+    KernelBuilder::<FusedBiasAddKernel>::new(
+        FUSED_BIAS_ADD_KERNEL_NAME,
+        FUSED_BIAS_ADD_OP_NAME,
+        DEVICE_TYPE,
+    )
+    .constraint(TYPE_CONSTRAINT_T, TF_FLOAT)
+    .create(create)
+    .compute(compute)
+    .delete(delete)
+    .register()
+}
+
+// Kernels can't panic across the C API, errors fail the construction instead
+unsafe fn fail(
+    construction: *mut TF_OpKernelConstruction,
+    status: *mut TF_Status,
+) -> *mut FusedBiasAddKernel {
+    TF_OpKernelConstruction_Failure(construction, status);
+    TF_DeleteStatus(status);
+    std::ptr::null_mut()
+}
+
+unsafe extern "C" fn create(construction: *mut TF_OpKernelConstruction) -> *mut FusedBiasAddKernel {
+    let fused_ops = match construction.get_attr_string_list("fused_ops\0") {
+        Ok(fused_ops) => fused_ops,
+        Err(status) => return fail(construction, status),
+    };
+    let activation = match Activation::from_fused_ops(&fused_ops) {
+        Some(activation) => activation,
+        None => {
+            let status = TF_Status::with_message(
+                TF_UNIMPLEMENTED,
+                &format!("Unsupported fused ops {:?}", fused_ops),
+            );
+            return fail(construction, status);
+        }
+    };
+    let format = match construction.get_attr_string("data_format\0") {
+        Ok(format) => format,
+        Err(status) => return fail(construction, status),
+    };
+
+    Box::into_raw(Box::new(FusedBiasAddKernel { format, activation }))
+}
+
+unsafe extern "C" fn compute(kernel: *mut FusedBiasAddKernel, ctx: *mut TF_OpKernelContext) {
+    run_kernel(FUSED_BIAS_ADD_KERNEL_NAME, ctx, || {
+        let activation = (*kernel).activation;
+        add_bias(ctx, &(*kernel).format, |x| activation.apply(x))
+    })
+}
+
+unsafe extern "C" fn delete(kernel: *mut FusedBiasAddKernel) {
+    if !kernel.is_null() {
+        std::mem::drop(Box::from_raw(kernel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Activation;
+
+    #[test]
+    fn fused_ops_activation() {
+        let fused_ops = |ops: &[&str]| ops.iter().map(|&op| op.to_owned()).collect::<Vec<_>>();
+        assert_eq!(
+            Activation::from_fused_ops(&fused_ops(&["BiasAdd", "Relu"])),
+            Some(Activation::Relu)
+        );
+        assert_eq!(
+            Activation::from_fused_ops(&fused_ops(&["BiasAdd", "Relu6"])),
+            Some(Activation::Relu6)
+        );
+        assert_eq!(Activation::from_fused_ops(&fused_ops(&["BiasAdd"])), None);
+        assert_eq!(Activation::from_fused_ops(&fused_ops(&["Relu"])), None);
+
+        assert_eq!(Activation::Relu.apply(-1.0), 0.0);
+        assert_eq!(Activation::Relu6.apply(7.0), 6.0);
+        assert_eq!(Activation::Relu6.apply(3.0), 3.0);
+    }
+}
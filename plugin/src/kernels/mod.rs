@@ -1,10 +1,108 @@
+use std::time::Instant;
+
+use crate::{
+    bindings::raw::{TF_OpKernelContext, TF_Status, TF_Tensor, TF_TensorData},
+    logging, memory, stats,
+    stream::Stream,
+    trace,
+};
+
 static TYPE_CONSTRAINT_T: &str = "T\0";
 
+// Inputs may still be written by copies enqueued on the stream,
+// so kernels wait for it before touching any data
+unsafe fn synchronized_stream<'a>(
+    ctx: *mut TF_OpKernelContext,
+) -> Result<&'a Stream, *mut TF_Status> {
+    let stream = &*ctx.get_stream::<Stream>()?;
+    match stream.synchronize() {
+        Ok(()) => Ok(stream),
+        Err(error) => Err(TF_Status::with_message(error.code, &error.message)),
+    }
+}
+
+/// Runs the body of a kernel once its stream caught up, and fails the kernel
+/// on error. Kernels compute on the host right away, but occupy the stream
+/// for their simulated duration afterwards
+unsafe fn run_kernel(
+    op_name: &str,
+    ctx: *mut TF_OpKernelContext,
+    body: impl FnOnce() -> Result<(), *mut TF_Status>,
+) {
+    let op_name = op_name.trim_end_matches('\0');
+    let stream = match synchronized_stream(ctx) {
+        Ok(stream) => stream,
+        Err(status) => return ctx.failure(status),
+    };
+    let mut span = logging::Span::enter(module_path!(), op_name);
+    span.record("on", || {
+        format!("{} stream {}", stream.device(), stream.id())
+    });
+    span.record("inputs", || {
+        let shapes: Vec<_> = ctx
+            .inputs_info()
+            .into_iter()
+            .map(|input| input.dims)
+            .collect();
+        format!("{:?}", shapes)
+    });
+
+    let start = Instant::now();
+    let result = body();
+    let duration = start.elapsed();
+    let outputs = ctx.take_outputs_info();
+    span.record("outputs", || {
+        let shapes: Vec<_> = outputs.iter().map(|output| &output.dims).collect();
+        format!("{:?}", shapes)
+    });
+    match result {
+        Ok(()) => {
+            if stats::is_enabled() {
+                stats::record(op_name, &ctx.inputs_info(), &outputs, duration);
+            }
+            let kernel = trace::is_recording().then(|| trace::Kernel {
+                op_name: op_name.to_owned(),
+                inputs: ctx.inputs_info(),
+            });
+            stream.enqueue_kernel(duration, kernel)
+        }
+        Err(status) => {
+            log::warn!("failed: {}", status.message());
+            ctx.failure(status)
+        }
+    }
+}
+
+// Device buffers may live in a separate address space,
+// so kernels access tensor data through a host view.
+// Allocations are aligned, but TF also passes slices and forwarded or
+// host-memory tensors, which needn't be, so kernels only assume the
+// alignment of their element type
+unsafe fn tensor_data<T>(tensor: *mut TF_Tensor) -> *mut T {
+    memory::host_view(TF_TensorData(tensor)) as *mut T
+}
+
 mod bias_add;
+pub(crate) mod fused_bias_add;
 mod relu;
 
+// `tf.load_op_library` only picks up ops registered while the library is
+// loaded, and never calls TF_InitKernel, so ops are defined at load time by a
+// static initializer. Unit tests run without TF, which it would call into
+#[cfg(not(test))]
+#[used]
+#[link_section = ".init_array"]
+static REGISTER_OPS: extern "C" fn() = register_ops;
+
+#[cfg(not(test))]
+extern "C" fn register_ops() {
+    fused_bias_add::register_op();
+}
+
 #[no_mangle]
 pub extern "C" fn TF_InitKernel() {
+    crate::init();
     bias_add::init();
+    fused_bias_add::init();
     relu::init();
 }
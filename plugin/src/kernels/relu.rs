@@ -1,9 +1,9 @@
 use crate::{
     bindings::{
         kernels::KernelBuilder,
-        raw::{TF_OpKernelConstruction, TF_OpKernelContext, TF_TensorData, TF_FLOAT},
+        raw::{TF_OpKernelConstruction, TF_OpKernelContext, TF_FLOAT},
     },
-    kernels::TYPE_CONSTRAINT_T,
+    kernels::{run_kernel, tensor_data, TYPE_CONSTRAINT_T},
     DEVICE_TYPE,
 };
 
@@ -26,44 +26,33 @@ extern "C" fn create(_construction: *mut TF_OpKernelConstruction) -> *mut ReluKe
 }
 
 unsafe extern "C" fn compute(_kernel: *mut ReluKernel, ctx: *mut TF_OpKernelContext) {
-    let device_data = &*match ctx.get_stream::<String>() {
-        Ok(stream) => stream,
-        Err(status) => return ctx.failure(status),
-    };
-
-    eprintln!("device passed into kernel: {}", device_data);
-
-    let input = match ctx.get_input(0) {
-        Ok(input) => input,
-        Err(status) => return ctx.failure(status),
-    };
-
-    let len = input.element_count() as usize;
-    if len == 0 {
-        return;
-    }
-
-    let dims = input.dims();
-    let output = match ctx.allocate_output(
-        0,
-        &dims,
-        (input.element_count() as u64) * (std::mem::size_of::<f32>() as u64),
-    ) {
-        Ok(output) => output,
-        Err(status) => return ctx.failure(status),
-    };
-
-    let input_raw: &mut [f32] =
-        std::slice::from_raw_parts_mut(TF_TensorData(input) as *mut f32, len);
-    let output_raw: &mut [f32] =
-        std::slice::from_raw_parts_mut(TF_TensorData(output) as *mut f32, len);
-
-    for i in 0..len {
-        output_raw[i] = match input_raw[i] > 0f32 {
-            true => input_raw[i],
-            false => 0f32,
-        };
-    }
+    run_kernel(RELU_KERNEL_NAME, ctx, || {
+        let input = ctx.get_input(0)?;
+
+        let len = input.element_count() as usize;
+        if len == 0 {
+            return Ok(());
+        }
+
+        let dims = input.dims();
+        let output = ctx.allocate_output(
+            0,
+            &dims,
+            (input.element_count() as u64) * (std::mem::size_of::<f32>() as u64),
+        )?;
+
+        let input_raw: &mut [f32] = std::slice::from_raw_parts_mut(tensor_data(input), len);
+        let output_raw: &mut [f32] = std::slice::from_raw_parts_mut(tensor_data(output), len);
+
+        for i in 0..len {
+            output_raw[i] = match input_raw[i] > 0f32 {
+                true => input_raw[i],
+                false => 0f32,
+            };
+        }
+
+        Ok(())
+    })
 }
 
 unsafe extern "C" fn delete(kernel: *mut ReluKernel) {
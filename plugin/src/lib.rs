@@ -4,6 +4,25 @@ pub static DEVICE_NAME: &str = "FAKE_CPU_DEVICE\0";
 pub static DEVICE_TYPE: &str = "MY_DEVICE\0";
 
 pub use tfp_bindings as bindings;
+
+mod chrome_trace;
+mod config;
+mod event;
 mod kernels;
+mod logging;
+mod memory;
+mod model;
 mod optimizer;
 mod plugin;
+mod profiler;
+mod stats;
+mod stream;
+mod timer;
+mod trace;
+
+// Every entry point TF loads the plugin through calls this first
+fn init() {
+    logging::init();
+    chrome_trace::init();
+    stats::init();
+}
@@ -0,0 +1,271 @@
+// Logger behind the `log` facade. It's configured through TFP_LOG, or like TF
+// through TF_CPP_MIN_LOG_LEVEL, TF_CPP_MAX_VLOG_LEVEL and TF_CPP_VMODULE, so
+// it logs at info level and above by default
+use std::{cell::RefCell, fmt::Write as _, io::Write, sync::Once, time::Instant};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+thread_local! {
+    // Names of the spans entered on this thread, innermost last
+    static SPANS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Debug, PartialEq)]
+struct Filter {
+    level: LevelFilter,
+    // Overrides matching a module name or a path prefix of the log target,
+    // the last matching one wins
+    modules: Vec<(String, LevelFilter)>,
+    // Directives which were ignored, reported once the logger is installed
+    invalid: Vec<String>,
+}
+
+impl Filter {
+    /// Comma separated directives, either a level or `module=level`,
+    /// e.g. `warn,relu=trace`
+    fn parse(spec: &str) -> Self {
+        let mut filter = Self {
+            level: LevelFilter::Off,
+            modules: Vec::new(),
+            invalid: Vec::new(),
+        };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let parsed = match directive.split_once('=') {
+                Some((module, level)) => level
+                    .parse()
+                    .map(|level| filter.modules.push((module.to_owned(), level))),
+                None => directive.parse().map(|level| filter.level = level),
+            };
+            if parsed.is_err() {
+                filter.invalid.push(directive.to_owned());
+            }
+        }
+        filter
+    }
+
+    /// Follows TF: severities below TF_CPP_MIN_LOG_LEVEL are dropped, 0 and
+    /// so info by default, while VLOG verbosity 1 maps to debug and anything
+    /// above to trace
+    fn from_tf(min_log_level: Option<&str>, max_vlog_level: Option<&str>, vmodule: &str) -> Self {
+        let verbosity = |vlog: i32| match vlog {
+            i32::MIN..=0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        };
+
+        // TF reads anything but a number as 0 too
+        let min_log_level = min_log_level.and_then(|level| level.trim().parse().ok());
+        let mut level = match min_log_level.unwrap_or(0) {
+            i32::MIN..=0 => LevelFilter::Info,
+            1 => LevelFilter::Warn,
+            2 => LevelFilter::Error,
+            _ => LevelFilter::Off,
+        };
+        if let Some(vlog) = max_vlog_level.and_then(|vlog| vlog.trim().parse().ok()) {
+            level = level.max(verbosity(vlog));
+        }
+
+        let modules = vmodule
+            .split(',')
+            .filter_map(|directive| {
+                let (module, vlog) = directive.split_once('=')?;
+                Some((
+                    module.trim().to_owned(),
+                    verbosity(vlog.trim().parse().ok()?),
+                ))
+            })
+            .collect();
+
+        Self {
+            level,
+            modules,
+            invalid: Vec::new(),
+        }
+    }
+
+    fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok();
+        match var("TFP_LOG") {
+            Some(spec) => Self::parse(&spec),
+            None => Self::from_tf(
+                var("TF_CPP_MIN_LOG_LEVEL").as_deref(),
+                var("TF_CPP_MAX_VLOG_LEVEL").as_deref(),
+                &var("TF_CPP_VMODULE").unwrap_or_default(),
+            ),
+        }
+    }
+
+    fn level(&self, target: &str) -> LevelFilter {
+        let matches = |module: &str| {
+            target.split("::").any(|segment| segment == module)
+                || target
+                    .strip_prefix(module)
+                    .is_some_and(|rest| rest.starts_with("::"))
+        };
+        self.modules
+            .iter()
+            .rev()
+            .find(|(module, _)| matches(module))
+            .map_or(self.level, |(_, level)| *level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.level, Ord::max)
+    }
+}
+
+struct Logger {
+    filter: Filter,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level(metadata.target())
+    }
+
+    // Same shape as TF's log lines, e.g. `I tfp::kernels] message`, with the
+    // spans the message was logged in, e.g. `W tfp::kernels] BiasAdd: message`
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let level = &record.level().as_str()[..1];
+            let _ = writeln!(
+                std::io::stderr().lock(),
+                "{} {}] {}{}",
+                level,
+                record.target(),
+                span_prefix(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger, unless the host process set up one already.
+/// Called from every entry point TF may load the plugin through
+pub fn init() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let mut filter = Filter::from_env();
+        let invalid = std::mem::take(&mut filter.invalid);
+        let max_level = filter.max_level();
+        if log::set_boxed_logger(Box::new(Logger { filter })).is_ok() {
+            log::set_max_level(max_level);
+        }
+        for directive in invalid {
+            log::error!("Ignoring invalid TFP_LOG directive: {}", directive);
+        }
+    });
+}
+
+fn span_prefix() -> String {
+    SPANS.with(|spans| {
+        spans
+            .borrow()
+            .iter()
+            .fold(String::new(), |prefix, span| prefix + span + ": ")
+    })
+}
+
+/// Scope of an operation, e.g. a kernel. Entering is logged at trace level and
+/// leaving at debug level, with the duration and the recorded fields. Messages
+/// logged on the thread meanwhile are prefixed with the name of the span
+pub struct Span {
+    target: &'static str,
+    name: String,
+    fields: Vec<(&'static str, String)>,
+    start: Instant,
+}
+
+impl Span {
+    pub fn enter(target: &'static str, name: &str) -> Self {
+        log::trace!(target: target, "{}{} started", span_prefix(), name);
+        SPANS.with(|spans| spans.borrow_mut().push(name.to_owned()));
+        Self {
+            target,
+            name: name.to_owned(),
+            fields: Vec::new(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Formatting is skipped unless the span will be logged
+    pub fn record(&mut self, key: &'static str, value: impl FnOnce() -> String) {
+        if log::log_enabled!(target: self.target, Level::Debug) {
+            self.fields.push((key, value()));
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        SPANS.with(|spans| spans.borrow_mut().pop());
+        let mut message = format!("{} took {:?}", self.name, self.start.elapsed());
+        for (key, value) in &self.fields {
+            let _ = write!(message, ", {} {}", key, value);
+        }
+        log::debug!(target: self.target, "{}{}", span_prefix(), message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{span_prefix, Filter, Span};
+    use log::LevelFilter;
+
+    #[test]
+    fn spans_nest() {
+        assert_eq!(span_prefix(), "");
+        {
+            let _outer = Span::enter("tfp", "Relu");
+            let _inner = Span::enter("tfp", "copy");
+            assert_eq!(span_prefix(), "Relu: copy: ");
+        }
+        assert_eq!(span_prefix(), "");
+    }
+
+    #[test]
+    fn info_by_default() {
+        let filter = Filter::from_tf(None, None, "");
+
+        assert_eq!(filter.max_level(), LevelFilter::Info);
+        assert_eq!(filter.level("tfp::kernels"), LevelFilter::Info);
+    }
+
+    #[test]
+    fn tf_min_log_level() {
+        let level = |min_log_level| Filter::from_tf(Some(min_log_level), None, "").level("tfp");
+
+        assert_eq!(level("0"), LevelFilter::Info);
+        assert_eq!(level("1"), LevelFilter::Warn);
+        assert_eq!(level("2"), LevelFilter::Error);
+        assert_eq!(level("3"), LevelFilter::Off);
+        assert_eq!(level("warn"), LevelFilter::Info);
+    }
+
+    #[test]
+    fn tf_vmodule() {
+        let filter = Filter::from_tf(Some("2"), Some("1"), "relu=2, stream=0,invalid");
+
+        assert_eq!(filter.level("tfp::kernels::relu"), LevelFilter::Trace);
+        assert_eq!(filter.level("tfp::stream"), LevelFilter::Info);
+        assert_eq!(filter.level("tfp::kernels"), LevelFilter::Debug);
+        assert_eq!(filter.max_level(), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn plugin_directives() {
+        let filter = Filter::parse("warn,tfp::kernels=debug,relu=trace,bad=level");
+
+        assert_eq!(filter.level("tfp::memory"), LevelFilter::Warn);
+        assert_eq!(filter.level("tfp::kernels::bias_add"), LevelFilter::Debug);
+        assert_eq!(filter.level("tfp::kernels::relu"), LevelFilter::Trace);
+        assert_eq!(filter.level("tfp_bindings::kernels"), LevelFilter::Warn);
+        assert_eq!(filter.modules.len(), 2);
+        assert_eq!(filter.invalid, ["bad=level"]);
+    }
+}
@@ -0,0 +1,572 @@
+// Helpers for device memory owned by the plugin
+use std::{
+    collections::BTreeMap,
+    ffi::c_void,
+    io,
+    ptr::{self, null_mut},
+    sync::{Mutex, OnceLock},
+};
+
+use crate::config::{self, MemoryMode};
+
+// Multiple of 4, so the pattern phase carries over between blocks
+const FILL_BLOCK: usize = 64;
+
+static MEMFD_NAME: &str = "tfp-device-memory\0";
+
+/// Simulated discrete device memory. A memfd is mapped twice: addresses handed
+/// out to TF point into an inaccessible mapping, so any host access faults,
+/// while copies and kernels go through a read-write alias of the same pages
+pub struct Arena {
+    fd: libc::c_int,
+    size: usize,
+    alignment: usize,
+    device_base: *mut u8,
+    host_base: *mut u8,
+    // Offset to length of free ranges, and of live allocations
+    free: Mutex<BTreeMap<usize, usize>>,
+    allocations: Mutex<BTreeMap<usize, usize>>,
+}
+
+unsafe impl Send for Arena {}
+unsafe impl Sync for Arena {}
+
+impl Arena {
+    pub fn new(size: usize, alignment: usize) -> io::Result<Self> {
+        unsafe {
+            let fd = libc::memfd_create(MEMFD_NAME.as_ptr() as *const i8, libc::MFD_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::ftruncate(fd, size as libc::off_t) != 0 {
+                let error = io::Error::last_os_error();
+                libc::close(fd);
+                return Err(error);
+            }
+
+            let device_base = match map_aligned(fd, size, alignment, libc::PROT_NONE) {
+                Ok(base) => base,
+                Err(error) => {
+                    libc::close(fd);
+                    return Err(error);
+                }
+            };
+            let host_base =
+                match map_aligned(fd, size, alignment, libc::PROT_READ | libc::PROT_WRITE) {
+                    Ok(base) => base,
+                    Err(error) => {
+                        libc::munmap(device_base as *mut c_void, size);
+                        libc::close(fd);
+                        return Err(error);
+                    }
+                };
+
+            Ok(Self {
+                fd,
+                size,
+                alignment,
+                device_base,
+                host_base,
+                free: Mutex::new(BTreeMap::from([(0, size)])),
+                allocations: Mutex::new(BTreeMap::new()),
+            })
+        }
+    }
+
+    /// First-fit allocation in the device address space, null when exhausted
+    pub fn allocate(&self, size: usize) -> *mut c_void {
+        if size == 0 {
+            return null_mut();
+        }
+        let size = size.next_multiple_of(self.alignment);
+
+        // Both mappings are aligned, so aligned offsets are aligned addresses
+        // in either of them. Larger alignments may leave a gap in front
+        let mut free = self.free.lock().unwrap();
+        let found = free.iter().find_map(|(&offset, &len)| {
+            let start = offset.next_multiple_of(self.alignment);
+            (start + size <= offset + len).then_some((offset, len, start))
+        });
+        let (offset, len, start) = match found {
+            Some(found) => found,
+            None => return null_mut(),
+        };
+
+        free.remove(&offset);
+        if start > offset {
+            free.insert(offset, start - offset);
+        }
+        if offset + len > start + size {
+            free.insert(start + size, offset + len - start - size);
+        }
+        self.allocations.lock().unwrap().insert(start, size);
+
+        unsafe { self.device_base.add(start) as *mut c_void }
+    }
+
+    pub fn deallocate(&self, ptr: *mut c_void) {
+        let offset = match self.offset(ptr) {
+            Some(offset) => offset,
+            None => return,
+        };
+        // Called from TF through the C API, where a panic would abort
+        let mut len = match self.allocations.lock().unwrap().remove(&offset) {
+            Some(len) => len,
+            None => {
+                log::error!("Deallocating unknown device memory at {:p}", ptr);
+                return;
+            }
+        };
+
+        // Merge with adjacent free ranges
+        let mut free = self.free.lock().unwrap();
+        let mut offset = offset;
+        if let Some((&next, &next_len)) = free.range(offset + len..).next() {
+            if next == offset + len {
+                free.remove(&next);
+                len += next_len;
+            }
+        }
+        if let Some((&prev, &prev_len)) = free.range(..offset).next_back() {
+            if prev + prev_len == offset {
+                free.remove(&prev);
+                offset = prev;
+                len += prev_len;
+            }
+        }
+        free.insert(offset, len);
+    }
+
+    /// Host-accessible alias of a device address, None if it isn't one
+    pub fn host_view(&self, ptr: *mut c_void) -> Option<*mut c_void> {
+        self.offset(ptr)
+            .map(|offset| unsafe { self.host_base.add(offset) as *mut c_void })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn bytes_in_use(&self) -> usize {
+        self.allocations.lock().unwrap().values().sum()
+    }
+
+    fn offset(&self, ptr: *mut c_void) -> Option<usize> {
+        let offset = (ptr as usize).wrapping_sub(self.device_base as usize);
+        (offset < self.size).then_some(offset)
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.device_base as *mut c_void, self.size);
+            libc::munmap(self.host_base as *mut c_void, self.size);
+            libc::close(self.fd);
+        }
+    }
+}
+
+// Maps the memfd at an address aligned to `alignment`, which may exceed the
+// page size mmap guarantees. Enough address space is reserved to find an
+// aligned start, the memfd is mapped over it and the rest is released
+unsafe fn map_aligned(
+    fd: libc::c_int,
+    size: usize,
+    alignment: usize,
+    prot: libc::c_int,
+) -> io::Result<*mut u8> {
+    let reserved = size + alignment;
+    let reservation = libc::mmap(
+        null_mut(),
+        reserved,
+        libc::PROT_NONE,
+        libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+        -1,
+        0,
+    );
+    if reservation == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+
+    let start = (reservation as usize).next_multiple_of(alignment);
+    let base = libc::mmap(
+        start as *mut c_void,
+        size,
+        prot,
+        libc::MAP_SHARED | libc::MAP_FIXED,
+        fd,
+        0,
+    );
+    if base == libc::MAP_FAILED {
+        let error = io::Error::last_os_error();
+        libc::munmap(reservation, reserved);
+        return Err(error);
+    }
+
+    let page_size = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+    let end = start + size.next_multiple_of(page_size);
+    if start > reservation as usize {
+        libc::munmap(reservation, start - reservation as usize);
+    }
+    if reservation as usize + reserved > end {
+        libc::munmap(end as *mut c_void, reservation as usize + reserved - end);
+    }
+    Ok(base as *mut u8)
+}
+
+fn arena() -> Option<&'static Arena> {
+    static ARENA: OnceLock<Option<Arena>> = OnceLock::new();
+    ARENA
+        .get_or_init(|| match config::get().memory_mode {
+            MemoryMode::Host => None,
+            MemoryMode::Discrete => Some(
+                Arena::new(config::get().memory_size, config::get().memory_alignment)
+                    .expect("Failed to map discrete device memory"),
+            ),
+        })
+        .as_ref()
+}
+
+/// # Safety
+///
+/// Result should be released with `deallocate`
+pub unsafe fn allocate(size: usize) -> *mut c_void {
+    let ptr = match arena() {
+        Some(arena) => arena.allocate(size),
+        None => {
+            // posix_memalign needs at least pointer alignment
+            let alignment = config::get().memory_alignment.max(size_of::<*mut c_void>());
+            let mut ptr = null_mut();
+            match libc::posix_memalign(&mut ptr, alignment, size) {
+                0 => ptr,
+                _ => null_mut(),
+            }
+        }
+    };
+    debug_assert!(
+        (ptr as usize).is_multiple_of(config::get().memory_alignment),
+        "Device allocation at {:p} isn't aligned to {} bytes",
+        ptr,
+        config::get().memory_alignment
+    );
+    ptr
+}
+
+/// # Safety
+///
+/// `ptr` should have been returned by `allocate`
+pub unsafe fn deallocate(ptr: *mut c_void) {
+    match arena() {
+        Some(arena) => arena.deallocate(ptr),
+        None => libc::free(ptr),
+    }
+}
+
+/// Free and total bytes, only known for discrete device memory
+pub fn usage() -> Option<(usize, usize)> {
+    arena().map(|arena| (arena.size() - arena.bytes_in_use(), arena.size()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostMemoryKind {
+    // Page-locked host memory, copies from it skip staging
+    Pinned,
+    // Host memory kernels may access directly, even with discrete device memory
+    Unified,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct HostAllocation {
+    size: usize,
+    kind: HostMemoryKind,
+    locked: bool,
+}
+
+// Start address to host allocations made through the stream executor
+static HOST_ALLOCATIONS: Mutex<BTreeMap<usize, HostAllocation>> = Mutex::new(BTreeMap::new());
+
+/// Page-aligned host allocation, or aligned to the configured boundary if it's
+/// larger. Pinned memory is also locked into RAM.
+/// Exceeding RLIMIT_MEMLOCK leaves it pageable, but it's still tracked
+///
+/// # Safety
+///
+/// Result should be released with `host_deallocate`
+pub unsafe fn host_allocate(size: usize, kind: HostMemoryKind) -> *mut c_void {
+    let page_size = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+    let alignment = page_size.max(config::get().memory_alignment);
+    let mut ptr = null_mut();
+    if libc::posix_memalign(&mut ptr, alignment, size.max(1)) != 0 {
+        return null_mut();
+    }
+
+    let locked = kind == HostMemoryKind::Pinned && libc::mlock(ptr, size) == 0;
+    HOST_ALLOCATIONS
+        .lock()
+        .unwrap()
+        .insert(ptr as usize, HostAllocation { size, kind, locked });
+    ptr
+}
+
+/// # Safety
+///
+/// `ptr` should have been returned by `host_allocate`
+pub unsafe fn host_deallocate(ptr: *mut c_void) {
+    if let Some(allocation) = HOST_ALLOCATIONS.lock().unwrap().remove(&(ptr as usize)) {
+        if allocation.locked {
+            libc::munlock(ptr, allocation.size);
+        }
+    }
+    libc::free(ptr);
+}
+
+/// Whether the whole range lies within a single allocation of the given kind
+pub fn is_host_memory(ptr: *const c_void, size: usize, kind: HostMemoryKind) -> bool {
+    let start = ptr as usize;
+    match HOST_ALLOCATIONS.lock().unwrap().range(..=start).next_back() {
+        Some((base, allocation)) => {
+            allocation.kind == kind && start + size <= base + allocation.size
+        }
+        None => false,
+    }
+}
+
+/// Translates device memory into an address the host may access, which is
+/// only needed for discrete device memory. Other pointers are returned as is
+pub fn host_view(ptr: *mut c_void) -> *mut c_void {
+    arena()
+        .and_then(|arena| arena.host_view(ptr))
+        .unwrap_or(ptr)
+}
+
+/// Repeats the 32-bit pattern in memory order over `size` bytes.
+/// A trailing partial word receives the leading bytes of the pattern
+///
+/// # Safety
+///
+/// `dst` should be valid for writes of `size` bytes
+pub unsafe fn fill32(dst: *mut u8, pattern: u32, size: usize) {
+    let bytes = pattern.to_ne_bytes();
+    if bytes.iter().all(|byte| *byte == bytes[0]) {
+        libc::memset(dst as *mut libc::c_void, bytes[0] as i32, size);
+        return;
+    }
+
+    let mut block = [0u8; FILL_BLOCK];
+    for (i, byte) in block.iter_mut().enumerate() {
+        *byte = bytes[i % bytes.len()];
+    }
+
+    // Whole blocks compile down to wide vector stores
+    let mut offset = 0;
+    while offset + FILL_BLOCK <= size {
+        (dst.add(offset) as *mut [u8; FILL_BLOCK]).write_unaligned(block);
+        offset += FILL_BLOCK;
+    }
+    ptr::copy_nonoverlapping(block.as_ptr(), dst.add(offset), size - offset);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        allocate, deallocate, fill32, host_allocate, host_deallocate, is_host_memory, Arena,
+        HostMemoryKind,
+    };
+    use crate::config;
+    use std::ffi::c_void;
+
+    fn reference(pattern: u32, size: usize) -> Vec<u8> {
+        pattern
+            .to_ne_bytes()
+            .into_iter()
+            .cycle()
+            .take(size)
+            .collect()
+    }
+
+    fn check(pattern: u32, size: usize) {
+        // Guard bytes around the buffer must stay untouched
+        let mut buffer = vec![0xaau8; size + 2];
+        unsafe { fill32(buffer.as_mut_ptr().add(1), pattern, size) };
+
+        assert_eq!(buffer[0], 0xaa);
+        assert_eq!(buffer[size + 1], 0xaa);
+        assert_eq!(
+            buffer[1..=size],
+            reference(pattern, size)[..],
+            "size {}",
+            size
+        );
+    }
+
+    #[test]
+    fn fill32_odd_sizes() {
+        for size in 0..=200 {
+            check(0x3f800000, size);
+            check(0x01020304, size);
+        }
+    }
+
+    #[test]
+    fn fill32_uniform_bytes() {
+        for size in [0, 1, 3, 4, 63, 64, 65, 1000] {
+            check(0, size);
+            check(0xffffffff, size);
+            check(0x7f7f7f7f, size);
+        }
+    }
+
+    #[test]
+    fn fill32_large_buffer() {
+        check(0x3f800000, (1 << 20) + 3);
+    }
+
+    #[test]
+    fn fill32_floats() {
+        let mut buffer = vec![0f32; 1027];
+        unsafe { fill32(buffer.as_mut_ptr() as *mut u8, 1f32.to_bits(), 1027 * 4) };
+
+        assert!(buffer.iter().all(|x| *x == 1f32));
+    }
+
+    // Touches the address in a child process, so a fault doesn't kill the tests
+    fn faults(ptr: *mut c_void) -> bool {
+        unsafe {
+            let pid = libc::fork();
+            if pid == 0 {
+                std::ptr::read_volatile(ptr as *const u8);
+                libc::_exit(0);
+            }
+
+            let mut status = 0;
+            libc::waitpid(pid, &mut status, 0);
+            libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGSEGV
+        }
+    }
+
+    #[test]
+    fn arena_device_memory_faults_on_host_access() {
+        let arena = Arena::new(1 << 20, 64).unwrap();
+        let ptr = arena.allocate(256);
+        let view = arena.host_view(ptr).unwrap();
+
+        assert!(faults(ptr));
+        assert!(!faults(view));
+    }
+
+    #[test]
+    fn arena_views_alias_device_memory() {
+        let arena = Arena::new(1 << 20, 64).unwrap();
+        let ptr = arena.allocate(4);
+
+        unsafe { *(arena.host_view(ptr).unwrap() as *mut u32) = 0x3f800000 };
+        assert_eq!(
+            unsafe { *(arena.host_view(ptr).unwrap() as *const u32) },
+            0x3f800000
+        );
+
+        let host = Box::into_raw(Box::new(0u32)) as *mut c_void;
+        assert_eq!(arena.host_view(host), None);
+        std::mem::drop(unsafe { Box::from_raw(host as *mut u32) });
+    }
+
+    #[test]
+    fn arena_allocations_are_aligned_and_disjoint() {
+        let arena = Arena::new(1 << 20, 64).unwrap();
+        let sizes = [1, 3, 64, 65, 1000, 4096];
+        let ptrs: Vec<_> = sizes.iter().map(|size| arena.allocate(*size)).collect();
+
+        for (i, ptr) in ptrs.iter().enumerate() {
+            assert_eq!(*ptr as usize % 64, 0);
+            for (j, other) in ptrs.iter().enumerate().skip(i + 1) {
+                let (a, b) = (*ptr as usize, *other as usize);
+                assert!(a + sizes[i] <= b || b + sizes[j] <= a);
+            }
+        }
+    }
+
+    #[test]
+    fn arena_alignment_above_page_size() {
+        let arena = Arena::new(1 << 20, 1 << 16).unwrap();
+        let ptrs: Vec<_> = (0..4).map(|_| arena.allocate(100)).collect();
+
+        for ptr in &ptrs {
+            assert!(!ptr.is_null());
+            assert_eq!(*ptr as usize % (1 << 16), 0);
+            assert_eq!(arena.host_view(*ptr).unwrap() as usize % (1 << 16), 0);
+        }
+        for ptr in ptrs {
+            arena.deallocate(ptr);
+        }
+        assert_eq!(arena.bytes_in_use(), 0);
+        assert!(!arena.allocate(1 << 19).is_null());
+    }
+
+    #[test]
+    fn device_memory_is_aligned() {
+        for size in [1, 3, 17, 100, 4097] {
+            let ptr = unsafe { allocate(size) };
+            assert_eq!(ptr as usize % config::get().memory_alignment, 0);
+            unsafe { deallocate(ptr) };
+        }
+    }
+
+    #[test]
+    fn arena_reuses_freed_memory() {
+        let arena = Arena::new(4096, 64).unwrap();
+        let a = arena.allocate(1024);
+        let b = arena.allocate(1024);
+        let c = arena.allocate(2048);
+        assert!(!c.is_null());
+        assert!(arena.allocate(1).is_null());
+
+        // Freed neighbours merge into a range fitting a larger allocation
+        arena.deallocate(a);
+        arena.deallocate(b);
+        assert_eq!(arena.bytes_in_use(), 2048);
+        assert_eq!(arena.allocate(2048), a);
+    }
+
+    #[test]
+    fn arena_ignores_unknown_pointers() {
+        let arena = Arena::new(4096, 64).unwrap();
+        let ptr = arena.allocate(64);
+
+        arena.deallocate(unsafe { ptr.add(8) });
+        assert_eq!(arena.bytes_in_use(), 64);
+        arena.deallocate(ptr);
+        assert_eq!(arena.bytes_in_use(), 0);
+    }
+
+    #[test]
+    fn host_memory_is_page_aligned() {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        for size in [1, 100, page_size + 1] {
+            let ptr = unsafe { host_allocate(size, HostMemoryKind::Pinned) };
+            assert_eq!(ptr as usize % page_size, 0);
+            unsafe { host_deallocate(ptr) };
+        }
+    }
+
+    #[test]
+    fn host_memory_registry() {
+        let pinned = unsafe { host_allocate(1024, HostMemoryKind::Pinned) };
+        let unified = unsafe { host_allocate(1024, HostMemoryKind::Unified) };
+        let interior = unsafe { pinned.add(512) };
+
+        assert!(is_host_memory(pinned, 1024, HostMemoryKind::Pinned));
+        assert!(is_host_memory(interior, 512, HostMemoryKind::Pinned));
+        assert!(!is_host_memory(interior, 513, HostMemoryKind::Pinned));
+        assert!(!is_host_memory(unified, 8, HostMemoryKind::Pinned));
+        assert!(is_host_memory(unified, 8, HostMemoryKind::Unified));
+
+        let pageable = [0u8; 16];
+        let pageable = pageable.as_ptr() as *const c_void;
+        assert!(!is_host_memory(pageable, 16, HostMemoryKind::Pinned));
+
+        unsafe { host_deallocate(pinned) };
+        assert!(!is_host_memory(interior, 8, HostMemoryKind::Pinned));
+        unsafe { host_deallocate(unified) };
+    }
+}
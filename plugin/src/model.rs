@@ -0,0 +1,192 @@
+// Timing model of the emulated device: stream workers stay busy for the
+// simulated duration of every activity, so overlap and pipelining show up
+use std::{collections::BTreeMap, fmt, time::Duration};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    HostToDevice,
+    DeviceToHost,
+    DeviceToDevice,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activity {
+    Memcpy(Direction, u64),
+    Memset(u64),
+    // Carries the time the kernel took to compute on the host
+    Kernel(Duration),
+}
+
+impl Activity {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Activity::Memcpy(Direction::HostToDevice, _) => "htod",
+            Activity::Memcpy(Direction::DeviceToHost, _) => "dtoh",
+            Activity::Memcpy(Direction::DeviceToDevice, _) => "dtod",
+            Activity::Memset(_) => "memset",
+            Activity::Kernel(_) => "kernel",
+        }
+    }
+}
+
+/// Zero disables the respective part of the model
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct DeviceModel {
+    // Bytes per second
+    pub htod_bandwidth: f64,
+    pub dtoh_bandwidth: f64,
+    pub dtod_bandwidth: f64,
+    pub kernel_latency: Duration,
+    // How many times faster than the host the device computes kernels
+    pub compute_factor: f64,
+}
+
+impl DeviceModel {
+    pub fn is_enabled(&self) -> bool {
+        *self != Self::default()
+    }
+
+    pub fn duration(&self, activity: &Activity) -> Duration {
+        let transfer = |bytes: u64, bandwidth: f64| match bandwidth > 0.0 {
+            true => Duration::from_secs_f64(bytes as f64 / bandwidth),
+            false => Duration::ZERO,
+        };
+
+        match *activity {
+            Activity::Memcpy(Direction::HostToDevice, bytes) => {
+                transfer(bytes, self.htod_bandwidth)
+            }
+            Activity::Memcpy(Direction::DeviceToHost, bytes) => {
+                transfer(bytes, self.dtoh_bandwidth)
+            }
+            // Memsets only write device memory, which is modelled by the dtod bandwidth
+            Activity::Memcpy(Direction::DeviceToDevice, bytes) | Activity::Memset(bytes) => {
+                transfer(bytes, self.dtod_bandwidth)
+            }
+            Activity::Kernel(compute) => match self.compute_factor > 0.0 {
+                true => self.kernel_latency + compute.div_f64(self.compute_factor),
+                false => self.kernel_latency,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct KindStats {
+    count: u64,
+    busy: Duration,
+}
+
+/// Simulated busy time of a stream per kind of activity
+#[derive(Debug, Default)]
+pub struct Utilization {
+    kinds: BTreeMap<&'static str, KindStats>,
+}
+
+impl Utilization {
+    pub fn record(&mut self, activity: &Activity, busy: Duration) {
+        let stats = self.kinds.entry(activity.kind()).or_default();
+        stats.count += 1;
+        stats.busy += busy;
+    }
+
+    pub fn busy(&self) -> Duration {
+        self.kinds.values().map(|stats| stats.busy).sum()
+    }
+
+    /// Summary over the stream lifetime, e.g. `1.500 ms busy of 10.000 ms (15.0%); htod 2 x 1.000 ms`
+    pub fn summary(&self, lifetime: Duration) -> String {
+        let mut summary = format!(
+            "{} busy of {} ({:.1}%)",
+            Millis(self.busy()),
+            Millis(lifetime),
+            100.0 * self.busy().as_secs_f64() / lifetime.as_secs_f64().max(f64::EPSILON),
+        );
+        for (kind, stats) in &self.kinds {
+            summary += &format!("; {} {} x {}", kind, stats.count, Millis(stats.busy));
+        }
+        summary
+    }
+}
+
+struct Millis(Duration);
+
+impl fmt::Display for Millis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.3} ms", self.0.as_secs_f64() * 1000.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Activity, DeviceModel, Direction, Utilization};
+    use std::time::Duration;
+
+    #[test]
+    fn model_disabled_by_default() {
+        let model = DeviceModel::default();
+
+        assert!(!model.is_enabled());
+        let activity = Activity::Memcpy(Direction::HostToDevice, 1 << 30);
+        assert_eq!(model.duration(&activity), Duration::ZERO);
+        let activity = Activity::Kernel(Duration::from_millis(5));
+        assert_eq!(model.duration(&activity), Duration::ZERO);
+    }
+
+    #[test]
+    fn model_transfer_durations() {
+        let model = DeviceModel {
+            htod_bandwidth: 1e9,
+            dtoh_bandwidth: 2e9,
+            dtod_bandwidth: 4e9,
+            ..Default::default()
+        };
+
+        let duration = |activity| model.duration(&activity);
+        let ms = Duration::from_millis;
+        assert_eq!(
+            duration(Activity::Memcpy(Direction::HostToDevice, 1_000_000)),
+            ms(1)
+        );
+        assert_eq!(
+            duration(Activity::Memcpy(Direction::DeviceToHost, 1_000_000)),
+            ms(1) / 2
+        );
+        assert_eq!(
+            duration(Activity::Memcpy(Direction::DeviceToDevice, 4_000_000)),
+            ms(1)
+        );
+        assert_eq!(duration(Activity::Memset(4_000_000)), ms(1));
+    }
+
+    #[test]
+    fn model_kernel_durations() {
+        let mut model = DeviceModel {
+            kernel_latency: Duration::from_micros(10),
+            ..Default::default()
+        };
+        let activity = Activity::Kernel(Duration::from_micros(100));
+        assert_eq!(model.duration(&activity), Duration::from_micros(10));
+
+        model.compute_factor = 4.0;
+        assert_eq!(model.duration(&activity), Duration::from_micros(35));
+    }
+
+    #[test]
+    fn utilization_summary() {
+        let mut utilization = Utilization::default();
+        let htod = Activity::Memcpy(Direction::HostToDevice, 8);
+        utilization.record(&htod, Duration::from_millis(1));
+        utilization.record(&htod, Duration::from_millis(1));
+        utilization.record(
+            &Activity::Kernel(Duration::ZERO),
+            Duration::from_micros(500),
+        );
+
+        assert_eq!(utilization.busy(), Duration::from_micros(2500));
+        assert_eq!(
+            utilization.summary(Duration::from_millis(10)),
+            "2.500 ms busy of 10.000 ms (25.0%); htod 2 x 2.000 ms; kernel 1 x 0.500 ms"
+        );
+    }
+}
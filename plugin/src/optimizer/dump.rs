@@ -0,0 +1,490 @@
+// Dumps of the graphs the optimizer is given and returns, in protobuf text
+// format and encoded, along with a summary of what the passes changed.
+// Invocations are numbered in order, so that the files of one are found next
+// to each other
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use prost::Message;
+
+use crate::bindings::graph::{
+    AttrValue, AttrValueValue, GraphDef, NameAttrList, NodeDef, TensorProto, TensorShapeProto,
+};
+
+static SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+
+/// Dump of one invocation, whose input is written when it's created
+pub struct Dump {
+    dir: PathBuf,
+    sequence: usize,
+    input: GraphDef,
+}
+
+impl Dump {
+    pub fn new(dir: &Path, input: &GraphDef) -> Self {
+        let dump = Self {
+            dir: dir.to_owned(),
+            sequence: SEQUENCE.fetch_add(1, Ordering::Relaxed),
+            input: input.clone(),
+        };
+        dump.write("input", input);
+        dump
+    }
+
+    pub fn finish(self, output: &GraphDef) {
+        self.write("output", output);
+        // Dumping was asked for, so the summary shows whatever the log level
+        eprintln!(
+            "Optimized graph {}: {}",
+            self.sequence,
+            Diff::new(&self.input, output)
+        );
+    }
+
+    // The text is for reading, the encoded graph has the parts the text only
+    // notes, like the function library, and loads back into TF
+    fn write(&self, suffix: &str, graph: &GraphDef) {
+        let name = format!("graph_{:04}_{}", self.sequence, suffix);
+        let files = [
+            ("pbtxt", to_text(graph).into_bytes()),
+            ("pb", graph.encode_to_vec()),
+        ];
+        for (extension, contents) in files {
+            let path = self.dir.join(format!("{}.{}", name, extension));
+            let result =
+                std::fs::create_dir_all(&self.dir).and_then(|_| std::fs::write(&path, contents));
+            match result {
+                Ok(()) => log::info!("Wrote {}", path.display()),
+                Err(error) => log::warn!("Failed to write {}: {}", path.display(), error),
+            }
+        }
+    }
+}
+
+/// Nodes added, removed, rewired or otherwise changed by the passes
+#[derive(Debug, Default, PartialEq)]
+pub struct Diff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    // With the inputs before and after
+    pub rewired: Vec<(String, Vec<String>, Vec<String>)>,
+    // Whose op, device or attrs changed
+    pub changed: Vec<String>,
+}
+
+impl Diff {
+    pub fn new(before: &GraphDef, after: &GraphDef) -> Self {
+        let nodes = |graph: &GraphDef| -> BTreeMap<String, NodeDef> {
+            graph
+                .node
+                .iter()
+                .map(|node| (node.name.clone(), node.clone()))
+                .collect()
+        };
+        let (before, after) = (nodes(before), nodes(after));
+
+        let mut diff = Self::default();
+        for (name, node) in &after {
+            let old = match before.get(name) {
+                Some(old) => old,
+                None => {
+                    diff.added.push(name.clone());
+                    continue;
+                }
+            };
+            if old.input != node.input {
+                diff.rewired
+                    .push((name.clone(), old.input.clone(), node.input.clone()));
+            }
+            if old.op != node.op || old.device != node.device || old.attr != node.attr {
+                diff.changed.push(name.clone());
+            }
+        }
+        diff.removed = before
+            .keys()
+            .filter(|name| !after.contains_key(*name))
+            .cloned()
+            .collect();
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+impl Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("unchanged");
+        }
+        write!(
+            f,
+            "{} added, {} removed, {} rewired, {} changed",
+            self.added.len(),
+            self.removed.len(),
+            self.rewired.len(),
+            self.changed.len()
+        )?;
+        for name in &self.added {
+            write!(f, "\n  + {}", name)?;
+        }
+        for name in &self.removed {
+            write!(f, "\n  - {}", name)?;
+        }
+        for (name, before, after) in &self.rewired {
+            write!(f, "\n  ~ {}: {:?} -> {:?}", name, before, after)?;
+        }
+        for name in &self.changed {
+            write!(f, "\n  * {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Protobuf text format of a graph, like the `.pbtxt` files TF writes.
+/// Parts kept encoded are only noted in comments, they're in the `.pb` dump
+pub fn to_text(graph: &GraphDef) -> String {
+    let mut text = Text::default();
+    for node in &graph.node {
+        text.message("node", |text| text.node(node));
+    }
+    if let Some(versions) = &graph.versions {
+        text.message("versions", |text| {
+            text.int("producer", versions.producer as i64);
+            text.int("min_consumer", versions.min_consumer as i64);
+            for &consumer in &versions.bad_consumers {
+                text.field("bad_consumers", consumer);
+            }
+        });
+    }
+    text.int("version", graph.version as i64);
+    text.opaque("library", graph.library.as_deref());
+    text.opaque("debug_info", graph.debug_info.as_deref());
+    text.out
+}
+
+#[derive(Default)]
+struct Text {
+    out: String,
+    indent: usize,
+}
+
+impl Text {
+    fn field(&mut self, name: &str, value: impl Display) {
+        let _ = writeln!(
+            self.out,
+            "{:indent$}{}: {}",
+            "",
+            name,
+            value,
+            indent = self.indent
+        );
+    }
+
+    // Scalars at their default value are left out, as in proto3
+    fn int(&mut self, name: &str, value: i64) {
+        if value != 0 {
+            self.field(name, value);
+        }
+    }
+
+    fn string(&mut self, name: &str, value: &[u8]) {
+        if !value.is_empty() {
+            self.field(name, escape(value));
+        }
+    }
+
+    fn data_type(&mut self, name: &str, value: i32) {
+        self.field(name, data_type_name(value));
+    }
+
+    fn opaque(&mut self, name: &str, value: Option<&[u8]>) {
+        if let Some(value) = value {
+            let _ = writeln!(
+                self.out,
+                "{:indent$}# {}: {} encoded bytes",
+                "",
+                name,
+                value.len(),
+                indent = self.indent
+            );
+        }
+    }
+
+    fn message(&mut self, name: &str, body: impl FnOnce(&mut Self)) {
+        let _ = writeln!(self.out, "{:indent$}{} {{", "", name, indent = self.indent);
+        self.indent += 2;
+        body(self);
+        self.indent -= 2;
+        let _ = writeln!(self.out, "{:indent$}}}", "", indent = self.indent);
+    }
+
+    fn node(&mut self, node: &NodeDef) {
+        self.string("name", node.name.as_bytes());
+        self.string("op", node.op.as_bytes());
+        for input in &node.input {
+            self.field("input", escape(input.as_bytes()));
+        }
+        self.string("device", node.device.as_bytes());
+        self.attrs(&node.attr);
+        self.opaque(
+            "experimental_debug_info",
+            node.experimental_debug_info.as_deref(),
+        );
+        self.opaque("experimental_type", node.experimental_type.as_deref());
+    }
+
+    fn attrs(&mut self, attrs: &BTreeMap<String, AttrValue>) {
+        for (key, value) in attrs {
+            self.message("attr", |text| {
+                text.field("key", escape(key.as_bytes()));
+                text.message("value", |text| text.attr(value));
+            });
+        }
+    }
+
+    fn attr(&mut self, attr: &AttrValue) {
+        match &attr.value {
+            Some(AttrValueValue::List(list)) => self.message("list", |text| {
+                for value in &list.s {
+                    text.field("s", escape(value));
+                }
+                for value in &list.i {
+                    text.field("i", value);
+                }
+                for value in &list.f {
+                    text.field("f", value);
+                }
+                for value in &list.b {
+                    text.field("b", value);
+                }
+                for &value in &list.r#type {
+                    text.data_type("type", value);
+                }
+                for shape in &list.shape {
+                    text.message("shape", |text| text.shape(shape));
+                }
+                for tensor in &list.tensor {
+                    text.message("tensor", |text| text.tensor(tensor));
+                }
+                for func in &list.func {
+                    text.message("func", |text| text.func(func));
+                }
+            }),
+            Some(AttrValueValue::S(value)) => self.field("s", escape(value)),
+            Some(AttrValueValue::I(value)) => self.field("i", value),
+            Some(AttrValueValue::F(value)) => self.field("f", value),
+            Some(AttrValueValue::B(value)) => self.field("b", value),
+            Some(AttrValueValue::Type(value)) => self.data_type("type", *value),
+            Some(AttrValueValue::Shape(shape)) => self.message("shape", |text| text.shape(shape)),
+            Some(AttrValueValue::Tensor(tensor)) => {
+                self.message("tensor", |text| text.tensor(tensor))
+            }
+            Some(AttrValueValue::Placeholder(value)) => {
+                self.field("placeholder", escape(value.as_bytes()))
+            }
+            Some(AttrValueValue::Func(func)) => self.message("func", |text| text.func(func)),
+            None => {}
+        }
+    }
+
+    fn shape(&mut self, shape: &TensorShapeProto) {
+        for dim in &shape.dim {
+            self.message("dim", |text| {
+                text.int("size", dim.size);
+                text.string("name", dim.name.as_bytes());
+            });
+        }
+        if shape.unknown_rank {
+            self.field("unknown_rank", true);
+        }
+    }
+
+    fn tensor(&mut self, tensor: &TensorProto) {
+        self.data_type("dtype", tensor.dtype);
+        if let Some(shape) = &tensor.tensor_shape {
+            self.message("tensor_shape", |text| text.shape(shape));
+        }
+        self.int("version_number", tensor.version_number as i64);
+        self.string("tensor_content", &tensor.tensor_content);
+        let values: [(&str, Vec<String>); 11] = [
+            ("half_val", strings(&tensor.half_val)),
+            ("float_val", strings(&tensor.float_val)),
+            ("double_val", strings(&tensor.double_val)),
+            ("int_val", strings(&tensor.int_val)),
+            ("scomplex_val", strings(&tensor.scomplex_val)),
+            ("int64_val", strings(&tensor.int64_val)),
+            ("bool_val", strings(&tensor.bool_val)),
+            ("dcomplex_val", strings(&tensor.dcomplex_val)),
+            ("uint32_val", strings(&tensor.uint32_val)),
+            ("uint64_val", strings(&tensor.uint64_val)),
+            (
+                "string_val",
+                tensor
+                    .string_val
+                    .iter()
+                    .map(|value| escape(value))
+                    .collect(),
+            ),
+        ];
+        for (name, values) in values {
+            for value in values {
+                self.field(name, value);
+            }
+        }
+        for value in &tensor.resource_handle_val {
+            self.opaque("resource_handle_val", Some(value));
+        }
+        for value in &tensor.variant_val {
+            self.opaque("variant_val", Some(value));
+        }
+        self.string("float8_val", &tensor.float8_val);
+    }
+
+    fn func(&mut self, func: &NameAttrList) {
+        self.string("name", func.name.as_bytes());
+        self.attrs(&func.attr);
+    }
+}
+
+fn strings<T: Display>(values: &[T]) -> Vec<String> {
+    values.iter().map(ToString::to_string).collect()
+}
+
+// Quoted, with the escapes of the C-style strings of text format
+fn escape(value: &[u8]) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for &byte in value {
+        match byte {
+            b'\n' => escaped.push_str("\\n"),
+            b'\r' => escaped.push_str("\\r"),
+            b'\t' => escaped.push_str("\\t"),
+            b'"' => escaped.push_str("\\\""),
+            b'\\' => escaped.push_str("\\\\"),
+            b' '..=b'~' => escaped.push(byte as char),
+            _ => {
+                let _ = write!(escaped, "\\{:03o}", byte);
+            }
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+// Names of the DataType enum, which text format accepts along with numbers
+fn data_type_name(value: i32) -> String {
+    static NAMES: [&str; 24] = [
+        "DT_INVALID",
+        "DT_FLOAT",
+        "DT_DOUBLE",
+        "DT_INT32",
+        "DT_UINT8",
+        "DT_INT16",
+        "DT_INT8",
+        "DT_STRING",
+        "DT_COMPLEX64",
+        "DT_INT64",
+        "DT_BOOL",
+        "DT_QINT8",
+        "DT_QUINT8",
+        "DT_QINT32",
+        "DT_BFLOAT16",
+        "DT_QINT16",
+        "DT_QUINT16",
+        "DT_UINT16",
+        "DT_COMPLEX128",
+        "DT_HALF",
+        "DT_RESOURCE",
+        "DT_VARIANT",
+        "DT_UINT32",
+        "DT_UINT64",
+    ];
+    match usize::try_from(value)
+        .ok()
+        .and_then(|value| NAMES.get(value))
+    {
+        Some(name) => name.to_string(),
+        None => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape, to_text, Diff};
+    use crate::{
+        bindings::graph::{AttrValue, TensorProto},
+        optimizer::rewrite::tests::{graph, node},
+    };
+
+    #[test]
+    fn dump_text() {
+        let mut perm = node("perm", "Const", &[]);
+        perm.device = String::new();
+        perm.attr.clear();
+        perm.attr.insert(
+            "value".to_owned(),
+            AttrValue::tensor(TensorProto::int32s(&[0, 2])),
+        );
+        let graph = graph(vec![perm]);
+        assert_eq!(
+            to_text(&graph),
+            r#"node {
+  name: "perm"
+  op: "Const"
+  attr {
+    key: "value"
+    value {
+      tensor {
+        dtype: DT_INT32
+        tensor_shape {
+          dim {
+            size: 2
+          }
+        }
+        int_val: 0
+        int_val: 2
+      }
+    }
+  }
+}
+"#
+        );
+        assert_eq!(escape(b"a\"\\\n\x01"), r#""a\"\\\n\001""#);
+    }
+
+    #[test]
+    fn dump_diff() {
+        let before = graph(vec![
+            node("x", "Placeholder", &[]),
+            node("bias_add", "BiasAdd", &["x", "bias"]),
+            node("relu", "Relu", &["bias_add"]),
+            node("output", "Identity", &["relu"]),
+        ]);
+        let mut after = graph(vec![
+            node("x", "Placeholder", &[]),
+            node("relu", "_FusedBiasAddActivation", &["x", "bias"]),
+            node("output", "Identity", &["relu"]),
+        ]);
+
+        let diff = Diff::new(&before, &after);
+        assert_eq!(diff.added, Vec::<String>::new());
+        assert_eq!(diff.removed, ["bias_add"]);
+        assert_eq!(diff.rewired.len(), 1);
+        assert_eq!(diff.rewired[0].0, "relu");
+        assert_eq!(diff.changed, ["relu"]);
+        assert_eq!(
+            diff.to_string(),
+            "0 added, 1 removed, 1 rewired, 1 changed\n  - bias_add\n  \
+             ~ relu: [\"bias_add\"] -> [\"x\", \"bias\"]\n  * relu"
+        );
+
+        after = before.clone();
+        assert!(Diff::new(&before, &after).is_empty());
+    }
+}
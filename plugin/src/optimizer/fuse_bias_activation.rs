@@ -0,0 +1,168 @@
+// Replaces BiasAdd followed by Relu or Relu6 with the plugin's fused kernel,
+// which saves writing and reading back the intermediate tensor. The kernel
+// only handles 4-D inputs, so others, e.g. of Dense layers, are left alone
+use super::{
+    rewrite::{self, Match, Pattern, Rewrite},
+    Item, Pass,
+};
+use crate::{
+    bindings::{
+        graph::{AttrValue, GraphDef, NodeDef},
+        raw::TF_FLOAT,
+    },
+    kernels::fused_bias_add::FUSED_BIAS_ADD_KERNEL_NAME,
+    DEVICE_TYPE,
+};
+
+pub struct FuseBiasActivation {
+    pattern: Pattern,
+}
+
+impl FuseBiasActivation {
+    pub fn new() -> Self {
+        Self {
+            pattern: Pattern::new("activation", &["Relu", "Relu6"])
+                .device_type(DEVICE_TYPE)
+                .dtypes(&[TF_FLOAT])
+                .node(
+                    Pattern::new("bias_add", &["BiasAdd"])
+                        .device_type(DEVICE_TYPE)
+                        .dtypes(&[TF_FLOAT])
+                        .rank(4)
+                        .any("value")
+                        .any("bias"),
+                ),
+        }
+    }
+}
+
+impl Rewrite for FuseBiasActivation {
+    fn pattern(&self) -> &Pattern {
+        &self.pattern
+    }
+
+    fn replace(&self, matched: &Match) -> Option<Vec<NodeDef>> {
+        let bias_add = matched.node("bias_add");
+        let activation = matched.node("activation");
+        let mut node = NodeDef {
+            name: matched.root().to_owned(),
+            op: FUSED_BIAS_ADD_KERNEL_NAME.trim_end_matches('\0').to_owned(),
+            input: vec![
+                matched.input("value").to_owned(),
+                matched.input("bias").to_owned(),
+            ],
+            device: activation.device.clone(),
+            ..Default::default()
+        };
+        node.attr.insert("T".to_owned(), bias_add.attr["T"].clone());
+        node.attr.insert(
+            "data_format".to_owned(),
+            bias_add
+                .attr
+                .get("data_format")
+                .cloned()
+                .unwrap_or_else(|| AttrValue::string("NHWC")),
+        );
+        node.attr.insert(
+            "fused_ops".to_owned(),
+            AttrValue::string_list(&["BiasAdd", &activation.op]),
+        );
+        Some(vec![node])
+    }
+}
+
+impl Pass for FuseBiasActivation {
+    fn name(&self) -> &'static str {
+        "FuseBiasActivation"
+    }
+
+    fn run(&self, graph: &mut GraphDef, item: &Item) -> Result<bool, String> {
+        Ok(rewrite::apply(graph, item, self) > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FuseBiasActivation;
+    use crate::{
+        bindings::graph::{AttrValue, AttrValueValue},
+        optimizer::{
+            rewrite::tests::{graph, node, ops},
+            Item, Pass,
+        },
+    };
+
+    fn ranked(node: &str, rank: usize) -> Item {
+        Item {
+            output_ranks: [(node.to_owned(), vec![Some(rank)])].into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn fuse_bias_relu() {
+        let mut bias_add = node("bias_add", "BiasAdd", &["x", "bias"]);
+        bias_add
+            .attr
+            .insert("data_format".to_owned(), AttrValue::string("NCHW"));
+        let mut graph = graph(vec![
+            node("x", "Placeholder", &[]),
+            node("bias", "Const", &[]),
+            bias_add,
+            node("relu", "Relu", &["bias_add"]),
+            node("output", "Identity", &["relu"]),
+        ]);
+
+        let pass = FuseBiasActivation::new();
+        assert_eq!(pass.run(&mut graph, &ranked("bias_add", 4)), Ok(true));
+        assert_eq!(
+            ops(&graph),
+            [
+                ("x", "Placeholder"),
+                ("bias", "Const"),
+                ("relu", "_FusedBiasAddActivation"),
+                ("output", "Identity")
+            ]
+        );
+
+        let fused = &graph.node[2];
+        assert_eq!(fused.input, ["x", "bias"]);
+        assert_eq!(fused.attr["data_format"].as_str(), Some("NCHW"));
+        match &fused.attr["fused_ops"].value {
+            Some(AttrValueValue::List(list)) => {
+                assert_eq!(list.s, [b"BiasAdd".to_vec(), b"Relu".to_vec()])
+            }
+            value => panic!("{:?}", value),
+        }
+
+        assert_eq!(pass.run(&mut graph, &ranked("bias_add", 4)), Ok(false));
+    }
+
+    #[test]
+    fn fuse_bias_relu_only_4d() {
+        let mut graph = graph(vec![
+            node("x", "Placeholder", &[]),
+            node("bias", "Const", &[]),
+            node("bias_add", "BiasAdd", &["x", "bias"]),
+            node("relu", "Relu", &["bias_add"]),
+        ]);
+        let pass = FuseBiasActivation::new();
+        assert_eq!(pass.run(&mut graph, &ranked("bias_add", 2)), Ok(false));
+        assert_eq!(pass.run(&mut graph, &Item::default()), Ok(false));
+        assert_eq!(pass.run(&mut graph, &ranked("bias_add", 4)), Ok(true));
+    }
+
+    #[test]
+    fn fuse_bias_relu_only_on_device() {
+        let mut relu = node("relu", "Relu", &["bias_add"]);
+        relu.device = "/job:localhost/replica:0/task:0/device:CPU:0".to_owned();
+        let mut graph = graph(vec![
+            node("x", "Placeholder", &[]),
+            node("bias", "Const", &[]),
+            node("bias_add", "BiasAdd", &["x", "bias"]),
+            relu,
+        ]);
+        let pass = FuseBiasActivation::new();
+        assert_eq!(pass.run(&mut graph, &ranked("bias_add", 4)), Ok(false));
+    }
+}
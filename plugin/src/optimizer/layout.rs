@@ -0,0 +1,458 @@
+// Converts NCHW ops on the device to NHWC, the layout the kernels are written
+// for. Transposes are only inserted where converted nodes meet the rest of the
+// graph, and a transpose followed by its inverse is bypassed. The device has no
+// Transpose kernel, so the inserted ones run on the host CPU
+use std::collections::{HashMap, HashSet};
+
+use super::{
+    rewrite::{device_type_of, host_device, unique_name},
+    Item, Pass,
+};
+use crate::{
+    bindings::{
+        graph::{parse_input, AttrValue, AttrValueValue, GraphDef, NodeDef, TensorProto},
+        raw::{TF_FLOAT, TF_INT32},
+    },
+    DEVICE_TYPE,
+};
+
+// Ops taking 4D inputs, which can start a converted region
+static RANK_4_OPS: [&str; 6] = [
+    "Conv2D",
+    "MaxPool",
+    "AvgPool",
+    "FusedBatchNorm",
+    "FusedBatchNormV2",
+    "FusedBatchNormV3",
+];
+// Ops accepting other ranks, only converted when fed by a converted node
+static BIAS_OPS: [&str; 2] = ["BiasAdd", "_FusedBiasAddActivation"];
+// Elementwise ops, which are the same in any layout
+static AGNOSTIC_OPS: [&str; 6] = ["Relu", "Relu6", "Elu", "Tanh", "Sigmoid", "Identity"];
+
+// Permutations of Transpose, output dimension i being input dimension perm[i]
+const TO_NHWC: [i32; 4] = [0, 2, 3, 1];
+const TO_NCHW: [i32; 4] = [0, 3, 1, 2];
+
+pub struct ConvertLayout;
+
+impl Pass for ConvertLayout {
+    fn name(&self) -> &'static str {
+        "ConvertLayout"
+    }
+
+    fn run(&self, graph: &mut GraphDef, item: &Item) -> Result<bool, String> {
+        let converted = select(graph, item);
+        if converted.is_empty() {
+            return Ok(false);
+        }
+        let created = convert(graph, &converted);
+        cancel_transposes(graph, item);
+        remove_unused(graph, created);
+        Ok(true)
+    }
+}
+
+fn is_convertible(node: &NodeDef, item: &Item) -> bool {
+    device_type_of(&node.device) == Some(DEVICE_TYPE.trim_end_matches('\0'))
+        && !item.nodes_to_preserve.contains(&node.name)
+}
+
+fn is_nchw(node: &NodeDef) -> bool {
+    node.attr
+        .get("data_format")
+        .and_then(AttrValue::as_str)
+        .is_some_and(|format| format == "NCHW")
+}
+
+// Nodes to convert: NCHW ops of rank 4, then bias and elementwise ops whose
+// input 0 is output 0 of a converted node, until no more join
+fn select(graph: &GraphDef, item: &Item) -> HashSet<String> {
+    let mut converted: HashSet<String> = graph
+        .node
+        .iter()
+        .filter(|node| RANK_4_OPS.contains(&node.op.as_str()))
+        .filter(|node| is_nchw(node) && is_convertible(node, item))
+        .map(|node| node.name.clone())
+        .collect();
+    if converted.is_empty() {
+        return converted;
+    }
+
+    loop {
+        let joining: Vec<String> = graph
+            .node
+            .iter()
+            .filter(|node| !converted.contains(&node.name))
+            .filter(|node| match node.op.as_str() {
+                op if BIAS_OPS.contains(&op) => is_nchw(node),
+                op => AGNOSTIC_OPS.contains(&op),
+            })
+            .filter(|node| is_convertible(node, item))
+            .filter(|node| {
+                node.data_inputs()
+                    .next()
+                    .map(parse_input)
+                    .is_some_and(|(input, index)| index == 0 && converted.contains(input))
+            })
+            .map(|node| node.name.clone())
+            .collect();
+        if joining.is_empty() {
+            return converted;
+        }
+        converted.extend(joining);
+    }
+}
+
+// Gathers the values of an NCHW list attr into NHWC, in groups of `group` values
+fn permute_list(node: &mut NodeDef, name: &str, group: usize) {
+    if let Some(AttrValue {
+        value: Some(AttrValueValue::List(list)),
+    }) = node.attr.get_mut(name)
+    {
+        if list.i.len() == TO_NHWC.len() * group {
+            list.i = TO_NHWC
+                .iter()
+                .flat_map(|&dim| list.i.chunks(group).nth(dim as usize).unwrap().to_vec())
+                .collect();
+        }
+    }
+}
+
+// Whether `input` of a node reads output 0 of a converted node
+fn reads_converted<'a>(input: &'a str, converted: &HashSet<String>) -> Option<&'a str> {
+    match parse_input(input) {
+        (node, 0) if !input.starts_with('^') && converted.contains(node) => Some(node),
+        _ => None,
+    }
+}
+
+// Rewrites the converted nodes to NHWC and inserts the boundary transposes,
+// returns the names of the inserted nodes
+fn convert(graph: &mut GraphDef, converted: &HashSet<String>) -> HashSet<String> {
+    let mut names: HashSet<String> = graph.node.iter().map(|node| node.name.clone()).collect();
+    let mut created = HashSet::new();
+    let mut inserted = Vec::new();
+    let mut to_nchw = HashMap::new();
+
+    for node in &graph.node {
+        if !converted.contains(&node.name) {
+            continue;
+        }
+        // Output 0 read by a node which expects NCHW
+        let read_as_nchw = graph.node.iter().any(|consumer| {
+            consumer
+                .input
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| i > 0 || !converted.contains(&consumer.name))
+                .any(|(_, input)| reads_converted(input, converted) == Some(node.name.as_str()))
+        });
+        if read_as_nchw {
+            let transpose = transpose(&mut names, node, &node.name, "to_nchw", &TO_NCHW);
+            to_nchw.insert(node.name.clone(), transpose[0].name.clone());
+            inserted.extend(transpose);
+        }
+    }
+
+    for node in &mut graph.node {
+        let is_converted = converted.contains(&node.name);
+        for i in 0..node.input.len() {
+            if let Some(input) = reads_converted(&node.input[i], converted) {
+                if i > 0 || !is_converted {
+                    node.input[i] = to_nchw[input].clone();
+                }
+            } else if i == 0 && is_converted {
+                let input = node.input[0].clone();
+                let transpose = transpose(&mut names, node, &input, "to_nhwc", &TO_NHWC);
+                node.input[0] = transpose[0].name.clone();
+                inserted.extend(transpose);
+            }
+        }
+        if !is_converted {
+            continue;
+        }
+
+        if node.attr.contains_key("data_format") {
+            node.attr
+                .insert("data_format".to_owned(), AttrValue::string("NHWC"));
+        }
+        for name in ["strides", "ksize", "dilations"] {
+            permute_list(node, name, 1);
+        }
+        permute_list(node, "explicit_paddings", 2);
+    }
+
+    created.extend(inserted.iter().map(|node| node.name.clone()));
+    graph.node.extend(inserted);
+    created
+}
+
+// Transpose of `input` next to `node`, on the host, along with its permutation
+fn transpose(
+    names: &mut HashSet<String>,
+    node: &NodeDef,
+    input: &str,
+    suffix: &str,
+    perm: &[i32],
+) -> [NodeDef; 2] {
+    let name = unique_name(names, &format!("{}/{}", node.name, suffix));
+    let device = host_device(&node.device);
+
+    let mut perm_node = NodeDef {
+        name: unique_name(names, &format!("{}/perm", name)),
+        op: "Const".to_owned(),
+        device: device.clone(),
+        ..Default::default()
+    };
+    perm_node
+        .attr
+        .insert("dtype".to_owned(), AttrValue::data_type(TF_INT32));
+    perm_node.attr.insert(
+        "value".to_owned(),
+        AttrValue::tensor(TensorProto::int32s(perm)),
+    );
+
+    let mut transpose = NodeDef {
+        name,
+        op: "Transpose".to_owned(),
+        input: vec![input.to_owned(), perm_node.name.clone()],
+        device,
+        ..Default::default()
+    };
+    transpose.attr.insert(
+        "T".to_owned(),
+        node.attr
+            .get("T")
+            .cloned()
+            .unwrap_or_else(|| AttrValue::data_type(TF_FLOAT)),
+    );
+    transpose
+        .attr
+        .insert("Tperm".to_owned(), AttrValue::data_type(TF_INT32));
+    [transpose, perm_node]
+}
+
+// Permutation of a Transpose whose perm is a constant
+fn permutation(nodes: &HashMap<&str, &NodeDef>, transpose: &NodeDef) -> Option<Vec<i64>> {
+    let (perm, _) = parse_input(transpose.input.get(1)?);
+    let perm = nodes.get(perm)?;
+    if perm.op != "Const" {
+        return None;
+    }
+    perm.attr.get("value")?.as_tensor()?.ints()
+}
+
+// Consumers of a Transpose which is undone by the Transpose it reads from are
+// rewired to the input of the first one, until no such pair is left
+fn cancel_transposes(graph: &mut GraphDef, item: &Item) {
+    loop {
+        let bypassed: HashMap<String, String> = {
+            let nodes: HashMap<&str, &NodeDef> = graph
+                .node
+                .iter()
+                .map(|node| (node.name.as_str(), node))
+                .collect();
+            graph
+                .node
+                .iter()
+                .filter(|node| node.op == "Transpose" && node.control_inputs().next().is_none())
+                .filter(|node| !item.nodes_to_preserve.contains(&node.name))
+                .filter_map(|second| {
+                    let first = match parse_input(second.data_inputs().next()?) {
+                        (first, 0) => nodes.get(first)?,
+                        _ => return None,
+                    };
+                    if first.op != "Transpose" {
+                        return None;
+                    }
+                    let outer = permutation(&nodes, first)?;
+                    let inner = permutation(&nodes, second)?;
+                    let cancels = outer.len() == inner.len()
+                        && inner.iter().enumerate().all(|(i, &dim)| {
+                            usize::try_from(dim)
+                                .ok()
+                                .and_then(|dim| outer.get(dim))
+                                .is_some_and(|&dim| dim == i as i64)
+                        });
+                    cancels.then(|| (second.name.clone(), first.input[0].clone()))
+                })
+                .collect()
+        };
+        if bypassed.is_empty() {
+            return;
+        }
+
+        let mut changed = false;
+        for node in &mut graph.node {
+            for input in node
+                .input
+                .iter_mut()
+                .filter(|input| !input.starts_with('^'))
+            {
+                if let (transpose, 0) = parse_input(input) {
+                    if let Some(bypass) = bypassed.get(transpose) {
+                        *input = bypass.clone();
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if !changed {
+            return;
+        }
+    }
+}
+
+// Removes inserted nodes nothing reads from, like transposes which were bypassed
+// and then their permutations
+fn remove_unused(graph: &mut GraphDef, mut created: HashSet<String>) {
+    loop {
+        let used: HashSet<&str> = graph
+            .node
+            .iter()
+            .flat_map(|node| node.input.iter().map(|input| parse_input(input).0))
+            .collect();
+        let unused: HashSet<String> = created
+            .iter()
+            .filter(|name| !used.contains(name.as_str()))
+            .cloned()
+            .collect();
+        if unused.is_empty() {
+            return;
+        }
+        graph.node.retain(|node| !unused.contains(&node.name));
+        created.retain(|name| !unused.contains(name));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConvertLayout;
+    use crate::{
+        bindings::graph::{AttrValue, AttrValueValue, GraphDef, ListValue, NodeDef, TensorProto},
+        optimizer::{
+            rewrite::tests::{graph, node, ops},
+            Item, Pass,
+        },
+    };
+
+    fn nchw(name: &str, op: &str, inputs: &[&str]) -> NodeDef {
+        let mut node = node(name, op, inputs);
+        node.attr
+            .insert("data_format".to_owned(), AttrValue::string("NCHW"));
+        node
+    }
+
+    fn transpose(name: &str, input: &str, perm: &[i32]) -> [NodeDef; 2] {
+        let mut perm_node = node(&format!("{}/perm", name), "Const", &[]);
+        perm_node.attr.insert(
+            "value".to_owned(),
+            AttrValue::tensor(TensorProto::int32s(perm)),
+        );
+        let transpose = node(name, "Transpose", &[input, &perm_node.name]);
+        [transpose, perm_node]
+    }
+
+    fn find<'a>(graph: &'a GraphDef, name: &str) -> &'a NodeDef {
+        graph.node.iter().find(|node| node.name == name).unwrap()
+    }
+
+    #[test]
+    fn layout_converts_region() {
+        let mut conv = nchw("conv", "Conv2D", &["x", "filter"]);
+        conv.attr.insert(
+            "strides".to_owned(),
+            AttrValue {
+                value: Some(AttrValueValue::List(ListValue {
+                    i: vec![1, 1, 2, 3],
+                    ..Default::default()
+                })),
+            },
+        );
+        let mut graph = graph(vec![
+            node("x", "Placeholder", &[]),
+            node("filter", "Const", &[]),
+            conv,
+            nchw("bias_add", "BiasAdd", &["conv", "bias"]),
+            node("relu", "Relu", &["bias_add"]),
+            node("output", "Identity", &["relu"]),
+        ]);
+        let item = Item {
+            nodes_to_preserve: ["output".to_owned()].into(),
+            ..Default::default()
+        };
+
+        assert_eq!(ConvertLayout.run(&mut graph, &item), Ok(true));
+        assert_eq!(find(&graph, "conv").input, ["conv/to_nhwc", "filter"]);
+        assert_eq!(find(&graph, "bias_add").input, ["conv", "bias"]);
+        assert_eq!(find(&graph, "relu").input, ["bias_add"]);
+        assert_eq!(find(&graph, "output").input, ["relu/to_nchw"]);
+        assert_eq!(
+            find(&graph, "conv/to_nhwc").input,
+            ["x", "conv/to_nhwc/perm"]
+        );
+        assert_eq!(
+            find(&graph, "relu/to_nchw").input,
+            ["relu", "relu/to_nchw/perm"]
+        );
+        assert_eq!(
+            find(&graph, "relu/to_nchw").device,
+            "/job:localhost/replica:0/task:0/device:CPU:0"
+        );
+
+        let conv = find(&graph, "conv");
+        assert_eq!(conv.attr["data_format"].as_str(), Some("NHWC"));
+        match &conv.attr["strides"].value {
+            Some(AttrValueValue::List(list)) => assert_eq!(list.i, [1, 2, 3, 1]),
+            value => panic!("{:?}", value),
+        }
+        assert_eq!(
+            find(&graph, "bias_add").attr["data_format"].as_str(),
+            Some("NHWC")
+        );
+
+        assert_eq!(ConvertLayout.run(&mut graph, &item), Ok(false));
+    }
+
+    #[test]
+    fn layout_cancels_inverse_transposes() {
+        let [to_nchw, to_nchw_perm] = transpose("x/to_nchw", "x", &[0, 3, 1, 2]);
+        let mut graph = graph(vec![
+            node("x", "Placeholder", &[]),
+            to_nchw_perm,
+            to_nchw,
+            nchw("pool", "MaxPool", &["x/to_nchw"]),
+            node("output", "Identity", &["pool"]),
+        ]);
+        let item = Item {
+            nodes_to_preserve: ["output".to_owned()].into(),
+            ..Default::default()
+        };
+
+        assert_eq!(ConvertLayout.run(&mut graph, &item), Ok(true));
+        assert_eq!(find(&graph, "pool").input, ["x"]);
+        assert_eq!(find(&graph, "output").input, ["pool/to_nchw"]);
+        assert_eq!(
+            ops(&graph),
+            [
+                ("x", "Placeholder"),
+                ("x/to_nchw/perm", "Const"),
+                ("x/to_nchw", "Transpose"),
+                ("pool", "MaxPool"),
+                ("output", "Identity"),
+                ("pool/to_nchw", "Transpose"),
+                ("pool/to_nchw/perm", "Const"),
+            ]
+        );
+    }
+
+    #[test]
+    fn layout_leaves_bias_add_alone() {
+        let mut graph = graph(vec![
+            node("x", "Placeholder", &[]),
+            nchw("bias_add", "BiasAdd", &["x", "bias"]),
+        ]);
+        assert_eq!(ConvertLayout.run(&mut graph, &Item::default()), Ok(false));
+    }
+}
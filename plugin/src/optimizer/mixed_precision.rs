@@ -0,0 +1,315 @@
+// Converts float32 subgraphs on the device to bfloat16 or float16. Allowed ops
+// are converted, inferred ops join them when they're next to converted ones, and
+// denied ops keep the inferred ops which feed them in float32. Only ops with a
+// half precision kernel on the device are converted, and Cast nodes are
+// inserted where converted nodes meet the rest of the graph. Listed ops are
+// expected to have all their inputs and outputs of type T
+use std::collections::{HashMap, HashSet};
+
+use super::{
+    rewrite::{device_type_of, host_device, unique_name},
+    Item, Pass,
+};
+use crate::{
+    bindings::{
+        graph::{parse_input, AttrValue, AttrValueValue, GraphDef, NodeDef},
+        kernels::registered_kernels,
+        raw::{TF_DataType, TF_BFLOAT16, TF_FLOAT, TF_HALF},
+    },
+    config::{MixedPrecision, MixedPrecisionConfig},
+    DEVICE_TYPE,
+};
+
+pub struct AutoMixedPrecision {
+    dtype: TF_DataType,
+    allow: HashSet<String>,
+    infer: HashSet<String>,
+    deny: HashSet<String>,
+    // Ops with a kernel on the device for `dtype`
+    kernels: HashSet<String>,
+}
+
+impl AutoMixedPrecision {
+    /// None when mixed precision is off
+    pub fn new(config: &MixedPrecisionConfig) -> Option<Self> {
+        let dtype = match config.precision {
+            MixedPrecision::Off => return None,
+            MixedPrecision::Bfloat16 => TF_BFLOAT16,
+            MixedPrecision::Float16 => TF_HALF,
+        };
+        let device_type = DEVICE_TYPE.trim_end_matches('\0');
+        let kernels = registered_kernels()
+            .into_iter()
+            .filter(|kernel| {
+                kernel.device_type == device_type && kernel.constraints.get("T") == Some(&dtype)
+            })
+            .map(|kernel| kernel.op.to_owned())
+            .collect();
+        Some(Self::with_kernels(config, dtype, kernels))
+    }
+
+    fn with_kernels(
+        config: &MixedPrecisionConfig,
+        dtype: TF_DataType,
+        kernels: HashSet<String>,
+    ) -> Self {
+        let set = |ops: &[String]| ops.iter().cloned().collect();
+        Self {
+            dtype,
+            allow: set(&config.allow),
+            infer: set(&config.infer),
+            deny: set(&config.deny),
+            kernels,
+        }
+    }
+
+    fn is_convertible(&self, node: &NodeDef, item: &Item) -> bool {
+        device_type_of(&node.device) == Some(DEVICE_TYPE.trim_end_matches('\0'))
+            && !item.nodes_to_preserve.contains(&node.name)
+            && node.attr.get("T").and_then(AttrValue::as_data_type) == Some(TF_FLOAT)
+            && self.kernels.contains(&node.op)
+    }
+
+    // Names of the nodes to convert
+    fn paint(&self, graph: &GraphDef, item: &Item) -> HashSet<String> {
+        let mut consumers: HashMap<&str, Vec<&str>> = HashMap::new();
+        for node in &graph.node {
+            for input in node.data_inputs() {
+                consumers
+                    .entry(parse_input(input).0)
+                    .or_default()
+                    .push(&node.name);
+            }
+        }
+        let inferred: Vec<&NodeDef> = graph
+            .node
+            .iter()
+            .filter(|node| self.infer.contains(&node.op))
+            .collect();
+
+        // Denial spreads upwards through inferred ops
+        let mut denied: HashSet<&str> = graph
+            .node
+            .iter()
+            .filter(|node| self.deny.contains(&node.op))
+            .map(|node| node.name.as_str())
+            .collect();
+        loop {
+            let joining: Vec<&str> = inferred
+                .iter()
+                .filter(|node| !denied.contains(node.name.as_str()))
+                .filter(|node| {
+                    consumers
+                        .get(node.name.as_str())
+                        .is_some_and(|consumers| consumers.iter().any(|c| denied.contains(c)))
+                })
+                .map(|node| node.name.as_str())
+                .collect();
+            if joining.is_empty() {
+                break;
+            }
+            denied.extend(joining);
+        }
+
+        let mut painted: HashSet<&str> = graph
+            .node
+            .iter()
+            .filter(|node| self.allow.contains(&node.op) && self.is_convertible(node, item))
+            .map(|node| node.name.as_str())
+            .collect();
+        loop {
+            let joining: Vec<&str> = inferred
+                .iter()
+                .filter(|node| !painted.contains(node.name.as_str()))
+                .filter(|node| !denied.contains(node.name.as_str()))
+                .filter(|node| self.is_convertible(node, item))
+                .filter(|node| {
+                    node.data_inputs()
+                        .any(|input| painted.contains(parse_input(input).0))
+                        || consumers
+                            .get(node.name.as_str())
+                            .is_some_and(|consumers| consumers.iter().any(|c| painted.contains(c)))
+                })
+                .map(|node| node.name.as_str())
+                .collect();
+            if joining.is_empty() {
+                break;
+            }
+            painted.extend(joining);
+        }
+        painted.into_iter().map(str::to_owned).collect()
+    }
+
+    fn convert(&self, graph: &mut GraphDef, painted: &HashSet<String>) {
+        let mut names: HashSet<String> = graph.node.iter().map(|node| node.name.clone()).collect();
+        // Cast of each tensor crossing the boundary, by tensor and direction
+        let mut casts: HashMap<(String, i32, TF_DataType), String> = HashMap::new();
+        let mut inserted = Vec::new();
+
+        for node in &mut graph.node {
+            let is_painted = painted.contains(&node.name);
+            for input in node
+                .input
+                .iter_mut()
+                .filter(|input| !input.starts_with('^'))
+            {
+                let (source, index) = parse_input(input);
+                let (src, dst) = match (is_painted, painted.contains(source)) {
+                    (true, false) => (TF_FLOAT, self.dtype),
+                    (false, true) => (self.dtype, TF_FLOAT),
+                    _ => continue,
+                };
+                let key = (source.to_owned(), index, dst);
+                if let Some(cast) = casts.get(&key) {
+                    *input = cast.clone();
+                    continue;
+                }
+
+                let tensor = match index {
+                    0 => source.to_owned(),
+                    index => format!("{}_{}", source, index),
+                };
+                let suffix = match dst {
+                    TF_FLOAT => "to_float",
+                    TF_BFLOAT16 => "to_bfloat16",
+                    _ => "to_half",
+                };
+                let mut cast = NodeDef {
+                    name: unique_name(&mut names, &format!("{}/{}", tensor, suffix)),
+                    op: "Cast".to_owned(),
+                    input: vec![input.clone()],
+                    device: host_device(&node.device),
+                    ..Default::default()
+                };
+                cast.attr
+                    .insert("SrcT".to_owned(), AttrValue::data_type(src));
+                cast.attr
+                    .insert("DstT".to_owned(), AttrValue::data_type(dst));
+                cast.attr.insert(
+                    "Truncate".to_owned(),
+                    AttrValue {
+                        value: Some(AttrValueValue::B(false)),
+                    },
+                );
+                *input = cast.name.clone();
+                casts.insert(key, cast.name.clone());
+                inserted.push(cast);
+            }
+            if is_painted {
+                node.attr
+                    .insert("T".to_owned(), AttrValue::data_type(self.dtype));
+            }
+        }
+        graph.node.extend(inserted);
+    }
+}
+
+impl Pass for AutoMixedPrecision {
+    fn name(&self) -> &'static str {
+        "AutoMixedPrecision"
+    }
+
+    fn run(&self, graph: &mut GraphDef, item: &Item) -> Result<bool, String> {
+        let painted = self.paint(graph, item);
+        if painted.is_empty() {
+            return Ok(false);
+        }
+        self.convert(graph, &painted);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AutoMixedPrecision;
+    use crate::{
+        bindings::{
+            graph::GraphDef,
+            raw::{TF_BFLOAT16, TF_FLOAT},
+        },
+        config::{MixedPrecision, MixedPrecisionConfig},
+        optimizer::{
+            rewrite::tests::{graph, node},
+            Item, Pass,
+        },
+    };
+
+    fn pass(kernels: &[&str]) -> AutoMixedPrecision {
+        let list = |ops: &[&str]| ops.iter().map(|&op| op.to_owned()).collect();
+        let config = MixedPrecisionConfig {
+            precision: MixedPrecision::Bfloat16,
+            allow: list(&["MatMul"]),
+            infer: list(&["Relu", "Identity"]),
+            deny: list(&["Softmax"]),
+        };
+        AutoMixedPrecision::with_kernels(
+            &config,
+            TF_BFLOAT16,
+            kernels.iter().map(|&op| op.to_owned()).collect(),
+        )
+    }
+
+    fn dtype(graph: &GraphDef, name: &str) -> Option<i32> {
+        let node = graph.node.iter().find(|node| node.name == name).unwrap();
+        node.attr
+            .get("T")
+            .and_then(|attr| attr.as_data_type())
+            .map(|dtype| dtype as i32)
+    }
+
+    fn inputs<'a>(graph: &'a GraphDef, name: &str) -> &'a [String] {
+        &graph
+            .node
+            .iter()
+            .find(|node| node.name == name)
+            .unwrap()
+            .input
+    }
+
+    #[test]
+    fn mixed_precision_converts_subgraph() {
+        let mut graph = graph(vec![
+            node("x", "Placeholder", &[]),
+            node("w", "Const", &[]),
+            node("matmul", "MatMul", &["x", "w"]),
+            node("relu", "Relu", &["matmul"]),
+            node("relu_2", "Relu", &["matmul"]),
+            node("softmax", "Softmax", &["relu_2"]),
+            node("output", "Identity", &["relu", "^softmax"]),
+        ]);
+        let item = Item {
+            nodes_to_preserve: ["output".to_owned()].into(),
+            ..Default::default()
+        };
+
+        let pass = pass(&["MatMul", "Relu", "Identity"]);
+        assert_eq!(pass.run(&mut graph, &item), Ok(true));
+        assert_eq!(dtype(&graph, "matmul"), Some(TF_BFLOAT16 as i32));
+        assert_eq!(dtype(&graph, "relu"), Some(TF_BFLOAT16 as i32));
+        // Feeds the denied softmax
+        assert_eq!(dtype(&graph, "relu_2"), Some(TF_FLOAT as i32));
+        assert_eq!(dtype(&graph, "output"), Some(TF_FLOAT as i32));
+
+        assert_eq!(inputs(&graph, "matmul"), ["x/to_bfloat16", "w/to_bfloat16"]);
+        assert_eq!(inputs(&graph, "relu_2"), ["matmul/to_float"]);
+        assert_eq!(inputs(&graph, "output"), ["relu/to_float", "^softmax"]);
+        assert_eq!(inputs(&graph, "relu/to_float"), ["relu"]);
+        assert_eq!(
+            graph.node.iter().filter(|node| node.op == "Cast").count(),
+            4
+        );
+
+        assert_eq!(pass.run(&mut graph, &item), Ok(false));
+    }
+
+    #[test]
+    fn mixed_precision_needs_kernels() {
+        let mut graph = graph(vec![
+            node("x", "Placeholder", &[]),
+            node("w", "Const", &[]),
+            node("matmul", "MatMul", &["x", "w"]),
+        ]);
+        assert_eq!(pass(&["Relu"]).run(&mut graph, &Item::default()), Ok(false));
+        assert_eq!(dtype(&graph, "matmul"), Some(TF_FLOAT as i32));
+    }
+}
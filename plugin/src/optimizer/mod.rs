@@ -0,0 +1,468 @@
+// Graph optimizer plugin: grappler hands every graph with nodes on the device
+// to the plugin as a serialized GraphDef, which is decoded, rewritten by the
+// passes below and encoded into a buffer the plugin owns
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::c_void,
+    path::Path,
+    ptr::slice_from_raw_parts_mut,
+};
+
+use prost::Message;
+
+use self::{
+    dump::Dump, fuse_bias_activation::FuseBiasActivation, layout::ConvertLayout,
+    mixed_precision::AutoMixedPrecision,
+};
+use crate::{
+    bindings::{
+        graph::{parse_input, GraphDef},
+        grappler::GraphProperties,
+        raw::*,
+    },
+    config::{self, GrapplerConfig, Toggle},
+    DEVICE_TYPE, EMPTY_CSTR,
+};
+
+mod dump;
+mod fuse_bias_activation;
+mod layout;
+mod mixed_precision;
+mod rewrite;
+
+/// What grappler tells about the graph besides the graph itself
+#[derive(Debug, Default)]
+pub struct Item {
+    /// Fetched, fed and signature nodes, which have to keep their name and outputs
+    pub nodes_to_preserve: HashSet<String>,
+    /// Statically inferred ranks of the node outputs, None where unknown.
+    /// Nodes created by passes have none
+    pub output_ranks: HashMap<String, Vec<Option<usize>>>,
+}
+
+impl Item {
+    unsafe fn new(
+        item: *const TF_GrapplerItem,
+        graph: &GraphDef,
+    ) -> Result<Self, (TF_Code, String)> {
+        let nodes_to_preserve = item
+            .nodes_to_preserve()
+            .map_err(|status| (status.code(), status.message()))?;
+        Ok(Self {
+            nodes_to_preserve: nodes_to_preserve.into_iter().collect(),
+            output_ranks: output_ranks(item, graph),
+        })
+    }
+
+    /// Rank of a tensor named like a node input, if it was inferred
+    pub fn output_rank(&self, tensor: &str) -> Option<usize> {
+        let (node, index) = parse_input(tensor);
+        *self
+            .output_ranks
+            .get(node)?
+            .get(usize::try_from(index).ok()?)?
+    }
+}
+
+// Passes do without ranks, so failed inference only costs rewrites
+unsafe fn output_ranks(
+    item: *const TF_GrapplerItem,
+    graph: &GraphDef,
+) -> HashMap<String, Vec<Option<usize>>> {
+    let mut properties = GraphProperties::new(item);
+    if let Err(status) = properties.infer_statically(false, false, false) {
+        log::debug!("Shape inference failed: {}", status.message());
+        return HashMap::new();
+    }
+    graph
+        .node
+        .iter()
+        .filter_map(|node| {
+            let outputs = properties.output_properties(&node.name).ok()?;
+            let ranks = outputs
+                .iter()
+                .map(|output| output.dims().map(|dims| dims.len()))
+                .collect();
+            Some((node.name.clone(), ranks))
+        })
+        .collect()
+}
+
+/// Rewrite of a graph, run by the optimizer in order with the other passes
+pub trait Pass: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Rewrites the graph in place, returns whether anything changed
+    fn run(&self, graph: &mut GraphDef, item: &Item) -> Result<bool, String>;
+}
+
+/// Created by TF for each grappler run, holds the pass pipeline
+pub struct Optimizer {
+    passes: Vec<Box<dyn Pass>>,
+    dump_dir: Option<&'static Path>,
+}
+
+impl Default for Optimizer {
+    fn default() -> Self {
+        let config = config::get();
+        if !config.optimizer {
+            return Self {
+                passes: Vec::new(),
+                dump_dir: None,
+            };
+        }
+        // Layouts are converted first, so that fusion sees NHWC ops, and
+        // precision last, since fusion only matches float ops
+        let mut passes: Vec<Box<dyn Pass>> =
+            vec![Box::new(ConvertLayout), Box::new(FuseBiasActivation::new())];
+        if let Some(pass) = AutoMixedPrecision::new(&config.mixed_precision) {
+            passes.push(Box::new(pass));
+        }
+        Self {
+            passes,
+            dump_dir: config.optimizer_dump_dir.as_deref(),
+        }
+    }
+}
+
+impl Optimizer {
+    pub fn optimize(&self, graph: &mut GraphDef, item: &Item) -> Result<(), String> {
+        for pass in &self.passes {
+            let changed = pass
+                .run(graph, item)
+                .map_err(|message| format!("{} failed: {}", pass.name(), message))?;
+            if changed {
+                log::debug!("{} rewrote the graph", pass.name());
+            }
+        }
+        Ok(())
+    }
+
+    // The item is built from the decoded graph
+    fn optimize_serialized(
+        &self,
+        input: &[u8],
+        item: impl FnOnce(&GraphDef) -> Result<Item, (TF_Code, String)>,
+    ) -> Result<Vec<u8>, (TF_Code, String)> {
+        let mut graph = GraphDef::decode(input).map_err(|error| {
+            (
+                TF_INVALID_ARGUMENT,
+                format!("Failed to decode GraphDef: {}", error),
+            )
+        })?;
+        log::trace!("Optimizing graph of {} nodes", graph.node.len());
+        let item = item(&graph)?;
+        let dump = self.dump_dir.map(|dir| Dump::new(dir, &graph));
+        self.optimize(&mut graph, &item)
+            .map_err(|message| (TF_INTERNAL, message))?;
+        if let Some(dump) = dump {
+            dump.finish(&graph);
+        }
+        Ok(graph.encode_to_vec())
+    }
+}
+
+#[no_mangle]
+unsafe extern "C" fn TF_InitGraph(
+    params: *mut TP_OptimizerRegistrationParams,
+    status: *mut TF_Status,
+) {
+    crate::init();
+    configure(params);
+    // Set here rather than in configure, which the tests call without TF
+    let optimizer = (*params).optimizer;
+    (*optimizer).struct_size = std::mem::size_of::<TP_Optimizer>() as u64;
+    (*optimizer).create_func = Some(plugin_create_func);
+    (*optimizer).optimize_func = Some(plugin_optimize_func);
+    (*optimizer).destroy_func = Some(plugin_destroy_func);
+    TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
+}
+
+unsafe fn configure(params: *mut TP_OptimizerRegistrationParams) {
+    (*params).struct_size = std::mem::size_of::<TP_OptimizerRegistrationParams>() as u64;
+    (*params).device_type = DEVICE_TYPE.as_ptr() as *const i8;
+
+    let grappler = &config::get().grappler;
+    let configs = (*params).optimizer_configs;
+    (*configs).struct_size = std::mem::size_of::<TP_OptimizerConfigs>() as u64;
+    (*configs).disable_model_pruning = grappler.disable_model_pruning as TF_Bool;
+    (*configs).implementation_selector = tri_state(grappler.implementation_selector);
+    (*configs).function_optimization = tri_state(grappler.function_optimization);
+    (*configs).common_subgraph_elimination = tri_state(grappler.common_subgraph_elimination);
+    (*configs).arithmetic_optimization = tri_state(grappler.arithmetic_optimization);
+    (*configs).debug_stripper = tri_state(grappler.debug_stripper);
+    (*configs).constant_folding = tri_state(grappler.constant_folding);
+    (*configs).shape_optimization = tri_state(grappler.shape_optimization);
+    (*configs).auto_mixed_precision = tri_state(grappler.auto_mixed_precision);
+    (*configs).auto_mixed_precision_onednn_bfloat16 =
+        tri_state(grappler.auto_mixed_precision_onednn_bfloat16);
+    (*configs).auto_mixed_precision_mkl = tri_state(grappler.auto_mixed_precision_mkl);
+    (*configs).pin_to_host_optimization = tri_state(grappler.pin_to_host_optimization);
+    (*configs).layout_optimizer = tri_state(grappler.layout_optimizer);
+    (*configs).remapping = tri_state(grappler.remapping);
+    (*configs).loop_optimization = tri_state(grappler.loop_optimization);
+    (*configs).dependency_optimization = tri_state(grappler.dependency_optimization);
+    (*configs).auto_parallel = tri_state(grappler.auto_parallel);
+    (*configs).memory_optimization = tri_state(grappler.memory_optimization);
+    (*configs).scoped_allocator_optimization = tri_state(grappler.scoped_allocator_optimization);
+    log_grappler_config(grappler);
+}
+
+fn tri_state(toggle: Toggle) -> TF_TriState {
+    match toggle {
+        Toggle::Default => TF_TriState_Default,
+        Toggle::Off => TF_TriState_Off,
+        Toggle::On => TF_TriState_On,
+    }
+}
+
+fn log_grappler_config(grappler: &GrapplerConfig) {
+    let toggles: Vec<_> = grappler
+        .toggles()
+        .iter()
+        .map(|(name, toggle)| format!("{}={}", name, toggle))
+        .collect();
+    log::info!(
+        "Grappler optimizers for {}: disable_model_pruning={}, {}",
+        DEVICE_TYPE.trim_end_matches('\0'),
+        grappler.disable_model_pruning,
+        toggles.join(", ")
+    );
+    log::info!(
+        "Plugin optimizer passes {}",
+        match config::get().optimizer {
+            true => "enabled",
+            false => "disabled",
+        }
+    );
+}
+
+extern "C" fn plugin_create_func() -> *mut c_void {
+    Box::into_raw(Box::<Optimizer>::default()) as *mut c_void
+}
+
+unsafe extern "C" fn plugin_destroy_func(optimizer: *mut c_void) {
+    drop(Box::from_raw(optimizer as *mut Optimizer));
+}
+
+unsafe extern "C" fn plugin_optimize_func(
+    optimizer: *mut c_void,
+    graph: *const TF_Buffer,
+    item: *const TF_GrapplerItem,
+    optimized_graph: *mut TF_Buffer,
+    status: *mut TF_Status,
+) {
+    let item = |graph: &GraphDef| Item::new(item, graph);
+    match optimize_buffer(optimizer, graph, item, optimized_graph) {
+        Ok(()) => TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8),
+        Err((code, message)) => {
+            log::warn!("Graph optimization failed: {}", message);
+            status.set(code, &message)
+        }
+    }
+}
+
+/// Leaves `optimized_graph` untouched on error, in which case TF keeps
+/// the graph as it was
+unsafe fn optimize_buffer(
+    optimizer: *mut c_void,
+    graph: *const TF_Buffer,
+    item: impl FnOnce(&GraphDef) -> Result<Item, (TF_Code, String)>,
+    optimized_graph: *mut TF_Buffer,
+) -> Result<(), (TF_Code, String)> {
+    let optimizer = &*(optimizer as *const Optimizer);
+    let input = match (*graph).data.is_null() {
+        true => &[][..],
+        false => std::slice::from_raw_parts((*graph).data as *const u8, (*graph).length as usize),
+    };
+
+    let output = optimizer.optimize_serialized(input, item)?;
+    set_buffer(optimized_graph, output);
+    Ok(())
+}
+
+// The output buffer is created and deleted by TF, but the data is the
+// plugin's, so it has to come with a matching deallocator. Sharing the
+// input's data instead gets it freed twice
+unsafe fn set_buffer(buffer: *mut TF_Buffer, data: Vec<u8>) {
+    if let Some(deallocator) = (*buffer).data_deallocator {
+        deallocator((*buffer).data as *mut c_void, (*buffer).length);
+    }
+
+    let data = data.into_boxed_slice();
+    (*buffer).length = data.len() as u64;
+    (*buffer).data = Box::into_raw(data) as *const c_void;
+    (*buffer).data_deallocator = Some(free_buffer);
+}
+
+unsafe extern "C" fn free_buffer(data: *mut c_void, length: u64) {
+    drop(Box::from_raw(slice_from_raw_parts_mut(
+        data as *mut u8,
+        length as usize,
+    )));
+}
+
+#[cfg(test)]
+mod tests {
+    // Nothing here calls into TF, so the tests run without it
+    use super::{
+        configure, optimize_buffer, plugin_create_func, plugin_destroy_func, Item, Optimizer, Pass,
+    };
+    use crate::bindings::{
+        graph::{AttrValue, GraphDef, NodeDef},
+        raw::{
+            TF_Buffer, TF_Code, TF_TriState_Default, TF_TriState_Off, TP_OptimizerConfigs,
+            TP_OptimizerRegistrationParams, TF_FLOAT, TF_INVALID_ARGUMENT,
+        },
+    };
+    use prost::Message;
+    use std::{ffi::c_void, ptr::null};
+
+    struct ClearDevices;
+
+    impl Pass for ClearDevices {
+        fn name(&self) -> &'static str {
+            "ClearDevices"
+        }
+
+        fn run(&self, graph: &mut GraphDef, _item: &Item) -> Result<bool, String> {
+            let mut changed = false;
+            for node in &mut graph.node {
+                changed |= !node.device.is_empty();
+                node.device.clear();
+            }
+            Ok(changed)
+        }
+    }
+
+    fn node(name: &str, op: &str, inputs: &[&str]) -> NodeDef {
+        let mut node = NodeDef {
+            name: name.to_owned(),
+            op: op.to_owned(),
+            input: inputs.iter().map(|&input| input.to_owned()).collect(),
+            device: "/device:MY_DEVICE:0".to_owned(),
+            ..Default::default()
+        };
+        node.attr
+            .insert("T".to_owned(), AttrValue::data_type(TF_FLOAT));
+        node
+    }
+
+    fn graph() -> GraphDef {
+        GraphDef {
+            node: vec![
+                node("x", "Placeholder", &[]),
+                node("identity", "Identity", &["x"]),
+                node("relu", "Relu", &["x", "^identity"]),
+            ],
+            library: Some(vec![]),
+            ..Default::default()
+        }
+    }
+
+    // Runs the optimizer as TF does, with a fresh output buffer
+    unsafe fn optimize(optimizer: *mut c_void, input: &[u8]) -> Result<GraphDef, TF_Code> {
+        let graph = TF_Buffer {
+            data: input.as_ptr() as *const c_void,
+            length: input.len() as u64,
+            data_deallocator: None,
+        };
+        let mut optimized = TF_Buffer {
+            data: null(),
+            length: 0,
+            data_deallocator: None,
+        };
+        match optimize_buffer(optimizer, &graph, |_| Ok(Item::default()), &mut optimized) {
+            Ok(()) => {
+                let data =
+                    std::slice::from_raw_parts(optimized.data as *const u8, optimized.length as _);
+                let graph = GraphDef::decode(data).unwrap();
+                optimized.data_deallocator.unwrap()(
+                    optimized.data as *mut c_void,
+                    optimized.length,
+                );
+                Ok(graph)
+            }
+            Err((code, _)) => {
+                assert!(optimized.data.is_null());
+                Err(code)
+            }
+        }
+    }
+
+    #[test]
+    fn optimizer_round_trip() {
+        unsafe {
+            let optimizer = plugin_create_func();
+            let graph = graph();
+            assert_eq!(optimize(optimizer, &graph.encode_to_vec()), Ok(graph));
+            assert_eq!(optimize(optimizer, &[]), Ok(GraphDef::default()));
+            plugin_destroy_func(optimizer);
+        }
+    }
+
+    #[test]
+    fn optimizer_runs_passes() {
+        let mut optimizer = Optimizer {
+            passes: vec![Box::new(ClearDevices)],
+            dump_dir: None,
+        };
+        let optimizer = &mut optimizer as *mut Optimizer as *mut c_void;
+        let optimized = unsafe { optimize(optimizer, &graph().encode_to_vec()) }.unwrap();
+        assert_eq!(optimized.node.len(), 3);
+        assert!(optimized.node.iter().all(|node| node.device.is_empty()));
+    }
+
+    #[test]
+    fn optimizer_dumps_graphs() {
+        let dir = std::env::temp_dir().join(format!("tfp-dump-{}", std::process::id()));
+        let mut optimizer = Optimizer {
+            passes: vec![Box::new(ClearDevices)],
+            dump_dir: Some(Box::leak(dir.clone().into_boxed_path())),
+        };
+        let optimizer = &mut optimizer as *mut Optimizer as *mut c_void;
+        unsafe { optimize(optimizer, &graph().encode_to_vec()) }.unwrap();
+
+        let mut files: Vec<_> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        files.sort();
+        assert_eq!(files.len(), 4);
+        assert!(files[0].ends_with("_input.pb"));
+        assert!(files[1].ends_with("_input.pbtxt"));
+        assert!(files[2].ends_with("_output.pb"));
+        assert!(files[3].ends_with("_output.pbtxt"));
+        let output = std::fs::read_to_string(dir.join(&files[3])).unwrap();
+        assert!(output.contains("op: \"Relu\"") && !output.contains("device:"));
+
+        // Encoded dumps keep what the text leaves out, like the library
+        let input = std::fs::read(dir.join(&files[0])).unwrap();
+        assert_eq!(GraphDef::decode(&input[..]).unwrap(), graph());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn optimizer_rejects_invalid_graph() {
+        unsafe {
+            let optimizer = plugin_create_func();
+            assert_eq!(optimize(optimizer, &[0xff]), Err(TF_INVALID_ARGUMENT));
+            plugin_destroy_func(optimizer);
+        }
+    }
+
+    #[test]
+    fn optimizer_registers_configs() {
+        unsafe {
+            let mut configs: TP_OptimizerConfigs = std::mem::zeroed();
+            let mut params: TP_OptimizerRegistrationParams = std::mem::zeroed();
+            params.optimizer_configs = &mut configs;
+
+            configure(&mut params);
+
+            assert_eq!(configs.remapping, TF_TriState_Off);
+            assert_eq!(configs.layout_optimizer, TF_TriState_Off);
+            assert_eq!(configs.constant_folding, TF_TriState_Default);
+        }
+    }
+}
@@ -0,0 +1,448 @@
+// Pattern-matching rewrites: a pattern describes a subgraph by op types and
+// how its nodes feed each other, rooted at the node whose outputs the rest of
+// the graph consumes. Every match is handed to the rewrite, whose replacement
+// takes over the name of the root, so consumers stay wired as they were
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use super::Item;
+use crate::bindings::{
+    graph::{parse_input, GraphDef, NodeDef},
+    raw::TF_DataType,
+};
+
+/// What feeds a data input of a pattern node
+pub enum Input {
+    /// Any tensor, available to the replacement under the label
+    Any(&'static str),
+    /// A node which has to match the pattern itself
+    Node(Pattern),
+}
+
+/// Node of a pattern, with the patterns of its data inputs in order.
+/// Inputs beyond those listed may be anything
+pub struct Pattern {
+    label: &'static str,
+    ops: &'static [&'static str],
+    inputs: Vec<Input>,
+    device_type: Option<&'static str>,
+    dtypes: Option<&'static [TF_DataType]>,
+    rank: Option<usize>,
+}
+
+impl Pattern {
+    pub fn new(label: &'static str, ops: &'static [&'static str]) -> Self {
+        Self {
+            label,
+            ops,
+            inputs: Vec::new(),
+            device_type: None,
+            dtypes: None,
+            rank: None,
+        }
+    }
+
+    pub fn input(mut self, input: Input) -> Self {
+        self.inputs.push(input);
+        self
+    }
+
+    /// Next input may be any tensor
+    pub fn any(self, label: &'static str) -> Self {
+        self.input(Input::Any(label))
+    }
+
+    /// Next input has to come from a node matching `pattern`
+    pub fn node(self, pattern: Pattern) -> Self {
+        self.input(Input::Node(pattern))
+    }
+
+    /// Only matches nodes placed on a device of this type, e.g. MY_DEVICE
+    pub fn device_type(mut self, device_type: &'static str) -> Self {
+        self.device_type = Some(device_type.trim_end_matches('\0'));
+        self
+    }
+
+    /// Only matches nodes whose `T` attr is one of `dtypes`
+    pub fn dtypes(mut self, dtypes: &'static [TF_DataType]) -> Self {
+        self.dtypes = Some(dtypes);
+        self
+    }
+
+    /// Only matches nodes whose output 0 is known to have this rank
+    pub fn rank(mut self, rank: usize) -> Self {
+        self.rank = Some(rank);
+        self
+    }
+
+    fn matches(&self, node: &NodeDef, item: &Item) -> bool {
+        self.ops.contains(&node.op.as_str())
+            && self
+                .device_type
+                .is_none_or(|device_type| device_type_of(&node.device) == Some(device_type))
+            && self.dtypes.is_none_or(|dtypes| {
+                node.attr
+                    .get("T")
+                    .and_then(|attr| attr.as_data_type())
+                    .is_some_and(|dtype| dtypes.contains(&dtype))
+            })
+            && self
+                .rank
+                .is_none_or(|rank| item.output_rank(&node.name) == Some(rank))
+    }
+}
+
+/// Type of a device name such as `/job:localhost/replica:0/task:0/device:MY_DEVICE:0`
+pub fn device_type_of(device: &str) -> Option<&str> {
+    let (_, device) = device.rsplit_once("device:")?;
+    device.split(':').next()
+}
+
+/// `name`, or with the first free suffix `_1`, `_2`... if it's taken
+pub fn unique_name(names: &mut HashSet<String>, name: &str) -> String {
+    let name = (0..)
+        .map(|i| match i {
+            0 => name.to_owned(),
+            i => format!("{}_{}", name, i),
+        })
+        .find(|name| !names.contains(name))
+        .unwrap();
+    names.insert(name.clone());
+    name
+}
+
+/// CPU of the task a device belongs to, for ops the device has no kernel for
+pub fn host_device(device: &str) -> String {
+    match device.rsplit_once("device:") {
+        Some((task, _)) => format!("{}device:CPU:0", task),
+        None => device.to_owned(),
+    }
+}
+
+/// Nodes and input tensors matched by the labels of a pattern
+#[derive(Debug, Default)]
+pub struct Match {
+    root: String,
+    nodes: BTreeMap<&'static str, NodeDef>,
+    inputs: BTreeMap<&'static str, String>,
+}
+
+impl Match {
+    /// Name the replacement has to take over
+    pub fn root(&self) -> &str {
+        &self.root
+    }
+
+    pub fn node(&self, label: &str) -> &NodeDef {
+        &self.nodes[label]
+    }
+
+    pub fn input(&self, label: &str) -> &str {
+        &self.inputs[label]
+    }
+}
+
+pub trait Rewrite {
+    fn pattern(&self) -> &Pattern;
+
+    /// Nodes replacing the matched ones, one of them named like the root.
+    /// None leaves the match as it is
+    fn replace(&self, matched: &Match) -> Option<Vec<NodeDef>>;
+}
+
+// Node indices and consumers by name
+struct View<'a> {
+    graph: &'a GraphDef,
+    nodes: HashMap<&'a str, usize>,
+    consumers: HashMap<&'a str, Vec<usize>>,
+}
+
+impl<'a> View<'a> {
+    fn new(graph: &'a GraphDef) -> Self {
+        let mut nodes = HashMap::new();
+        let mut consumers: HashMap<_, Vec<_>> = HashMap::new();
+        for (index, node) in graph.node.iter().enumerate() {
+            nodes.insert(node.name.as_str(), index);
+            for input in &node.input {
+                consumers
+                    .entry(parse_input(input).0)
+                    .or_default()
+                    .push(index);
+            }
+        }
+        Self {
+            graph,
+            nodes,
+            consumers,
+        }
+    }
+
+    // Collects matched node indices by label
+    fn match_node(
+        &self,
+        pattern: &Pattern,
+        index: usize,
+        item: &Item,
+        matched: &mut Match,
+        indices: &mut BTreeMap<&'static str, usize>,
+    ) -> bool {
+        let node = &self.graph.node[index];
+        if !pattern.matches(node, item) {
+            return false;
+        }
+        if indices.insert(pattern.label, index).is_some() {
+            return false;
+        }
+        matched.nodes.insert(pattern.label, node.clone());
+
+        let mut inputs = node.data_inputs();
+        pattern.inputs.iter().all(|input_pattern| {
+            let input = match inputs.next() {
+                Some(input) => input,
+                None => return false,
+            };
+            match input_pattern {
+                Input::Any(label) => match matched.inputs.get(label) {
+                    Some(previous) => previous == input,
+                    None => {
+                        matched.inputs.insert(label, input.to_owned());
+                        true
+                    }
+                },
+                Input::Node(pattern) => match self.nodes.get(parse_input(input).0) {
+                    Some(&input) => self.match_node(pattern, input, item, matched, indices),
+                    None => false,
+                },
+            }
+        })
+    }
+
+    // Intermediate nodes go away, so nothing else may refer to them
+    fn is_replaceable(&self, root: usize, indices: &HashSet<usize>, item: &Item) -> bool {
+        indices
+            .iter()
+            .filter(|&&index| index != root)
+            .all(|&index| {
+                let name = self.graph.node[index].name.as_str();
+                !item.nodes_to_preserve.contains(name)
+                    && self
+                        .consumers
+                        .get(name)
+                        .into_iter()
+                        .flatten()
+                        .all(|consumer| indices.contains(consumer))
+            })
+    }
+}
+
+/// Replaces every match of the rewrite's pattern which doesn't overlap an
+/// earlier one. Control dependencies of the matched nodes carry over to the
+/// replacement of the root. Returns the number of replaced matches
+pub fn apply(graph: &mut GraphDef, item: &Item, rewrite: &dyn Rewrite) -> usize {
+    let mut replaced = HashSet::new();
+    let mut replacements = HashMap::new();
+    {
+        let view = View::new(graph);
+        for root in 0..graph.node.len() {
+            let mut matched = Match {
+                root: graph.node[root].name.clone(),
+                ..Default::default()
+            };
+            let mut indices = BTreeMap::new();
+            if !view.match_node(rewrite.pattern(), root, item, &mut matched, &mut indices) {
+                continue;
+            }
+
+            let indices: HashSet<_> = indices.into_values().collect();
+            if !indices.is_disjoint(&replaced) || !view.is_replaceable(root, &indices, item) {
+                continue;
+            }
+            let mut nodes = match rewrite.replace(&matched) {
+                Some(nodes) => nodes,
+                None => continue,
+            };
+
+            let names: HashSet<_> = indices
+                .iter()
+                .map(|&index| graph.node[index].name.as_str())
+                .collect();
+            let mut control_inputs: Vec<_> = indices
+                .iter()
+                .flat_map(|&index| graph.node[index].control_inputs())
+                .filter(|input| !names.contains(input))
+                .collect();
+            control_inputs.sort_unstable();
+            control_inputs.dedup();
+            if let Some(node) = nodes.iter_mut().find(|node| node.name == matched.root) {
+                for input in control_inputs {
+                    let input = format!("^{}", input);
+                    if !node.input.contains(&input) {
+                        node.input.push(input);
+                    }
+                }
+            }
+
+            log::trace!(
+                "Replacing {} with {}",
+                matched.root,
+                nodes
+                    .iter()
+                    .map(|node| node.op.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            replaced.extend(indices);
+            replacements.insert(root, nodes);
+        }
+    }
+
+    let count = replacements.len();
+    if count > 0 {
+        let nodes = std::mem::take(&mut graph.node);
+        for (index, node) in nodes.into_iter().enumerate() {
+            match replacements.remove(&index) {
+                Some(replacement) => graph.node.extend(replacement),
+                None if replaced.contains(&index) => {}
+                None => graph.node.push(node),
+            }
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{apply, device_type_of, Match, Pattern, Rewrite};
+    use crate::{
+        bindings::{
+            graph::{AttrValue, GraphDef, NodeDef},
+            raw::{TF_DataType, TF_FLOAT, TF_INT32},
+        },
+        optimizer::Item,
+        DEVICE_TYPE,
+    };
+
+    pub static DEVICE: &str = "/job:localhost/replica:0/task:0/device:MY_DEVICE:0";
+
+    pub fn node(name: &str, op: &str, inputs: &[&str]) -> NodeDef {
+        typed_node(name, op, inputs, TF_FLOAT)
+    }
+
+    pub fn typed_node(name: &str, op: &str, inputs: &[&str], dtype: TF_DataType) -> NodeDef {
+        let mut node = NodeDef {
+            name: name.to_owned(),
+            op: op.to_owned(),
+            input: inputs.iter().map(|&input| input.to_owned()).collect(),
+            device: DEVICE.to_owned(),
+            ..Default::default()
+        };
+        node.attr
+            .insert("T".to_owned(), AttrValue::data_type(dtype));
+        node
+    }
+
+    pub fn graph(nodes: Vec<NodeDef>) -> GraphDef {
+        GraphDef {
+            node: nodes,
+            ..Default::default()
+        }
+    }
+
+    pub fn ops(graph: &GraphDef) -> Vec<(&str, &str)> {
+        graph
+            .node
+            .iter()
+            .map(|node| (node.name.as_str(), node.op.as_str()))
+            .collect()
+    }
+
+    struct BiasRelu(Pattern);
+
+    impl BiasRelu {
+        fn new() -> Self {
+            Self(
+                Pattern::new("relu", &["Relu"])
+                    .device_type(DEVICE_TYPE)
+                    .node(
+                        Pattern::new("bias_add", &["BiasAdd"])
+                            .dtypes(&[TF_FLOAT])
+                            .any("x")
+                            .any("bias"),
+                    ),
+            )
+        }
+    }
+
+    impl Rewrite for BiasRelu {
+        fn pattern(&self) -> &Pattern {
+            &self.0
+        }
+
+        fn replace(&self, matched: &Match) -> Option<Vec<NodeDef>> {
+            Some(vec![node(
+                matched.root(),
+                "BiasRelu",
+                &[matched.input("x"), matched.input("bias")],
+            )])
+        }
+    }
+
+    fn bias_relu_graph() -> GraphDef {
+        graph(vec![
+            node("x", "Placeholder", &[]),
+            node("bias", "Const", &[]),
+            node("init", "NoOp", &[]),
+            node("bias_add", "BiasAdd", &["x", "bias", "^init"]),
+            node("relu", "Relu", &["bias_add", "^x"]),
+            node("output", "Identity", &["relu"]),
+        ])
+    }
+
+    #[test]
+    fn rewrite_device_type_of() {
+        assert_eq!(device_type_of(DEVICE), Some("MY_DEVICE"));
+        assert_eq!(device_type_of("/device:CPU:0"), Some("CPU"));
+        assert_eq!(device_type_of(""), None);
+    }
+
+    #[test]
+    fn rewrite_replaces_match() {
+        let mut graph = bias_relu_graph();
+        assert_eq!(apply(&mut graph, &Item::default(), &BiasRelu::new()), 1);
+        assert_eq!(
+            ops(&graph),
+            [
+                ("x", "Placeholder"),
+                ("bias", "Const"),
+                ("init", "NoOp"),
+                ("relu", "BiasRelu"),
+                ("output", "Identity")
+            ]
+        );
+        assert_eq!(graph.node[3].input, ["x", "bias", "^init", "^x"]);
+    }
+
+    #[test]
+    fn rewrite_keeps_shared_intermediates() {
+        let mut graph = bias_relu_graph();
+        graph.node.push(node("other", "Identity", &["^bias_add"]));
+        assert_eq!(apply(&mut graph, &Item::default(), &BiasRelu::new()), 0);
+
+        let mut graph = bias_relu_graph();
+        let item = Item {
+            nodes_to_preserve: ["bias_add".to_owned()].into_iter().collect(),
+            ..Default::default()
+        };
+        assert_eq!(apply(&mut graph, &item, &BiasRelu::new()), 0);
+    }
+
+    #[test]
+    fn rewrite_checks_constraints() {
+        let mut graph = bias_relu_graph();
+        graph.node[4].device = "/device:CPU:0".to_owned();
+        assert_eq!(apply(&mut graph, &Item::default(), &BiasRelu::new()), 0);
+
+        let mut graph = bias_relu_graph();
+        graph.node[3] = typed_node("bias_add", "BiasAdd", &["x", "bias"], TF_INT32);
+        assert_eq!(apply(&mut graph, &Item::default(), &BiasRelu::new()), 0);
+    }
+}
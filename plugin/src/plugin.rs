@@ -1,12 +1,44 @@
-use crate::{bindings::raw::*, DEVICE_NAME, DEVICE_TYPE, EMPTY_CSTR};
+use crate::{
+    bindings::raw::*,
+    config,
+    event::{Event, EventStatus},
+    memory::{self, HostMemoryKind},
+    model::{Activity, Direction},
+    stream::{self, SendPtr, Stream, StreamError},
+    timer::Timer,
+    trace, DEVICE_NAME, DEVICE_TYPE, EMPTY_CSTR,
+};
 
-use std::{os::raw::c_void, ptr::null_mut};
+use std::{os::raw::c_void, ptr::null_mut, sync::Arc, time::Instant};
+
+unsafe fn get_stream<'a>(stream: SP_Stream) -> &'a Stream {
+    &*((*stream).stream_handle as *const Stream)
+}
+
+unsafe fn get_event<'a>(event: SP_Event) -> &'a Arc<Event> {
+    &*((*event).event_handle as *const Arc<Event>)
+}
+
+unsafe fn get_timer<'a>(timer: SP_Timer) -> &'a Arc<Timer> {
+    &*((*timer).timer_handle as *const Arc<Timer>)
+}
+
+// Callbacks wrap Rust functions which report errors as StreamErrors, so only
+// the wrappers touch TF_Status and tests can do without TF
+unsafe fn set_status(status: *mut TF_Status, result: Result<(), StreamError>) {
+    match result {
+        Ok(()) => TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8),
+        Err(error) => status.set(error.code, &error.message),
+    }
+}
 
 #[no_mangle]
 unsafe extern "C" fn SE_InitPlugin(
     params: *mut SE_PlatformRegistrationParams,
     status: *mut TF_Status,
 ) {
+    crate::init();
+
     (*params).struct_size = std::mem::size_of::<SE_PlatformRegistrationParams>() as u64;
     (*params).destroy_platform = Some(plugin_destroy_platform);
     (*params).destroy_platform_fns = Some(plugin_destroy_platform_fns);
@@ -14,6 +46,7 @@ unsafe extern "C" fn SE_InitPlugin(
     (*(*params).platform).struct_size = std::mem::size_of::<SP_Platform>() as u64;
     (*(*params).platform).name = DEVICE_NAME.as_ptr() as *const i8;
     (*(*params).platform).type_ = DEVICE_TYPE.as_ptr() as *const i8;
+    (*(*params).platform).supports_unified_memory = config::get().unified_memory as u8;
 
     (*(*params).platform_fns).struct_size = std::mem::size_of::<SP_PlatformFns>() as u64;
     (*(*params).platform_fns).get_device_count = Some(plugin_get_device_count);
@@ -46,11 +79,21 @@ unsafe extern "C" fn plugin_create_device(
     params: *mut SE_CreateDeviceParams,
     status: *mut TF_Status,
 ) {
+    create_device(params);
+    TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
+}
+
+unsafe fn create_device(params: *mut SE_CreateDeviceParams) {
     (*(*params).device).struct_size = std::mem::size_of::<SP_Device>() as u64;
     (*(*params).device).device_handle = Box::into_raw(Box::new("magic".to_owned())) as *mut c_void;
 
     (*(*params).device).ordinal = (*params).ordinal;
-    TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
+
+    // Strings live in the config, so they outlive the device
+    let info = &config::get().device;
+    (*(*params).device).hardware_name = info.hardware_name.as_ptr();
+    (*(*params).device).device_vendor = info.vendor.as_ptr();
+    (*(*params).device).pci_bus_id = info.pci_bus_id.as_ptr();
 }
 
 unsafe extern "C" fn plugin_destroy_device(_platform: *const SP_Platform, device: *mut SP_Device) {
@@ -65,10 +108,29 @@ unsafe extern "C" fn plugin_create_device_fns(
     params: *mut SE_CreateDeviceFnsParams,
     status: *mut TF_Status,
 ) {
-    (*(*params).device_fns).struct_size = std::mem::size_of::<SP_DeviceFns>() as u64;
+    create_device_fns(params);
     TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
 }
 
+unsafe fn create_device_fns(params: *mut SE_CreateDeviceFnsParams) {
+    (*(*params).device_fns).struct_size = std::mem::size_of::<SP_DeviceFns>() as u64;
+    (*(*params).device_fns).get_numa_node = Some(plugin_get_numa_node);
+    (*(*params).device_fns).get_memory_bandwidth = Some(plugin_get_memory_bandwidth);
+    (*(*params).device_fns).get_gflops = Some(plugin_get_gflops);
+}
+
+unsafe extern "C" fn plugin_get_numa_node(_device: *const SP_Device) -> i32 {
+    config::get().device.numa_node
+}
+
+unsafe extern "C" fn plugin_get_memory_bandwidth(_device: *const SP_Device) -> i64 {
+    config::get().device.memory_bandwidth
+}
+
+unsafe extern "C" fn plugin_get_gflops(_device: *const SP_Device) -> f64 {
+    config::get().device.gflops
+}
+
 extern "C" fn plugin_destroy_device_fns(
     _platform: *const SP_Platform,
     _device_fns: *mut SP_DeviceFns,
@@ -85,6 +147,11 @@ unsafe extern "C" fn plugin_create_stream_executor(
     (*(*params).stream_executor).deallocate = Some(plugin_deallocate);
     (*(*params).stream_executor).host_memory_allocate = Some(plugin_host_memory_allocate);
     (*(*params).stream_executor).host_memory_deallocate = Some(plugin_host_memory_deallocate);
+    if config::get().unified_memory {
+        (*(*params).stream_executor).unified_memory_allocate = Some(plugin_unified_memory_allocate);
+        (*(*params).stream_executor).unified_memory_deallocate =
+            Some(plugin_unified_memory_deallocate);
+    }
     (*(*params).stream_executor).get_allocator_stats = Some(plugin_get_allocator_stats);
     (*(*params).stream_executor).device_memory_usage = Some(plugin_device_memory_usage);
 
@@ -109,7 +176,6 @@ unsafe extern "C" fn plugin_create_stream_executor(
     (*(*params).stream_executor).sync_memcpy_htod = Some(plugin_sync_memcpy_htod);
     (*(*params).stream_executor).sync_memcpy_dtod = Some(plugin_sync_memcpy_dtod);
 
-    // TODO(plugin): Fill the function for block stream
     (*(*params).stream_executor).block_host_until_done = Some(plugin_block_host_until_done);
     (*(*params).stream_executor).block_host_for_event = Some(plugin_block_host_for_event);
 
@@ -130,12 +196,18 @@ unsafe extern "C" fn plugin_allocate(
     mem: *mut SP_DeviceMemoryBase,
 ) {
     (*mem).struct_size = std::mem::size_of::<SP_DeviceMemoryBase>() as u64;
-    (*mem).opaque = libc::malloc(size as libc::size_t) as *mut std::ffi::c_void;
+    (*mem).opaque = memory::allocate(size as usize);
     (*mem).size = size;
+    if !(*mem).opaque.is_null() {
+        trace::record_allocation(size as i64);
+    }
 }
 
 unsafe extern "C" fn plugin_deallocate(_device: *const SP_Device, mem: *mut SP_DeviceMemoryBase) {
-    libc::free((*mem).opaque);
+    if !(*mem).opaque.is_null() {
+        trace::record_allocation(-((*mem).size as i64));
+    }
+    memory::deallocate((*mem).opaque);
     (*mem).opaque = null_mut();
     (*mem).size = 0;
 }
@@ -144,14 +216,28 @@ unsafe extern "C" fn plugin_host_memory_allocate(
     _device: *const SP_Device,
     size: u64,
 ) -> *mut std::ffi::c_void {
-    libc::malloc(size as libc::size_t) as *mut std::ffi::c_void
+    memory::host_allocate(size as usize, HostMemoryKind::Pinned)
 }
 
 unsafe extern "C" fn plugin_host_memory_deallocate(
     _device: *const SP_Device,
     mem: *mut std::ffi::c_void,
 ) {
-    libc::free(mem);
+    memory::host_deallocate(mem);
+}
+
+unsafe extern "C" fn plugin_unified_memory_allocate(
+    _device: *const SP_Device,
+    size: u64,
+) -> *mut std::ffi::c_void {
+    memory::host_allocate(size as usize, HostMemoryKind::Unified)
+}
+
+unsafe extern "C" fn plugin_unified_memory_deallocate(
+    _device: *const SP_Device,
+    mem: *mut std::ffi::c_void,
+) {
+    memory::host_deallocate(mem);
 }
 
 unsafe extern "C" fn plugin_get_allocator_stats(
@@ -170,9 +256,17 @@ unsafe extern "C" fn plugin_device_memory_usage(
     free: *mut i64,
     total: *mut i64,
 ) -> u8 {
-    // FIXME
-    *free = 256_000_000;
-    *total = 512_000_000;
+    match memory::usage() {
+        Some((free_bytes, total_bytes)) => {
+            *free = free_bytes as i64;
+            *total = total_bytes as i64;
+        }
+        None => {
+            // FIXME
+            *free = 256_000_000;
+            *total = 512_000_000;
+        }
+    }
     1
 }
 
@@ -181,73 +275,101 @@ unsafe extern "C" fn plugin_create_stream(
     stream: *mut SP_Stream,
     status: *mut TF_Status,
 ) {
-    *stream = Box::into_raw(Box::new(SP_Stream_st {
-        stream_handle: (*device).device_handle,
-    }));
-
+    *stream = create_stream(device);
     TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
 }
 
+unsafe fn create_stream(device: *const SP_Device) -> SP_Stream {
+    let device_data = &*((*device).device_handle as *const String);
+    Box::into_raw(Box::new(SP_Stream_st {
+        stream_handle: Box::into_raw(Box::new(Stream::new(device_data.clone()))) as *mut c_void,
+    }))
+}
+
 // Destroys SP_Stream and deallocates any underlying resources.
+// Pending work is drained before the worker exits.
 unsafe extern "C" fn plugin_destroy_stream(_device: *const SP_Device, stream: SP_Stream) {
+    std::mem::drop(Box::from_raw((*stream).stream_handle as *mut Stream));
     std::mem::drop(Box::from_raw(stream))
 }
 
+// Work enqueued later on dependent waits for everything enqueued so far on other.
 unsafe extern "C" fn plugin_create_stream_dependency(
     _device: *const SP_Device,
-    _dependent: SP_Stream,
-    _other: SP_Stream,
+    dependent: SP_Stream,
+    other: SP_Stream,
     status: *mut TF_Status,
 ) {
+    create_stream_dependency(dependent, other);
     TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
 }
 
+unsafe fn create_stream_dependency(dependent: SP_Stream, other: SP_Stream) {
+    let barrier = Arc::new(Event::default());
+    barrier.record(get_stream(other));
+    barrier.wait_on(get_stream(dependent));
+}
+
 // Without blocking the device, retrieve the current stream status.
 unsafe extern "C" fn plugin_get_stream_status(
     _device: *const SP_Device,
-    _stream: SP_Stream,
+    stream: SP_Stream,
     status: *mut TF_Status,
 ) {
-    TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
+    set_status(status, get_stream(stream).status());
 }
 
 unsafe extern "C" fn plugin_create_event(
     _device: *const SP_Device,
-    _event: *mut SP_Event,
+    event: *mut SP_Event,
     status: *mut TF_Status,
 ) {
+    *event = Box::into_raw(Box::new(SP_Event_st {
+        event_handle: Box::into_raw(Box::new(Arc::new(Event::default()))) as *mut c_void,
+    }));
+
     TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
 }
 
 // Destroy SE_Event and perform any platform-specific deallocation and
 // cleanup of an event.
-extern "C" fn plugin_destroy_event(_device: *const SP_Device, _event: SP_Event) {}
+// Records still enqueued on streams keep their own reference to the event.
+unsafe extern "C" fn plugin_destroy_event(_device: *const SP_Device, event: SP_Event) {
+    std::mem::drop(Box::from_raw((*event).event_handle as *mut Arc<Event>));
+    std::mem::drop(Box::from_raw(event))
+}
 
 // Requests the current status of the event from the underlying platform.
-extern "C" fn plugin_get_event_status(
+unsafe extern "C" fn plugin_get_event_status(
     _device: *const SP_Device,
-    _event: SP_Event,
+    event: SP_Event,
 ) -> SE_EventStatus {
-    SE_EVENT_COMPLETE
+    match get_event(event).status() {
+        EventStatus::Pending => SE_EVENT_PENDING,
+        EventStatus::Complete => SE_EVENT_COMPLETE,
+        EventStatus::Error(_) => SE_EVENT_ERROR,
+    }
 }
 
 // Inserts the specified event at the end of the specified stream.
 unsafe extern "C" fn plugin_record_event(
     _device: *const SP_Device,
-    _stream: SP_Stream,
-    _event: SP_Event,
+    stream: SP_Stream,
+    event: SP_Event,
     status: *mut TF_Status,
 ) {
+    get_event(event).record(get_stream(stream));
     TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
 }
 
 // Wait for the specified event at the end of the specified stream.
 unsafe extern "C" fn plugin_wait_for_event(
     _device: *const SP_Device,
-    _stream: SP_Stream,
-    _event: SP_Event,
+    stream: SP_Stream,
+    event: SP_Event,
     status: *mut TF_Status,
 ) {
+    get_event(event).wait_on(get_stream(stream));
     TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
 }
 
@@ -259,46 +381,71 @@ extern "C" fn plugin_destroy_stream_executor(
 
 unsafe extern "C" fn plugin_create_timer(
     _device: *const SP_Device,
-    _timer: *mut SP_Timer,
+    timer: *mut SP_Timer,
     status: *mut TF_Status,
 ) {
+    *timer = Box::into_raw(Box::new(SP_Timer_st {
+        timer_handle: Box::into_raw(Box::new(Arc::new(Timer::default()))) as *mut c_void,
+    }));
+
     TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
 }
 
 // Destroy timer and deallocates timer resources on the underlying platform.
-extern "C" fn plugin_destroy_timer(_device: *const SP_Device, _timer: SP_Timer) {}
+unsafe extern "C" fn plugin_destroy_timer(_device: *const SP_Device, timer: SP_Timer) {
+    std::mem::drop(Box::from_raw((*timer).timer_handle as *mut Arc<Timer>));
+    std::mem::drop(Box::from_raw(timer))
+}
 
 // Records a start event for an interval timer.
 unsafe extern "C" fn plugin_start_timer(
     _device: *const SP_Device,
-    _stream: SP_Stream,
-    _timer: SP_Timer,
+    stream: SP_Stream,
+    timer: SP_Timer,
     status: *mut TF_Status,
 ) {
+    get_timer(timer).start(get_stream(stream));
     TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
 }
 
 // Records a stop event for an interval timer.
 unsafe extern "C" fn plugin_stop_timer(
     _device: *const SP_Device,
-    _stream: SP_Stream,
-    _timer: SP_Timer,
+    stream: SP_Stream,
+    timer: SP_Timer,
     status: *mut TF_Status,
 ) {
+    get_timer(timer).stop(get_stream(stream));
     TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
 }
 
 unsafe extern "C" fn plugin_memcpy_dtoh(
     _device: *const SP_Device,
-    _stream: SP_Stream,
+    stream: SP_Stream,
     host_dst: *mut std::ffi::c_void,
     device_src: *const SP_DeviceMemoryBase,
     size: u64,
     status: *mut TF_Status,
 ) {
-    libc::memcpy(host_dst, (*device_src).opaque, size as usize);
+    memcpy_dtoh(stream, host_dst, device_src, size);
     TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
 }
+
+unsafe fn memcpy_dtoh(
+    stream: SP_Stream,
+    host_dst: *mut c_void,
+    device_src: *const SP_DeviceMemoryBase,
+    size: u64,
+) {
+    let dst = SendPtr(host_dst);
+    let src = SendPtr(memory::host_view((*device_src).opaque));
+    let activity = Activity::Memcpy(Direction::DeviceToHost, size);
+    get_stream(stream).enqueue_activity(activity, move || {
+        libc::memcpy(dst.get(), src.get(), size as usize);
+        Ok(())
+    });
+}
+
 unsafe extern "C" fn plugin_sync_memcpy_dtoh(
     _device: *const SP_Device,
     host_dst: *mut std::ffi::c_void,
@@ -306,19 +453,36 @@ unsafe extern "C" fn plugin_sync_memcpy_dtoh(
     size: u64,
     status: *mut TF_Status,
 ) {
-    libc::memcpy(host_dst, (*device_src).opaque, size as usize);
+    let start = Instant::now();
+    libc::memcpy(
+        host_dst,
+        memory::host_view((*device_src).opaque),
+        size as usize,
+    );
+    trace::record(
+        None,
+        Activity::Memcpy(Direction::DeviceToHost, size),
+        None,
+        start,
+    );
     TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
 }
 
 unsafe extern "C" fn plugin_memcpy_dtod(
     _device: *const SP_Device,
-    _stream: SP_Stream,
+    stream: SP_Stream,
     device_dst: *mut SP_DeviceMemoryBase,
     device_src: *const SP_DeviceMemoryBase,
     size: u64,
     status: *mut TF_Status,
 ) {
-    libc::memcpy((*device_dst).opaque, (*device_src).opaque, size as usize);
+    let dst = SendPtr(memory::host_view((*device_dst).opaque));
+    let src = SendPtr(memory::host_view((*device_src).opaque));
+    let activity = Activity::Memcpy(Direction::DeviceToDevice, size);
+    get_stream(stream).enqueue_activity(activity, move || {
+        libc::memcpy(dst.get(), src.get(), size as usize);
+        Ok(())
+    });
     TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
 }
 unsafe extern "C" fn plugin_sync_memcpy_dtod(
@@ -328,21 +492,59 @@ unsafe extern "C" fn plugin_sync_memcpy_dtod(
     size: u64,
     status: *mut TF_Status,
 ) {
-    libc::memcpy((*device_dst).opaque, (*device_src).opaque, size as usize);
+    let start = Instant::now();
+    libc::memcpy(
+        memory::host_view((*device_dst).opaque),
+        memory::host_view((*device_src).opaque),
+        size as usize,
+    );
+    trace::record(
+        None,
+        Activity::Memcpy(Direction::DeviceToDevice, size),
+        None,
+        start,
+    );
     TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
 }
 
 unsafe extern "C" fn plugin_memcpy_htod(
     _device: *const SP_Device,
-    _stream: SP_Stream,
+    stream: SP_Stream,
     device_dst: *mut SP_DeviceMemoryBase,
     host_src: *const std::ffi::c_void,
     size: u64,
     status: *mut TF_Status,
 ) {
-    libc::memcpy((*device_dst).opaque, host_src, size as usize);
+    memcpy_htod(stream, device_dst, host_src, size);
     TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
 }
+
+unsafe fn memcpy_htod(
+    stream: SP_Stream,
+    device_dst: *mut SP_DeviceMemoryBase,
+    host_src: *const c_void,
+    size: u64,
+) {
+    let dst = SendPtr(memory::host_view((*device_dst).opaque));
+    let activity = Activity::Memcpy(Direction::HostToDevice, size);
+
+    // Pinned memory is copied directly, while pageable memory is staged
+    // when the copy is enqueued, like a DMA engine would need it
+    if memory::is_host_memory(host_src, size as usize, HostMemoryKind::Pinned) {
+        let src = SendPtr(host_src as *mut std::ffi::c_void);
+        get_stream(stream).enqueue_activity(activity, move || {
+            libc::memcpy(dst.get(), src.get(), size as usize);
+            Ok(())
+        });
+    } else {
+        let staging = std::slice::from_raw_parts(host_src as *const u8, size as usize).to_vec();
+        get_stream(stream).enqueue_activity(activity, move || {
+            libc::memcpy(dst.get(), staging.as_ptr() as *const c_void, size as usize);
+            Ok(())
+        });
+    }
+}
+
 unsafe extern "C" fn plugin_sync_memcpy_htod(
     _device: *const SP_Device,
     device_dst: *mut SP_DeviceMemoryBase,
@@ -350,84 +552,134 @@ unsafe extern "C" fn plugin_sync_memcpy_htod(
     size: u64,
     status: *mut TF_Status,
 ) {
-    libc::memcpy((*device_dst).opaque, host_src, size as usize);
+    let start = Instant::now();
+    libc::memcpy(
+        memory::host_view((*device_dst).opaque),
+        host_src,
+        size as usize,
+    );
+    trace::record(
+        None,
+        Activity::Memcpy(Direction::HostToDevice, size),
+        None,
+        start,
+    );
     TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
 }
 
 unsafe extern "C" fn plugin_block_host_for_event(
     _device: *const SP_Device,
-    _event: SP_Event,
+    event: SP_Event,
     status: *mut TF_Status,
 ) {
-    TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
+    set_status(status, get_event(event).wait());
 }
 
 unsafe extern "C" fn plugin_block_host_until_done(
     _device: *const SP_Device,
-    _stream: SP_Stream,
+    stream: SP_Stream,
     status: *mut TF_Status,
 ) {
-    TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
+    set_status(status, get_stream(stream).synchronize());
 }
 
 unsafe extern "C" fn plugin_synchronize_all_activity(
     _device: *const SP_Device,
     status: *mut TF_Status,
 ) {
-    TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
+    set_status(status, stream::synchronize_all());
 }
 unsafe extern "C" fn plugin_mem_zero(
-    _device: *const SP_Device,
-    _stream: SP_Stream,
+    device: *const SP_Device,
+    stream: SP_Stream,
     location: *mut SP_DeviceMemoryBase,
     size: u64,
     status: *mut TF_Status,
 ) {
-    plugin_memset32(_device, _stream, location, 0, size, status);
+    plugin_memset(device, stream, location, 0, size, status);
 }
 
 unsafe extern "C" fn plugin_memset(
     _device: *const SP_Device,
-    _stream: SP_Stream,
+    stream: SP_Stream,
     location: *mut SP_DeviceMemoryBase,
     pattern: u8,
     size: u64,
     status: *mut TF_Status,
 ) {
-    let pattern: u32 = pattern.into();
-    plugin_memset32(
-        _device,
-        _stream,
-        location,
-        pattern << 24 | pattern << 16 | pattern << 8 | pattern,
-        size,
-        status,
-    );
+    memset(stream, location, pattern, size);
+    TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
+}
+
+unsafe fn memset(stream: SP_Stream, location: *mut SP_DeviceMemoryBase, pattern: u8, size: u64) {
+    let dst = SendPtr(memory::host_view((*location).opaque));
+    let activity = Activity::Memset(size);
+    get_stream(stream).enqueue_activity(activity, move || {
+        libc::memset(dst.get(), pattern as i32, size as usize);
+        Ok(())
+    });
 }
 
+// Size is in bytes, a trailing partial word gets the leading bytes of the pattern.
 unsafe extern "C" fn plugin_memset32(
     _device: *const SP_Device,
-    _stream: SP_Stream,
+    stream: SP_Stream,
     location: *mut SP_DeviceMemoryBase,
     pattern: u32,
     size: u64,
     status: *mut TF_Status,
 ) {
-    libc::memset((*location).opaque, pattern as i32, size as usize);
+    memset32(stream, location, pattern, size);
     TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
 }
 
-extern "C" fn plugin_host_callback(
+unsafe fn memset32(stream: SP_Stream, location: *mut SP_DeviceMemoryBase, pattern: u32, size: u64) {
+    let dst = SendPtr(memory::host_view((*location).opaque) as *mut u8);
+    let activity = Activity::Memset(size);
+    get_stream(stream).enqueue_activity(activity, move || {
+        memory::fill32(dst.get(), pattern, size as usize);
+        Ok(())
+    });
+}
+
+// Callbacks run even on a failed stream, as TF releases resources in them.
+unsafe extern "C" fn plugin_host_callback(
     _device: *const SP_Device,
-    _stream: SP_Stream,
-    _callback_fn: SE_StatusCallbackFn,
-    _callback_arg: *mut std::ffi::c_void,
+    stream: SP_Stream,
+    callback_fn: SE_StatusCallbackFn,
+    callback_arg: *mut std::ffi::c_void,
 ) -> u8 {
+    let callback_fn = match callback_fn {
+        Some(callback_fn) => callback_fn,
+        None => return 0,
+    };
+
+    let arg = SendPtr(callback_arg);
+    host_callback(stream, move || {
+        let status = TF_NewStatus();
+        callback_fn(arg.get(), status);
+
+        // The callback's code is passed on, e.g. TF_ABORTED stays TF_ABORTED
+        let result = match status.is_ok() {
+            true => Ok(()),
+            false => Err(StreamError::new(TF_GetCode(status), status.message())),
+        };
+        TF_DeleteStatus(status);
+        result
+    });
+
     1
 }
 
-extern "C" fn nanoseconds(timer: *mut SP_Timer_st) -> u64 {
-    unsafe { (*timer).timer_handle as u64 }
+unsafe fn host_callback<F>(stream: SP_Stream, callback: F)
+where
+    F: FnOnce() -> Result<(), StreamError> + Send + 'static,
+{
+    get_stream(stream).enqueue_always(move |_| callback());
+}
+
+unsafe extern "C" fn nanoseconds(timer: SP_Timer) -> u64 {
+    get_timer(timer).nanoseconds()
 }
 
 unsafe extern "C" fn plugin_create_timer_fns(
@@ -435,6 +687,7 @@ unsafe extern "C" fn plugin_create_timer_fns(
     timer_fns: *mut SP_TimerFns,
     status: *mut TF_Status,
 ) {
+    (*timer_fns).struct_size = std::mem::size_of::<SP_TimerFns>() as u64;
     (*timer_fns).nanoseconds = Some(nanoseconds);
     TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
 }
@@ -444,3 +697,341 @@ extern "C" fn plugin_destroy_timer_fns(
     _timer_fns: *mut SP_TimerFns,
 ) {
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{ffi::CStr, sync::mpsc, time::Duration};
+
+    struct TestDevice {
+        device: SP_Device,
+    }
+
+    impl TestDevice {
+        unsafe fn new() -> Self {
+            let mut device: SP_Device = std::mem::zeroed();
+            device.device_handle = Box::into_raw(Box::new("test".to_owned())) as *mut c_void;
+            Self { device }
+        }
+
+        unsafe fn create_stream(&self) -> SP_Stream {
+            create_stream(&self.device)
+        }
+
+        unsafe fn allocate(&self, size: u64) -> SP_DeviceMemoryBase {
+            let mut mem: SP_DeviceMemoryBase = std::mem::zeroed();
+            plugin_allocate(&self.device, size, 0, &mut mem);
+            mem
+        }
+
+        unsafe fn synchronize(&self, stream: SP_Stream) {
+            assert_eq!(get_stream(stream).synchronize(), Ok(()));
+        }
+    }
+
+    impl Drop for TestDevice {
+        fn drop(&mut self) {
+            unsafe {
+                std::mem::drop(Box::from_raw(self.device.device_handle as *mut String));
+            }
+        }
+    }
+
+    #[test]
+    fn device_reports_attributes() {
+        unsafe {
+            let mut device: SP_Device = std::mem::zeroed();
+            let mut params: SE_CreateDeviceParams = std::mem::zeroed();
+            params.device = &mut device;
+
+            create_device(&mut params);
+            let info = &config::get().device;
+            assert_eq!(
+                CStr::from_ptr(device.hardware_name),
+                info.hardware_name.as_c_str()
+            );
+            assert_eq!(CStr::from_ptr(device.device_vendor), info.vendor.as_c_str());
+            assert_eq!(
+                CStr::from_ptr(device.pci_bus_id),
+                info.pci_bus_id.as_c_str()
+            );
+
+            let mut device_fns: SP_DeviceFns = std::mem::zeroed();
+            let mut params: SE_CreateDeviceFnsParams = std::mem::zeroed();
+            params.device = &mut device;
+            params.device_fns = &mut device_fns;
+            create_device_fns(&mut params);
+            assert_eq!(device_fns.get_numa_node.unwrap()(&device), info.numa_node);
+            assert!(device_fns.get_memory_bandwidth.unwrap()(&device) > 0);
+            assert!(device_fns.get_gflops.unwrap()(&device) > 0.0);
+
+            std::mem::drop(Box::from_raw(device.device_handle as *mut String));
+        }
+    }
+
+    // Keeps the stream busy, so anything enqueued after runs late
+    unsafe fn delay(stream: SP_Stream) {
+        get_stream(stream).enqueue(|| {
+            std::thread::sleep(Duration::from_millis(50));
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn stream_dependency_orders_copies() {
+        unsafe {
+            let test = TestDevice::new();
+            let copy = test.create_stream();
+            let compute = test.create_stream();
+
+            let src: Vec<u8> = (0..=255).collect();
+            let mut dst = vec![0u8; src.len()];
+            let mut mem = test.allocate(src.len() as u64);
+
+            delay(copy);
+            memcpy_htod(copy, &mut mem, src.as_ptr() as *const c_void, 256);
+            create_stream_dependency(compute, copy);
+            memcpy_dtoh(compute, dst.as_mut_ptr() as *mut c_void, &mem, 256);
+
+            test.synchronize(compute);
+            assert_eq!(src, dst);
+
+            plugin_deallocate(&test.device, &mut mem);
+            plugin_destroy_stream(&test.device, compute);
+            plugin_destroy_stream(&test.device, copy);
+        }
+    }
+
+    #[test]
+    fn stream_dependency_ignores_later_work() {
+        unsafe {
+            let test = TestDevice::new();
+            let copy = test.create_stream();
+            let compute = test.create_stream();
+
+            let src = [1u8; 64];
+            let mut dst = [0u8; 64];
+            let mut mem = test.allocate(64);
+
+            memcpy_htod(copy, &mut mem, src.as_ptr() as *const c_void, 64);
+            create_stream_dependency(compute, copy);
+
+            // Blocks the copy stream until the end of the test
+            let (sender, receiver) = mpsc::channel::<()>();
+            get_stream(copy).enqueue(move || {
+                receiver.recv().unwrap();
+                Ok(())
+            });
+
+            memcpy_dtoh(compute, dst.as_mut_ptr() as *mut c_void, &mem, 64);
+            test.synchronize(compute);
+            assert_eq!(src, dst);
+
+            sender.send(()).unwrap();
+            plugin_deallocate(&test.device, &mut mem);
+            plugin_destroy_stream(&test.device, compute);
+            plugin_destroy_stream(&test.device, copy);
+        }
+    }
+
+    #[test]
+    fn stream_dependency_propagates_error() {
+        unsafe {
+            let test = TestDevice::new();
+            let copy = test.create_stream();
+            let compute = test.create_stream();
+
+            get_stream(copy).enqueue(|| Err("copy failed".into()));
+            create_stream_dependency(compute, copy);
+
+            let expected = Err(StreamError::new(TF_INTERNAL, "copy failed"));
+            assert_eq!(get_stream(compute).synchronize(), expected);
+            assert_eq!(get_stream(compute).status(), expected);
+
+            plugin_destroy_stream(&test.device, compute);
+            plugin_destroy_stream(&test.device, copy);
+        }
+    }
+
+    #[test]
+    fn memset32_fills_floats() {
+        unsafe {
+            let test = TestDevice::new();
+            let stream = test.create_stream();
+
+            // 10 floats and 3 bytes of the next one
+            let mut dst = [0u8; 43];
+            let mut mem = test.allocate(43);
+
+            delay(stream);
+            memset32(stream, &mut mem, 0x3f800000, 43);
+            memcpy_dtoh(stream, dst.as_mut_ptr() as *mut c_void, &mem, 43);
+            test.synchronize(stream);
+
+            for chunk in dst.chunks_exact(4) {
+                assert_eq!(f32::from_ne_bytes(chunk.try_into().unwrap()), 1f32);
+            }
+            assert_eq!(dst[40..], 1f32.to_ne_bytes()[..3]);
+
+            plugin_deallocate(&test.device, &mut mem);
+            plugin_destroy_stream(&test.device, stream);
+        }
+    }
+
+    #[test]
+    fn memset_is_byte_exact() {
+        unsafe {
+            let test = TestDevice::new();
+            let stream = test.create_stream();
+
+            let src = [0u8; 16];
+            let mut dst = [0u8; 16];
+            let mut mem = test.allocate(16);
+
+            memcpy_htod(stream, &mut mem, src.as_ptr() as *const c_void, 16);
+            memset(stream, &mut mem, 0xab, 7);
+            let dst_ptr = dst.as_mut_ptr() as *mut c_void;
+            memcpy_dtoh(stream, dst_ptr, &mem, 16);
+            test.synchronize(stream);
+
+            assert_eq!(dst[..7], [0xab; 7]);
+            assert_eq!(dst[7..], [0; 9]);
+
+            memset(stream, &mut mem, 0, 16);
+            memcpy_dtoh(stream, dst_ptr, &mem, 16);
+            test.synchronize(stream);
+            assert_eq!(dst, [0; 16]);
+
+            plugin_deallocate(&test.device, &mut mem);
+            plugin_destroy_stream(&test.device, stream);
+        }
+    }
+
+    #[test]
+    fn memcpy_htod_stages_pageable_memory() {
+        unsafe {
+            let test = TestDevice::new();
+            let stream = test.create_stream();
+
+            let mut src = [1u8; 16];
+            let mut dst = [0u8; 16];
+            let mut mem = test.allocate(16);
+
+            delay(stream);
+            memcpy_htod(stream, &mut mem, src.as_ptr() as *const c_void, 16);
+            // Data was captured when the copy was enqueued
+            src.fill(2);
+            memcpy_dtoh(stream, dst.as_mut_ptr() as *mut c_void, &mem, 16);
+            test.synchronize(stream);
+            assert_eq!(dst, [1; 16]);
+
+            plugin_deallocate(&test.device, &mut mem);
+            plugin_destroy_stream(&test.device, stream);
+        }
+    }
+
+    #[test]
+    fn memcpy_htod_reads_pinned_memory_directly() {
+        unsafe {
+            let test = TestDevice::new();
+            let stream = test.create_stream();
+
+            let src = plugin_host_memory_allocate(&test.device, 16) as *mut u8;
+            std::slice::from_raw_parts_mut(src, 16).fill(1);
+            let mut dst = [0u8; 16];
+            let mut mem = test.allocate(16);
+
+            delay(stream);
+            memcpy_htod(stream, &mut mem, src as *const c_void, 16);
+            // Pinned memory must stay untouched until the copy completes,
+            // so the late write shows the copy isn't staged
+            std::slice::from_raw_parts_mut(src, 16).fill(2);
+            memcpy_dtoh(stream, dst.as_mut_ptr() as *mut c_void, &mem, 16);
+            test.synchronize(stream);
+            assert_eq!(dst, [2; 16]);
+
+            plugin_host_memory_deallocate(&test.device, src as *mut c_void);
+            plugin_deallocate(&test.device, &mut mem);
+            plugin_destroy_stream(&test.device, stream);
+        }
+    }
+
+    // Callback reading back the device memory, which it sends to the test
+    unsafe fn read_back(
+        mem: &SP_DeviceMemoryBase,
+        result: Result<(), StreamError>,
+    ) -> (
+        impl FnOnce() -> Result<(), StreamError> + Send + 'static,
+        mpsc::Receiver<Vec<u8>>,
+    ) {
+        let (sender, receiver) = mpsc::channel();
+        let src = SendPtr(memory::host_view(mem.opaque) as *mut u8);
+        let len = mem.size as usize;
+        let callback = move || {
+            let observed = std::slice::from_raw_parts(src.get(), len).to_vec();
+            sender.send(observed).unwrap();
+            result
+        };
+        (callback, receiver)
+    }
+
+    #[test]
+    fn host_callback_runs_after_copies() {
+        unsafe {
+            let test = TestDevice::new();
+            let stream = test.create_stream();
+
+            let src = [7u8; 32];
+            let mut mem = test.allocate(32);
+
+            delay(stream);
+            memcpy_htod(stream, &mut mem, src.as_ptr() as *const c_void, 32);
+            let (callback, observed) = read_back(&mem, Ok(()));
+            host_callback(stream, callback);
+
+            test.synchronize(stream);
+            assert_eq!(observed.recv().unwrap(), src);
+
+            plugin_deallocate(&test.device, &mut mem);
+            plugin_destroy_stream(&test.device, stream);
+        }
+    }
+
+    #[test]
+    fn host_callback_error_fails_stream() {
+        unsafe {
+            let test = TestDevice::new();
+            let stream = test.create_stream();
+
+            let mut mem = test.allocate(8);
+            let error = StreamError::new(TF_ABORTED, "callback failed");
+            let (callback, _observed) = read_back(&mem, Err(error.clone()));
+            host_callback(stream, callback);
+
+            assert_eq!(get_stream(stream).synchronize(), Err(error));
+
+            plugin_deallocate(&test.device, &mut mem);
+            plugin_destroy_stream(&test.device, stream);
+        }
+    }
+
+    #[test]
+    fn host_callback_runs_on_failed_stream() {
+        unsafe {
+            let test = TestDevice::new();
+            let stream = test.create_stream();
+
+            let mut mem = test.allocate(8);
+
+            get_stream(stream).enqueue(|| Err("copy failed".into()));
+            let (callback, observed) = read_back(&mem, Ok(()));
+            host_callback(stream, callback);
+
+            assert_eq!(get_stream(stream).synchronize(), Err("copy failed".into()));
+            assert_eq!(observed.recv().unwrap().len(), 8);
+
+            plugin_deallocate(&test.device, &mut mem);
+            plugin_destroy_stream(&test.device, stream);
+        }
+    }
+}
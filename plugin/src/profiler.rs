@@ -0,0 +1,363 @@
+// Profiler plugin: TF starts and stops recording of the device timeline, then
+// collects it as an XSpace which shows up next to host activity in TensorBoard
+use std::{collections::HashMap, sync::Mutex};
+
+use prost::Message;
+
+use crate::{
+    bindings::{
+        compute::data_type_name,
+        raw::*,
+        xplane::{
+            XEvent, XEventData, XEventMetadata, XLine, XPlane, XSpace, XStat, XStatMetadata,
+            XStatValue,
+        },
+    },
+    trace::{self, Timeline},
+    DEVICE_TYPE, EMPTY_CSTR,
+};
+
+// TF converts planes with this prefix into device tracks of the trace viewer
+static DEVICE_PLANE_NAME: &str = "/device:CUSTOM:0";
+static SYNC_LINE_NAME: &str = "Synchronous copies";
+static MEMORY_LINE_NAME: &str = "Memory";
+static ALLOCATION_EVENT_NAME: &str = "MemoryAllocation";
+static DEALLOCATION_EVENT_NAME: &str = "MemoryDeallocation";
+
+// TF asks for the size of the collected data before the data itself
+static COLLECTED: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+
+#[no_mangle]
+unsafe extern "C" fn TF_InitProfiler(
+    params: *mut TF_ProfilerRegistrationParams,
+    status: *mut TF_Status,
+) {
+    crate::init();
+
+    (*params).struct_size = std::mem::size_of::<TF_ProfilerRegistrationParams>() as u64;
+    (*params).destroy_profiler = Some(plugin_destroy_profiler);
+    (*params).destroy_profiler_fns = Some(plugin_destroy_profiler_fns);
+
+    (*(*params).profiler).struct_size = std::mem::size_of::<TP_Profiler>() as u64;
+    (*(*params).profiler).device_type = DEVICE_TYPE.as_ptr() as *const i8;
+
+    (*(*params).profiler_fns).struct_size = std::mem::size_of::<TP_ProfilerFns>() as u64;
+    (*(*params).profiler_fns).start = Some(plugin_start);
+    (*(*params).profiler_fns).stop = Some(plugin_stop);
+    (*(*params).profiler_fns).collect_data_xspace = Some(plugin_collect_data_xspace);
+
+    TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
+}
+
+extern "C" fn plugin_destroy_profiler(_profiler: *mut TP_Profiler) {}
+extern "C" fn plugin_destroy_profiler_fns(_profiler_fns: *mut TP_ProfilerFns) {}
+
+unsafe extern "C" fn plugin_start(_profiler: *const TP_Profiler, status: *mut TF_Status) {
+    log::debug!("Profiler started");
+    trace::start();
+    TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
+}
+
+unsafe extern "C" fn plugin_stop(_profiler: *const TP_Profiler, status: *mut TF_Status) {
+    trace::stop();
+    log::debug!("Profiler stopped");
+    TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8);
+}
+
+/// Reports the size of the serialized XSpace while `buffer` is null, and
+/// fills `buffer` on the following call
+unsafe extern "C" fn plugin_collect_data_xspace(
+    _profiler: *const TP_Profiler,
+    buffer: *mut u8,
+    size_in_bytes: *mut u64,
+    status: *mut TF_Status,
+) {
+    match collect(buffer, size_in_bytes) {
+        Ok(()) => TF_SetStatus(status, TF_OK, EMPTY_CSTR.as_ptr() as *const i8),
+        Err(message) => status.set(TF_INVALID_ARGUMENT, message),
+    }
+}
+
+unsafe fn collect(buffer: *mut u8, size_in_bytes: *mut u64) -> Result<(), &'static str> {
+    let mut collected = COLLECTED.lock().unwrap();
+    if buffer.is_null() {
+        let data = xspace(&trace::take()).encode_to_vec();
+        *size_in_bytes = data.len() as u64;
+        *collected = Some(data);
+    } else {
+        let data = collected.take().unwrap_or_default();
+        if data.len() as u64 > *size_in_bytes {
+            return Err("Buffer too small for the XSpace");
+        }
+        std::ptr::copy_nonoverlapping(data.as_ptr(), buffer, data.len());
+        *size_in_bytes = data.len() as u64;
+    }
+    Ok(())
+}
+
+/// Interns event and stat names of a plane
+#[derive(Default)]
+struct PlaneBuilder {
+    plane: XPlane,
+}
+
+impl PlaneBuilder {
+    fn event_metadata(&mut self, name: &str) -> i64 {
+        let metadata = &mut self.plane.event_metadata;
+        match metadata.values().find(|metadata| metadata.name == name) {
+            Some(metadata) => metadata.id,
+            None => {
+                let id = metadata.len() as i64 + 1;
+                metadata.insert(
+                    id,
+                    XEventMetadata {
+                        id,
+                        name: name.to_owned(),
+                        ..Default::default()
+                    },
+                );
+                id
+            }
+        }
+    }
+
+    fn stat(&mut self, name: &str, value: XStatValue) -> XStat {
+        let metadata = &mut self.plane.stat_metadata;
+        let metadata_id = match metadata.values().find(|metadata| metadata.name == name) {
+            Some(metadata) => metadata.id,
+            None => {
+                let id = metadata.len() as i64 + 1;
+                metadata.insert(
+                    id,
+                    XStatMetadata {
+                        id,
+                        name: name.to_owned(),
+                        ..Default::default()
+                    },
+                );
+                id
+            }
+        };
+        XStat {
+            metadata_id,
+            value: Some(value),
+        }
+    }
+
+    fn event(&mut self, line: &XLine, name: &str, begin_ns: i64, end_ns: i64) -> XEvent {
+        XEvent {
+            metadata_id: self.event_metadata(name),
+            data: Some(XEventData::OffsetPs((begin_ns - line.timestamp_ns) * 1000)),
+            duration_ps: (end_ns - begin_ns) * 1000,
+            stats: Vec::new(),
+        }
+    }
+}
+
+/// Device plane with a line per stream, and a line of allocations
+fn xspace(timeline: &Timeline) -> XSpace {
+    let mut builder = PlaneBuilder::default();
+    builder.plane.name = DEVICE_PLANE_NAME.to_owned();
+
+    // Lines start with their earliest event
+    let mut events: Vec<_> = timeline.events.iter().collect();
+    events.sort_by_key(|event| event.begin);
+
+    let mut lines: HashMap<Option<usize>, XLine> = HashMap::new();
+    for event in events {
+        let begin = trace::unix_nanos(event.begin);
+        let end = trace::unix_nanos(event.end);
+
+        let line = lines.entry(event.stream).or_insert_with(|| {
+            let (id, name) = match event.stream {
+                Some(stream) => (stream as i64, format!("Stream #{}", stream)),
+                None => (-2, SYNC_LINE_NAME.to_owned()),
+            };
+            XLine {
+                id,
+                display_id: id,
+                name,
+                timestamp_ns: begin,
+                ..Default::default()
+            }
+        });
+        let mut xevent = builder.event(line, event.name(), begin, end);
+        match &event.kernel {
+            Some(kernel) => {
+                let shapes: Vec<_> = kernel.inputs.iter().map(|input| &input.dims).collect();
+                let shapes = XStatValue::StrValue(format!("{:?}", shapes));
+                xevent.stats.push(builder.stat("shapes", shapes));
+                let dtypes: Vec<_> = kernel
+                    .inputs
+                    .iter()
+                    .map(|input| data_type_name(input.dtype))
+                    .collect();
+                let dtypes = XStatValue::StrValue(dtypes.join(", "));
+                xevent.stats.push(builder.stat("dtypes", dtypes));
+            }
+            None => {
+                let bytes = XStatValue::Uint64Value(event.bytes());
+                xevent.stats.push(builder.stat("bytes", bytes));
+            }
+        }
+        line.duration_ps = line.duration_ps.max((end - line.timestamp_ns) * 1000);
+        line.events.push(xevent);
+    }
+
+    let mut lines: Vec<_> = lines.into_values().collect();
+    lines.sort_by_key(|line| (line.id < 0, line.id));
+
+    if let Some(first) = timeline.allocations.first() {
+        let mut line = XLine {
+            id: -1,
+            display_id: -1,
+            name: MEMORY_LINE_NAME.to_owned(),
+            timestamp_ns: trace::unix_nanos(first.time),
+            ..Default::default()
+        };
+        for allocation in &timeline.allocations {
+            let time = trace::unix_nanos(allocation.time);
+            let name = match allocation.bytes >= 0 {
+                true => ALLOCATION_EVENT_NAME,
+                false => DEALLOCATION_EVENT_NAME,
+            };
+            let mut xevent = builder.event(&line, name, time, time);
+            let bytes = XStatValue::Int64Value(allocation.bytes.abs());
+            xevent.stats.push(builder.stat("bytes", bytes));
+            let in_use = XStatValue::Int64Value(allocation.bytes_in_use);
+            xevent.stats.push(builder.stat("bytes_in_use", in_use));
+            line.duration_ps = (time - line.timestamp_ns) * 1000;
+            line.events.push(xevent);
+        }
+        lines.push(line);
+    }
+
+    builder.plane.lines = lines;
+    XSpace {
+        planes: vec![builder.plane],
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{collect, xspace};
+    use crate::{
+        bindings::{
+            compute::TensorInfo,
+            raw::TF_FLOAT,
+            xplane::{XEventData, XSpace, XStatValue},
+        },
+        model::{Activity, Direction},
+        stream::Stream,
+        trace::{self, Allocation, Event, Kernel, Timeline},
+    };
+    use prost::Message;
+    use std::{
+        ptr::null_mut,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn profiler_collects_stream_activity() {
+        unsafe {
+            let stream = Stream::new("test".to_owned());
+
+            trace::start();
+            stream.enqueue_activity(Activity::Memset(64), || Ok(()));
+            stream.synchronize().unwrap();
+            trace::stop();
+
+            let mut size = 0;
+            assert_eq!(collect(null_mut(), &mut size), Ok(()));
+            let mut buffer = vec![0u8; size as usize];
+            assert_eq!(collect(buffer.as_mut_ptr(), &mut size), Ok(()));
+
+            let space = XSpace::decode(&buffer[..size as usize]).unwrap();
+            let plane = &space.planes[0];
+            let name = format!("Stream #{}", stream.id());
+            let line = plane.lines.iter().find(|line| line.name == name).unwrap();
+            assert_eq!(line.events.len(), 1);
+            let metadata_id = line.events[0].metadata_id;
+            assert_eq!(plane.event_metadata[&metadata_id].name, "Memset");
+        }
+    }
+
+    #[test]
+    fn xspace_lines_per_stream() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let event = |stream, activity, kernel, begin, end| Event {
+            stream,
+            activity,
+            kernel,
+            begin: start + ms(begin),
+            end: start + ms(end),
+        };
+        let relu = Kernel {
+            op_name: "Relu".to_owned(),
+            inputs: vec![TensorInfo {
+                dims: vec![2, 3],
+                dtype: TF_FLOAT,
+                bytes: 24,
+            }],
+        };
+        let timeline = Timeline {
+            events: vec![
+                event(
+                    Some(7),
+                    Activity::Memcpy(Direction::HostToDevice, 24),
+                    None,
+                    0,
+                    1,
+                ),
+                event(Some(7), Activity::Kernel(ms(2)), Some(relu), 1, 3),
+                event(Some(9), Activity::Memset(8), None, 2, 4),
+                event(
+                    None,
+                    Activity::Memcpy(Direction::DeviceToHost, 24),
+                    None,
+                    4,
+                    5,
+                ),
+            ],
+            allocations: vec![Allocation {
+                time: start,
+                bytes: 24,
+                bytes_in_use: 24,
+            }],
+        };
+
+        let space = xspace(&timeline);
+        let plane = &space.planes[0];
+        assert_eq!(plane.name, "/device:CUSTOM:0");
+        let names: Vec<_> = plane.lines.iter().map(|line| line.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["Stream #7", "Stream #9", "Synchronous copies", "Memory"]
+        );
+
+        let stream = &plane.lines[0];
+        assert_eq!(stream.duration_ps, 3_000_000_000);
+        let kernel = &stream.events[1];
+        assert_eq!(plane.event_metadata[&kernel.metadata_id].name, "Relu");
+        assert_eq!(kernel.data, Some(XEventData::OffsetPs(1_000_000_000)));
+        assert_eq!(kernel.duration_ps, 2_000_000_000);
+        assert_eq!(
+            kernel.stats[0].value,
+            Some(XStatValue::StrValue("[[2, 3]]".to_owned()))
+        );
+        assert_eq!(
+            kernel.stats[1].value,
+            Some(XStatValue::StrValue("float".to_owned()))
+        );
+
+        let copy = &stream.events[0];
+        assert_eq!(plane.event_metadata[&copy.metadata_id].name, "MemcpyHtoD");
+        assert_eq!(
+            plane.stat_metadata[&copy.stats[0].metadata_id].name,
+            "bytes"
+        );
+        assert_eq!(copy.stats[0].value, Some(XStatValue::Uint64Value(24)));
+    }
+}
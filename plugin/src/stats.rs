@@ -0,0 +1,335 @@
+// Runtime statistics of kernels per op and dtype, gathered on every launch
+// while TFP_KERNEL_STATS is set and reported when the process exits. Other
+// code in the process reads them through `TFP_KernelStatsSnapshot`. With the
+// timing model enabled, the utilization of streams is reported along with them
+use std::{
+    collections::HashMap,
+    ffi::c_void,
+    fmt::Write as _,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, Once,
+    },
+    time::Duration,
+};
+
+use crate::{
+    bindings::{
+        compute::{data_type_name, TensorInfo},
+        stats::{self as exported, KernelStats, WriteFn},
+    },
+    config, stream,
+};
+
+// Latency histogram buckets per power of two, about 9% resolution
+const SUBBUCKETS: usize = 8;
+const BUCKETS: usize = 64 * SUBBUCKETS;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static STATS: Mutex<Option<HashMap<Key, Accumulator>>> = Mutex::new(None);
+
+/// Where the summary goes at exit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    Stderr,
+    File(PathBuf),
+}
+
+impl FromStr for Output {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "" => Err(()),
+            "1" | "stderr" => Ok(Self::Stderr),
+            path => Ok(Self::File(PathBuf::from(path))),
+        }
+    }
+}
+
+// Dtype of the first input, which is what kernels are registered for
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    op_name: String,
+    dtype: &'static str,
+}
+
+struct Accumulator {
+    count: u64,
+    total: Duration,
+    max: Duration,
+    bytes_read: u64,
+    bytes_written: u64,
+    histogram: Box<[u64; BUCKETS]>,
+}
+
+impl Default for Accumulator {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            total: Duration::ZERO,
+            max: Duration::ZERO,
+            bytes_read: 0,
+            bytes_written: 0,
+            histogram: Box::new([0; BUCKETS]),
+        }
+    }
+}
+
+impl Accumulator {
+    fn bucket(latency: Duration) -> usize {
+        let nanos = latency.as_nanos().clamp(1, u64::MAX as u128) as f64;
+        ((nanos.log2() * SUBBUCKETS as f64) as usize).min(BUCKETS - 1)
+    }
+
+    // Upper bound of the bucket holding the percentile, at most the maximum
+    fn percentile(&self, percentile: f64) -> Duration {
+        let rank = ((self.count as f64 * percentile / 100.0).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.histogram.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let bound = 2f64.powf((bucket + 1) as f64 / SUBBUCKETS as f64);
+                return Duration::from_nanos(bound as u64).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// Starts gathering if statistics were requested
+pub fn init() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let config = config::get();
+        if config.kernel_stats.is_some() {
+            ENABLED.store(true, Ordering::Relaxed);
+        }
+        if config.kernel_stats.is_some() || config.model.is_enabled() {
+            unsafe { libc::atexit(report_at_exit) };
+        }
+    });
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn record(op_name: &str, inputs: &[TensorInfo], outputs: &[TensorInfo], latency: Duration) {
+    let key = Key {
+        op_name: op_name.to_owned(),
+        dtype: inputs
+            .first()
+            .map_or("none", |input| data_type_name(input.dtype)),
+    };
+
+    let mut stats = STATS.lock().unwrap();
+    let accumulator = stats
+        .get_or_insert_with(HashMap::new)
+        .entry(key)
+        .or_default();
+    accumulator.count += 1;
+    accumulator.total += latency;
+    accumulator.max = accumulator.max.max(latency);
+    accumulator.bytes_read += inputs.iter().map(|input| input.bytes).sum::<u64>();
+    accumulator.bytes_written += outputs.iter().map(|output| output.bytes).sum::<u64>();
+    accumulator.histogram[Accumulator::bucket(latency)] += 1;
+}
+
+/// Statistics gathered so far, the kernels which took the longest in total first
+pub fn snapshot() -> Vec<KernelStats> {
+    let stats = STATS.lock().unwrap();
+    let mut snapshot: Vec<_> = stats
+        .iter()
+        .flatten()
+        .map(|(key, accumulator)| KernelStats {
+            op_name: key.op_name.clone(),
+            dtype: key.dtype.to_owned(),
+            count: accumulator.count,
+            total: accumulator.total,
+            p50: accumulator.percentile(50.0),
+            p90: accumulator.percentile(90.0),
+            p99: accumulator.percentile(99.0),
+            max: accumulator.max,
+            bytes_read: accumulator.bytes_read,
+            bytes_written: accumulator.bytes_written,
+        })
+        .collect();
+    snapshot.sort_by(|a, b| (b.total, &a.op_name, &a.dtype).cmp(&(a.total, &b.op_name, &b.dtype)));
+    snapshot
+}
+
+/// `SnapshotFn` of the bindings, which decode what it writes
+#[no_mangle]
+pub unsafe extern "C" fn TFP_KernelStatsSnapshot(write: WriteFn, arg: *mut c_void) {
+    let data = exported::encode(&snapshot());
+    write(arg, data.as_ptr(), data.len());
+}
+
+pub fn table(snapshot: &[KernelStats]) -> String {
+    let micros = |duration: Duration| duration.as_secs_f64() * 1e6;
+    let mut table = format!(
+        "{:<24} {:<10} {:>8} {:>12} {:>10} {:>10} {:>10} {:>10} {:>12} {:>12}\n",
+        "op",
+        "dtype",
+        "count",
+        "total ms",
+        "mean us",
+        "p50 us",
+        "p90 us",
+        "p99 us",
+        "read B",
+        "written B"
+    );
+    for stats in snapshot {
+        writeln!(
+            table,
+            "{:<24} {:<10} {:>8} {:>12.3} {:>10.1} {:>10.1} {:>10.1} {:>10.1} {:>12} {:>12}",
+            stats.op_name,
+            stats.dtype,
+            stats.count,
+            stats.total.as_secs_f64() * 1e3,
+            micros(stats.mean()),
+            micros(stats.p50),
+            micros(stats.p90),
+            micros(stats.p99),
+            stats.bytes_read,
+            stats.bytes_written
+        )
+        .unwrap();
+    }
+    table
+}
+
+// TF doesn't destroy every stream before the process exits, so their
+// utilization is only complete here
+extern "C" fn report_at_exit() {
+    if config::get().model.is_enabled() {
+        eprint!("Stream utilization\n{}", stream::utilization_report());
+    }
+
+    let table = table(&snapshot());
+    match &config::get().kernel_stats {
+        Some(Output::Stderr) => eprint!("Kernel statistics\n{}", table),
+        Some(Output::File(path)) => {
+            if let Err(error) = std::fs::write(path, table) {
+                log::error!(
+                    "Failed to write kernel statistics to {}: {}",
+                    path.display(),
+                    error
+                );
+            }
+        }
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{record, snapshot, table, Accumulator, Output, TFP_KernelStatsSnapshot};
+    use crate::bindings::{
+        compute::TensorInfo,
+        raw::{TF_DataType, TF_FLOAT, TF_INT32},
+        stats,
+    };
+    use std::{path::PathBuf, time::Duration};
+
+    fn tensor(dtype: TF_DataType, bytes: u64) -> TensorInfo {
+        TensorInfo {
+            dims: vec![bytes as i64 / 4],
+            dtype,
+            bytes,
+        }
+    }
+
+    #[test]
+    fn stats_output_from_str() {
+        assert_eq!("1".parse(), Ok(Output::Stderr));
+        assert_eq!("stderr".parse(), Ok(Output::Stderr));
+        assert_eq!(
+            "stats.txt".parse(),
+            Ok(Output::File(PathBuf::from("stats.txt")))
+        );
+        assert_eq!("".parse::<Output>(), Err(()));
+    }
+
+    #[test]
+    fn stats_percentiles() {
+        let mut accumulator = Accumulator::default();
+        for micros in 1..=100 {
+            let latency = Duration::from_micros(micros);
+            accumulator.count += 1;
+            accumulator.max = latency;
+            accumulator.histogram[Accumulator::bucket(latency)] += 1;
+        }
+
+        let within = |actual: Duration, expected: u64| {
+            let expected = Duration::from_micros(expected);
+            assert!(
+                actual >= expected && actual <= expected.mul_f64(1.1),
+                "{:?}",
+                actual
+            );
+        };
+        within(accumulator.percentile(50.0), 50);
+        within(accumulator.percentile(90.0), 90);
+        within(accumulator.percentile(99.0), 99);
+        assert_eq!(accumulator.percentile(100.0), Duration::from_micros(100));
+    }
+
+    #[test]
+    fn stats_per_op_and_dtype() {
+        let ms = Duration::from_millis;
+        record(
+            "StatsTestA",
+            &[tensor(TF_FLOAT, 16)],
+            &[tensor(TF_FLOAT, 16)],
+            ms(1),
+        );
+        record(
+            "StatsTestA",
+            &[tensor(TF_FLOAT, 16)],
+            &[tensor(TF_FLOAT, 16)],
+            ms(3),
+        );
+        record("StatsTestA", &[tensor(TF_INT32, 8)], &[], ms(1));
+        record("StatsTestB", &[], &[], ms(5));
+
+        let snapshot: Vec<_> = snapshot()
+            .into_iter()
+            .filter(|stats| stats.op_name.starts_with("StatsTest"))
+            .collect();
+        let keys: Vec<_> = snapshot
+            .iter()
+            .map(|stats| (stats.op_name.as_str(), stats.dtype.as_str()))
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                ("StatsTestB", "none"),
+                ("StatsTestA", "float"),
+                ("StatsTestA", "int32")
+            ]
+        );
+
+        let float = &snapshot[1];
+        assert_eq!(float.count, 2);
+        assert_eq!(float.total, ms(4));
+        assert_eq!(float.mean(), ms(2));
+        assert_eq!(float.max, ms(3));
+        assert_eq!((float.bytes_read, float.bytes_written), (32, 32));
+
+        let table = table(&snapshot);
+        assert_eq!(table.lines().count(), 4);
+        assert!(table.lines().nth(2).unwrap().starts_with("StatsTestA"));
+
+        let exported: Vec<_> = unsafe { stats::snapshot(TFP_KernelStatsSnapshot) }
+            .unwrap()
+            .into_iter()
+            .filter(|stats| stats.op_name.starts_with("StatsTest"))
+            .collect();
+        assert_eq!(exported, snapshot);
+    }
+}
@@ -0,0 +1,519 @@
+// Emulation of asynchronous device streams: every SP_Stream owns a FIFO of
+// work items which is drained in order by a dedicated worker thread
+use std::{
+    any::Any,
+    collections::VecDeque,
+    fmt,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, Weak,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    bindings::raw::{TF_Code, TF_INTERNAL},
+    config,
+    model::{Activity, DeviceModel, Utilization},
+    trace,
+};
+
+// Receives the current stream status, so that work which must run even on a
+// failed stream (e.g. markers) can observe it
+type Task = Box<dyn FnOnce(Result<(), &StreamError>) -> Result<(), StreamError> + Send>;
+
+// Every stream alive in the process, used to synchronize all activity
+static STREAMS: Mutex<Vec<Weak<Shared>>> = Mutex::new(Vec::new());
+// Utilization summaries of destroyed streams, reported at exit along with
+// those of streams which are still alive
+static DESTROYED: Mutex<Vec<(usize, String)>> = Mutex::new(Vec::new());
+static NEXT_STREAM_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Raw pointer which may be moved into work executed by a stream worker.
+/// Access it through `get` so closures capture the wrapper and not the pointer
+#[derive(Debug, Clone, Copy)]
+pub struct SendPtr<T>(pub *mut T);
+
+unsafe impl<T> Send for SendPtr<T> {}
+
+impl<T> SendPtr<T> {
+    pub fn get(self) -> *mut T {
+        self.0
+    }
+}
+
+/// Failure of work on a stream, reported to TF with its code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamError {
+    pub code: TF_Code,
+    pub message: String,
+}
+
+impl StreamError {
+    pub fn new(code: TF_Code, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+// Errors of the plugin itself are internal
+impl From<String> for StreamError {
+    fn from(message: String) -> Self {
+        Self::new(TF_INTERNAL, message)
+    }
+}
+
+impl From<&str> for StreamError {
+    fn from(message: &str) -> Self {
+        Self::new(TF_INTERNAL, message)
+    }
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    tasks: VecDeque<Task>,
+    // Enqueued tasks which haven't finished yet, including the running one
+    pending: usize,
+    // First error reported on the stream, sticky until the stream is destroyed
+    error: Option<StreamError>,
+    shutdown: bool,
+}
+
+struct Shared {
+    id: usize,
+    created: Instant,
+    queue: Mutex<Queue>,
+    work_available: Condvar,
+    drained: Condvar,
+    model: DeviceModel,
+    utilization: Mutex<Utilization>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        self.queue.lock().unwrap()
+    }
+
+    fn utilization_summary(&self) -> String {
+        let utilization = self.utilization.lock().unwrap();
+        utilization.summary(self.created.elapsed())
+    }
+
+    fn synchronize(&self) -> Result<(), StreamError> {
+        let mut queue = self.lock();
+        while queue.pending != 0 {
+            queue = self.drained.wait(queue).unwrap();
+        }
+
+        match &queue.error {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct Stream {
+    device: String,
+    shared: Arc<Shared>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Stream {
+    pub fn new(device: String) -> Self {
+        Self::with_model(device, config::get().model)
+    }
+
+    pub fn with_model(device: String, model: DeviceModel) -> Self {
+        let id = NEXT_STREAM_ID.fetch_add(1, Ordering::Relaxed);
+        let shared = Arc::new(Shared {
+            id,
+            created: Instant::now(),
+            queue: Mutex::default(),
+            work_available: Condvar::new(),
+            drained: Condvar::new(),
+            model,
+            utilization: Mutex::default(),
+        });
+
+        let worker_shared = shared.clone();
+        let worker = std::thread::Builder::new()
+            .name(format!("tfp-stream-{}", id))
+            .spawn(move || run(worker_shared))
+            .expect("Failed to spawn stream worker");
+
+        let mut streams = STREAMS.lock().unwrap();
+        streams.retain(|stream| stream.strong_count() != 0);
+        streams.push(Arc::downgrade(&shared));
+
+        Self {
+            device,
+            shared,
+            worker: Some(worker),
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.shared.id
+    }
+
+    pub fn device(&self) -> &str {
+        &self.device
+    }
+
+    /// Enqueues work which keeps the stream busy for at least the simulated
+    /// duration of the activity, see `enqueue` for error handling
+    pub fn enqueue_activity<F>(&self, activity: Activity, work: F)
+    where
+        F: FnOnce() -> Result<(), StreamError> + Send + 'static,
+    {
+        self.enqueue_traced(activity, None, work)
+    }
+
+    /// Enqueues the simulated execution of a kernel,
+    /// which took `compute` on the host
+    pub fn enqueue_kernel(&self, compute: Duration, kernel: Option<trace::Kernel>) {
+        self.enqueue_traced(Activity::Kernel(compute), kernel, || Ok(()))
+    }
+
+    fn enqueue_traced<F>(&self, activity: Activity, kernel: Option<trace::Kernel>, work: F)
+    where
+        F: FnOnce() -> Result<(), StreamError> + Send + 'static,
+    {
+        let shared = self.shared.clone();
+        self.enqueue(move || {
+            let start = Instant::now();
+            let result = work();
+
+            let simulated = shared.model.duration(&activity);
+            if let Some(remaining) = simulated.checked_sub(start.elapsed()) {
+                std::thread::sleep(remaining);
+            }
+
+            let busy = start.elapsed();
+            shared.utilization.lock().unwrap().record(&activity, busy);
+            trace::record(Some(shared.id), activity, kernel, start);
+            result
+        })
+    }
+
+    /// Enqueues work which is skipped if the stream has already failed.
+    /// An error returned by the work puts the stream into the failed state
+    pub fn enqueue<F>(&self, work: F)
+    where
+        F: FnOnce() -> Result<(), StreamError> + Send + 'static,
+    {
+        self.enqueue_always(move |status| {
+            status.map_err(StreamError::clone)?;
+            work()
+        })
+    }
+
+    /// Enqueues work which runs in stream order regardless of the stream status
+    pub fn enqueue_always<F>(&self, task: F)
+    where
+        F: FnOnce(Result<(), &StreamError>) -> Result<(), StreamError> + Send + 'static,
+    {
+        let mut queue = self.shared.lock();
+        queue.tasks.push_back(Box::new(task));
+        queue.pending += 1;
+        self.shared.work_available.notify_one();
+    }
+
+    /// Blocks until all work enqueued so far has completed
+    pub fn synchronize(&self) -> Result<(), StreamError> {
+        self.shared.synchronize()
+    }
+
+    /// Retrieves the stream status without blocking
+    pub fn status(&self) -> Result<(), StreamError> {
+        match &self.shared.lock().error {
+            Some(error) => Err(error.clone()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        // Worker drains the remaining work before it exits
+        self.shared.lock().shutdown = true;
+        self.shared.work_available.notify_one();
+
+        // Unwinding out of destroy_stream would abort TF
+        if let Some(Err(_)) = self.worker.take().map(JoinHandle::join) {
+            log::error!("Stream {} worker panicked", self.shared.id);
+        }
+
+        if self.shared.model.is_enabled() {
+            let summary = self.shared.utilization_summary();
+            DESTROYED.lock().unwrap().push((self.shared.id, summary));
+        }
+    }
+}
+
+/// Busy time and utilization of every stream created so far, a line per
+/// stream. Streams which are still alive report them up to now
+pub fn utilization_report() -> String {
+    let mut summaries = DESTROYED.lock().unwrap().clone();
+    summaries.extend(
+        STREAMS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|shared| (shared.id, shared.utilization_summary())),
+    );
+    summaries.sort();
+    summaries
+        .iter()
+        .map(|(id, summary)| format!("Stream {}: {}\n", id, summary))
+        .collect()
+}
+
+/// Blocks until every stream in the process is idle, reporting the first error
+pub fn synchronize_all() -> Result<(), StreamError> {
+    let streams: Vec<_> = STREAMS
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect();
+
+    streams
+        .iter()
+        .map(|stream| stream.synchronize())
+        .fold(Ok(()), Result::and)
+}
+
+fn run(shared: Arc<Shared>) {
+    loop {
+        let (task, error) = {
+            let mut queue = shared.lock();
+            while queue.tasks.is_empty() && !queue.shutdown {
+                queue = shared.work_available.wait(queue).unwrap();
+            }
+
+            match queue.tasks.pop_front() {
+                Some(task) => (task, queue.error.clone()),
+                None => return,
+            }
+        };
+
+        // A panicking task fails the stream like an error, so the worker
+        // survives to finish the remaining work
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            task(match &error {
+                Some(error) => Err(error),
+                None => Ok(()),
+            })
+        }))
+        .unwrap_or_else(|panic| {
+            Err(format!("Stream work panicked: {}", panic_message(&*panic)).into())
+        });
+
+        let mut queue = shared.lock();
+        if let (Err(error), None) = (result, &queue.error) {
+            queue.error = Some(error);
+        }
+        queue.pending -= 1;
+        if queue.pending == 0 {
+            shared.drained.notify_all();
+        }
+    }
+}
+
+pub fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic
+            .downcast_ref::<String>()
+            .map_or("unknown panic", String::as_str),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{utilization_report, SendPtr, Stream, StreamError};
+    use crate::model::{Activity, DeviceModel, Direction};
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc, Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    #[test]
+    fn stream_runs_work_in_order() {
+        let stream = Stream::new("test".to_owned());
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        for i in 0..100 {
+            let log = log.clone();
+            stream.enqueue(move || {
+                log.lock().unwrap().push(i);
+                Ok(())
+            });
+        }
+
+        assert_eq!(stream.synchronize(), Ok(()));
+        assert_eq!(*log.lock().unwrap(), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn stream_is_asynchronous() {
+        let stream = Stream::new("test".to_owned());
+        let mut value = 0u32;
+        let ptr = SendPtr(&mut value as *mut u32);
+        let done = Arc::new(AtomicBool::new(false));
+
+        let done_clone = done.clone();
+        stream.enqueue(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            unsafe { *ptr.get() = 42 };
+            done_clone.store(true, Ordering::SeqCst);
+            Ok(())
+        });
+        // Work is still sleeping on the worker thread
+        assert!(!done.load(Ordering::SeqCst));
+
+        assert_eq!(stream.synchronize(), Ok(()));
+        assert_eq!(value, 42);
+    }
+
+    #[test]
+    fn stream_error_is_sticky() {
+        let stream = Stream::new("test".to_owned());
+        let ran = Arc::new(Mutex::new(false));
+
+        stream.enqueue(|| Err("first".into()));
+        stream.enqueue(|| Err("second".into()));
+        let ran_clone = ran.clone();
+        stream.enqueue(move || {
+            *ran_clone.lock().unwrap() = true;
+            Ok(())
+        });
+
+        assert_eq!(stream.synchronize(), Err("first".into()));
+        assert_eq!(stream.status(), Err("first".into()));
+        assert!(!*ran.lock().unwrap());
+    }
+
+    #[test]
+    fn stream_panic_fails_stream() {
+        let stream = Stream::new("test".to_owned());
+        let ran = Arc::new(Mutex::new(false));
+
+        stream.enqueue(|| panic!("broken kernel"));
+        let ran_clone = ran.clone();
+        stream.enqueue_always(move |status| {
+            *ran_clone.lock().unwrap() = true;
+            status.map_err(StreamError::clone)
+        });
+
+        let expected = StreamError::from("Stream work panicked: broken kernel");
+        assert_eq!(stream.synchronize(), Err(expected.clone()));
+        assert_eq!(stream.status(), Err(expected));
+        assert!(*ran.lock().unwrap());
+    }
+
+    #[test]
+    fn stream_drop_drains_work() {
+        let stream = Stream::new("test".to_owned());
+        let counter = Arc::new(Mutex::new(0));
+
+        for _ in 0..10 {
+            let counter = counter.clone();
+            stream.enqueue(move || {
+                *counter.lock().unwrap() += 1;
+                Ok(())
+            });
+        }
+        std::mem::drop(stream);
+
+        assert_eq!(*counter.lock().unwrap(), 10);
+    }
+
+    #[test]
+    fn stream_models_activity_duration() {
+        let model = DeviceModel {
+            htod_bandwidth: 1e9,
+            kernel_latency: Duration::from_millis(10),
+            ..Default::default()
+        };
+        let stream = Stream::with_model("test".to_owned(), model);
+
+        // 20 ms at 1 GB/s
+        let copy = Activity::Memcpy(Direction::HostToDevice, 20_000_000);
+        let kernel = Activity::Kernel(Duration::ZERO);
+        let simulated = model.duration(&copy) + model.duration(&kernel);
+        assert_eq!(simulated, Duration::from_millis(30));
+
+        stream.enqueue_activity(copy, || Ok(()));
+        stream.enqueue_activity(kernel, || Ok(()));
+        assert_eq!(stream.synchronize(), Ok(()));
+
+        // Workers sleep for at least the simulated duration
+        assert!(stream.shared.utilization.lock().unwrap().busy() >= simulated);
+    }
+
+    #[test]
+    fn stream_utilization_reports_live_and_destroyed() {
+        let model = DeviceModel {
+            kernel_latency: Duration::from_millis(1),
+            ..Default::default()
+        };
+        let live = Stream::with_model("live".to_owned(), model);
+        let destroyed = Stream::with_model("destroyed".to_owned(), model);
+        let destroyed_id = destroyed.id();
+        live.enqueue_kernel(Duration::ZERO, None);
+        destroyed.enqueue_kernel(Duration::ZERO, None);
+        assert_eq!(live.synchronize(), Ok(()));
+        std::mem::drop(destroyed);
+
+        let report = utilization_report();
+        for id in [live.id(), destroyed_id] {
+            let line = report
+                .lines()
+                .find(|line| line.starts_with(&format!("Stream {}: ", id)))
+                .unwrap();
+            assert!(line.contains("; kernel 1 x "));
+        }
+    }
+
+    #[test]
+    fn stream_model_overlaps_streams() {
+        let model = DeviceModel {
+            kernel_latency: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let first = Stream::with_model("first".to_owned(), model);
+        let second = Stream::with_model("second".to_owned(), model);
+        let (sender, receiver) = mpsc::channel::<()>();
+
+        // The first kernel only finishes once the second one ran, which
+        // can't happen if the streams were serialized
+        first.enqueue_activity(Activity::Kernel(Duration::ZERO), move || {
+            receiver
+                .recv_timeout(Duration::from_secs(10))
+                .map_err(|_| "streams didn't overlap".into())
+        });
+        second.enqueue_activity(Activity::Kernel(Duration::ZERO), move || {
+            sender.send(()).unwrap();
+            Ok(())
+        });
+
+        assert_eq!(first.synchronize(), Ok(()));
+        assert_eq!(second.synchronize(), Ok(()));
+    }
+}
@@ -0,0 +1,117 @@
+// Interval timers take monotonic timestamps when the stream reaches their
+// start and stop points, not when those are enqueued
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use crate::stream::Stream;
+
+#[derive(Debug, Default)]
+struct Interval {
+    start: Option<Instant>,
+    stop: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+pub struct Timer {
+    interval: Mutex<Interval>,
+}
+
+impl Timer {
+    pub fn start(self: &Arc<Self>, stream: &Stream) {
+        let timer = self.clone();
+        stream.enqueue_always(move |_| {
+            *timer.interval.lock().unwrap() = Interval {
+                start: Some(Instant::now()),
+                stop: None,
+            };
+            Ok(())
+        });
+    }
+
+    pub fn stop(self: &Arc<Self>, stream: &Stream) {
+        let timer = self.clone();
+        stream.enqueue_always(move |_| {
+            timer.interval.lock().unwrap().stop = Some(Instant::now());
+            Ok(())
+        });
+    }
+
+    /// Elapsed time between the start and stop points, zero until both are reached
+    pub fn nanoseconds(&self) -> u64 {
+        match *self.interval.lock().unwrap() {
+            Interval {
+                start: Some(start),
+                stop: Some(stop),
+            } => stop.saturating_duration_since(start).as_nanos() as u64,
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Timer;
+    use crate::stream::Stream;
+    use std::{sync::Arc, time::Duration};
+
+    fn sleep(stream: &Stream, ms: u64) {
+        stream.enqueue(move || {
+            std::thread::sleep(Duration::from_millis(ms));
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn timer_measures_stream_work() {
+        let stream = Stream::new("test".to_owned());
+        let timer = Arc::new(Timer::default());
+
+        timer.start(&stream);
+        sleep(&stream, 20);
+        timer.stop(&stream);
+
+        assert_eq!(stream.synchronize(), Ok(()));
+        assert!(timer.nanoseconds() >= 20_000_000);
+    }
+
+    #[test]
+    fn timer_excludes_earlier_work() {
+        let stream = Stream::new("test".to_owned());
+        let timer = Arc::new(Timer::default());
+
+        sleep(&stream, 50);
+        timer.start(&stream);
+        timer.stop(&stream);
+
+        assert_eq!(stream.synchronize(), Ok(()));
+        assert!(timer.nanoseconds() < 50_000_000);
+    }
+
+    #[test]
+    fn timer_incomplete_is_zero() {
+        let stream = Stream::new("test".to_owned());
+        let timer = Arc::new(Timer::default());
+        assert_eq!(timer.nanoseconds(), 0);
+
+        timer.start(&stream);
+        assert_eq!(stream.synchronize(), Ok(()));
+        assert_eq!(timer.nanoseconds(), 0);
+    }
+
+    #[test]
+    fn timer_restart_resets_interval() {
+        let stream = Stream::new("test".to_owned());
+        let timer = Arc::new(Timer::default());
+
+        timer.start(&stream);
+        sleep(&stream, 50);
+        timer.stop(&stream);
+        timer.start(&stream);
+        timer.stop(&stream);
+
+        assert_eq!(stream.synchronize(), Ok(()));
+        assert!(timer.nanoseconds() < 50_000_000);
+    }
+}
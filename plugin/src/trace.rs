@@ -0,0 +1,170 @@
+// Timeline of device activity: stream workers record every activity they run
+// while recording is on, allocations are sampled along with the memory in use.
+// A profiler session and the trace export record independently of each other
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicI64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    bindings::compute::TensorInfo,
+    model::{Activity, Direction},
+};
+
+static PROFILING: AtomicBool = AtomicBool::new(false);
+static EXPORTING: AtomicBool = AtomicBool::new(false);
+static SESSION: Mutex<Timeline> = Mutex::new(Timeline::new());
+static EXPORT: Mutex<Timeline> = Mutex::new(Timeline::new());
+static BYTES_IN_USE: AtomicI64 = AtomicI64::new(0);
+
+/// Kernel launch which produced a kernel activity
+#[derive(Debug, Clone, PartialEq)]
+pub struct Kernel {
+    pub op_name: String,
+    pub inputs: Vec<TensorInfo>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    // None for synchronous copies, which run on the calling thread
+    pub stream: Option<usize>,
+    pub activity: Activity,
+    // Set for kernel activities
+    pub kernel: Option<Kernel>,
+    pub begin: Instant,
+    pub end: Instant,
+}
+
+impl Event {
+    pub fn name(&self) -> &str {
+        match (&self.kernel, self.activity) {
+            (Some(kernel), _) => &kernel.op_name,
+            (None, Activity::Memcpy(Direction::HostToDevice, _)) => "MemcpyHtoD",
+            (None, Activity::Memcpy(Direction::DeviceToHost, _)) => "MemcpyDtoH",
+            (None, Activity::Memcpy(Direction::DeviceToDevice, _)) => "MemcpyDtoD",
+            (None, Activity::Memset(_)) => "Memset",
+            (None, Activity::Kernel(_)) => "Kernel",
+        }
+    }
+
+    // Bytes transferred or written, zero for kernels
+    pub fn bytes(&self) -> u64 {
+        match self.activity {
+            Activity::Memcpy(_, bytes) | Activity::Memset(bytes) => bytes,
+            Activity::Kernel(_) => 0,
+        }
+    }
+}
+
+/// Device memory allocation, or deallocation for negative sizes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Allocation {
+    pub time: Instant,
+    pub bytes: i64,
+    // Including this allocation
+    pub bytes_in_use: i64,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Timeline {
+    pub events: Vec<Event>,
+    pub allocations: Vec<Allocation>,
+}
+
+impl Timeline {
+    const fn new() -> Self {
+        Self {
+            events: Vec::new(),
+            allocations: Vec::new(),
+        }
+    }
+}
+
+pub fn is_recording() -> bool {
+    PROFILING.load(Ordering::Relaxed) || EXPORTING.load(Ordering::Relaxed)
+}
+
+/// Starts a profiler session, recording into an empty timeline
+pub fn start() {
+    *SESSION.lock().unwrap() = Timeline::new();
+    PROFILING.store(true, Ordering::Relaxed);
+}
+
+pub fn stop() {
+    PROFILING.store(false, Ordering::Relaxed);
+}
+
+/// Takes everything the profiler session recorded, leaving its timeline empty
+pub fn take() -> Timeline {
+    std::mem::take(&mut *SESSION.lock().unwrap())
+}
+
+/// Records for the trace export from now on, until the process exits
+pub fn start_export() {
+    EXPORTING.store(true, Ordering::Relaxed);
+}
+
+pub fn take_export() -> Timeline {
+    std::mem::take(&mut *EXPORT.lock().unwrap())
+}
+
+fn push(push: impl Fn(&mut Timeline)) {
+    if EXPORTING.load(Ordering::Relaxed) {
+        push(&mut EXPORT.lock().unwrap());
+    }
+    if PROFILING.load(Ordering::Relaxed) {
+        push(&mut SESSION.lock().unwrap());
+    }
+}
+
+/// Records an activity which a stream started at `begin` and just finished.
+/// Kernels compute on the host before they reach their stream, which is idle
+/// meanwhile. Without a device model they barely occupy the stream, so they are
+/// shown for at least their compute time, ending when the stream reached them
+pub fn record(stream: Option<usize>, activity: Activity, kernel: Option<Kernel>, begin: Instant) {
+    if !is_recording() {
+        return;
+    }
+
+    let end = Instant::now();
+    let begin = match activity {
+        Activity::Kernel(compute) => begin.min(end.checked_sub(compute).unwrap_or(begin)),
+        _ => begin,
+    };
+    let event = Event {
+        stream,
+        activity,
+        kernel,
+        begin,
+        end,
+    };
+    push(|timeline| timeline.events.push(event.clone()));
+}
+
+/// Tracks device memory in use, which is sampled while recording
+pub fn record_allocation(bytes: i64) {
+    let bytes_in_use = BYTES_IN_USE.fetch_add(bytes, Ordering::Relaxed) + bytes;
+    let allocation = Allocation {
+        time: Instant::now(),
+        bytes,
+        bytes_in_use,
+    };
+    push(|timeline| timeline.allocations.push(allocation));
+}
+
+/// Nanoseconds since the Unix epoch, the time base of TF's profiler
+pub fn unix_nanos(instant: Instant) -> i64 {
+    static ANCHOR: OnceLock<(Instant, i64)> = OnceLock::new();
+    let (anchor, anchor_nanos) = *ANCHOR.get_or_init(|| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        (Instant::now(), now.as_nanos() as i64)
+    });
+
+    match instant.checked_duration_since(anchor) {
+        Some(after) => anchor_nanos + after.as_nanos() as i64,
+        None => anchor_nanos - anchor.duration_since(instant).as_nanos() as i64,
+    }
+}